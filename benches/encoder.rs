@@ -0,0 +1,127 @@
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use schedule1::combinatorial::CombinatorialEncoder;
+use std::time::Duration;
+
+/// Number of combinations encoded or decoded per iteration.
+const SAMPLES: usize = 1 << 20;
+
+/// The encoder for the game's 34 effects, mixed up to 8 at a time.
+fn encoder() -> CombinatorialEncoder {
+    CombinatorialEncoder::new(34, 8)
+}
+
+/// Indices spread evenly over the whole range, so every combination size is represented.
+fn sample_indices(encoder: &CombinatorialEncoder) -> Vec<u32> {
+    let stride = encoder.maximum_index() / SAMPLES as u32;
+    (0..SAMPLES as u32)
+        .map(|i| i * stride + i % stride)
+        .collect()
+}
+
+fn encode(c: &mut Criterion) {
+    let encoder = encoder();
+    let bitsets = sample_indices(&encoder)
+        .into_iter()
+        .map(|i| encoder.decode(i))
+        .collect::<Vec<_>>();
+
+    let mut group = c.benchmark_group("encode");
+    group.bench_function("scalar", |b| {
+        b.iter_batched_ref(
+            || vec![0u32; SAMPLES],
+            |indices| {
+                for (bitset, index) in bitsets.iter().zip(indices.iter_mut()) {
+                    *index = encoder.encode(black_box(*bitset));
+                }
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("encode_many", |b| {
+        b.iter_batched_ref(
+            || vec![0u32; SAMPLES],
+            |indices| encoder.encode_many(black_box(&bitsets), indices),
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+fn decode(c: &mut Criterion) {
+    let encoder = encoder();
+    let indices = sample_indices(&encoder);
+
+    let mut group = c.benchmark_group("decode");
+    group.bench_function("scalar", |b| {
+        b.iter_batched_ref(
+            || vec![0u64; SAMPLES],
+            |bitsets| {
+                for (index, bitset) in indices.iter().zip(bitsets.iter_mut()) {
+                    *bitset = encoder.decode(black_box(*index));
+                }
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("decode_many", |b| {
+        b.iter_batched_ref(
+            || vec![0u64; SAMPLES],
+            |bitsets| encoder.decode_many(black_box(&indices), bitsets),
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+fn iterate(c: &mut Criterion) {
+    let encoder = encoder();
+
+    let mut group = c.benchmark_group("iterate");
+    group.bench_function("decode each index", |b| {
+        b.iter(|| {
+            (0..SAMPLES as u32)
+                .map(|i| encoder.decode(i))
+                .fold(0u64, |acc, bitset| acc ^ bitset)
+        })
+    });
+    group.bench_function("combinations", |b| {
+        b.iter(|| {
+            encoder
+                .combinations()
+                .take(SAMPLES)
+                .fold(0u64, |acc, (_, bitset)| acc ^ bitset)
+        })
+    });
+    group.finish();
+}
+
+fn supersets(c: &mut Criterion) {
+    let encoder = encoder();
+    // Two effects, as when searching for routes that include them.
+    let target = 1 << 3 | 1 << 17;
+
+    let mut group = c.benchmark_group("supersets");
+    group.bench_function("filter combinations", |b| {
+        b.iter(|| {
+            encoder
+                .combinations()
+                .filter(|(_, bitset)| bitset & black_box(target) == target)
+                .fold(0u32, |acc, (index, _)| acc ^ index)
+        })
+    });
+    group.bench_function("supersets", |b| {
+        b.iter(|| {
+            encoder
+                .supersets(black_box(target))
+                .fold(0u32, |acc, (index, _)| acc ^ index)
+        })
+    });
+    group.finish();
+}
+
+criterion_group! {
+    name = encoder_benches;
+    config = Criterion::default().without_plots().measurement_time(Duration::from_secs(10));
+    targets = encode, decode, iterate, supersets
+}
+criterion_main!(encoder_benches);
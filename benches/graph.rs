@@ -127,7 +127,11 @@ fn parallel_simd_rows(data: &[u8], target: u32, predecessors: &mut Vec<u32>) {
     }));
 }
 
-fn pred(c: &mut Criterion) -> Result<(), Box<dyn Error>> {
+fn pred(c: &mut Criterion) {
+    pred_fallible(c).expect("failed to run predecessor search benchmarks")
+}
+
+fn pred_fallible(c: &mut Criterion) -> Result<(), Box<dyn Error>> {
     let mut group = c.benchmark_group("predecessor_search");
 
     let mut data = Vec::with_capacity(
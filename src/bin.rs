@@ -1,66 +1,149 @@
 use clap::Parser;
 use indicatif::{ProgressBar, ProgressIterator, ProgressStyle};
-use rayon::iter::{
-    IntoParallelIterator, IntoParallelRefIterator, ParallelExtend, ParallelIterator,
-};
-use savefile_derive::Savefile;
+use rayon::iter::{ParallelBridge, ParallelExtend, ParallelIterator};
 use schedule1::combinatorial::CombinatorialEncoder;
+use schedule1::container::{self, Codec};
 use schedule1::effect_graph::{EffectGraph, GRAPH_VERSION};
-use schedule1::flat_storage::FlatStorage;
+use schedule1::export::{export_records, write_table, TableFormat};
+use schedule1::file_header::FileHeader;
+use schedule1::graph_export::{write_dot, write_graphml, GraphFormat, Limits, Subgraph};
 use schedule1::mixing::{
-    base_price, inherent_effects, parse_rules_file, substance_cost, Drugs, Effects, MixtureRules,
-    Substance, MAX_EFFECTS, NUM_EFFECTS, SUBSTANCES,
+    parse_drug, parse_rules_file, substance_cost, Drugs, Effects, MixtureRules, Substance,
+};
+use schedule1::mosp::{Cost, EffectIndex, Label, PathLength};
+use schedule1::output::{Format, MetadataRecord, RecordWriter, SanityRecord};
+use schedule1::query::{
+    filtered_search_records, load_routes, lookup_records, parse_recipe, profit_records,
+    shortest_path, simulate, trace_path, FlattenedResultsFile, ProfitQuery, SHORTEST_PATH_VERSION,
+    STARTING_DRUGS,
 };
-use schedule1::mosp::{multiobjective_shortest_path, Cost, EffectIndex, Label, PathLength};
-use serde::{Deserialize, Serialize};
+use schedule1::reachability::{reachability_records, ReachableSets};
+use schedule1::recipes::{verify_recipe, RecipeBook};
+use schedule1::render::{stdout_supports_color, Renderer};
+use schedule1::repl::{self, Session};
+use schedule1::route_index::RouteIndex;
+use schedule1::rules_diff::{recipe_diff, rule_changes, transition_diff, RulesDiff};
+use schedule1::server::{QueryServer, QueryState};
+use serde::Serialize;
 use std::collections::HashMap;
 use std::error::Error;
 use std::fs::OpenOptions;
 use std::io::{stdout, BufWriter, Write};
 use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 use std::time::Duration;
 use topset::TopSet;
 
-type FlatPaths = FlatStorage<Label>;
-
-#[derive(Savefile, Serialize, Deserialize)]
-struct FlattenedResultsFile {
-    price_multipliers: Vec<u16>,
-    kush: FlatPaths,
-    sour_diesel: FlatPaths,
-    green_crack: FlatPaths,
-    granddaddy_purple: FlatPaths,
-    meth_cocaine: FlatPaths,
-}
-
-const SHORTEST_PATH_VERSION: u32 = 3;
-
 #[derive(Debug, clap::Parser)]
 struct Args {
     #[arg(long)]
     rules: PathBuf,
 
+    #[arg(long, value_enum, default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+
+    /// Output format, see the `output` module for the record schemas
+    #[arg(long, global = true, value_enum, default_value_t = Format::Text)]
+    format: Format,
+
+    /// Use graph and routes files built from different rules or costs, with a warning
+    #[arg(long, global = true)]
+    allow_mismatched_files: bool,
+
     #[command(subcommand)]
     command: Command,
 }
 
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum ColorChoice {
+    /// Colour output when stdout is a terminal
+    Auto,
+    Always,
+    Never,
+}
+
 #[derive(Debug, clap::Subcommand)]
 enum Command {
     Generate {
         #[arg(long)]
         graph: PathBuf,
+        /// Save the graph in a compact container, see the `container` module
+        #[arg(long)]
+        container: Option<Codec>,
     },
     ShortestPath {
         #[arg(long)]
         graph: PathBuf,
         #[arg(long)]
         output_file: PathBuf,
+        /// Save the routes in a compact container, see the `container` module
+        #[arg(long)]
+        container: Option<Codec>,
     },
     Search {
         #[arg(long)]
         routes: PathBuf,
         #[arg(long)]
         effects: String,
+        /// Effects the matching effect sets must not have
+        #[arg(long)]
+        exclude: Option<String>,
+        /// Index built with `index`, to find the matching effect sets without a scan
+        #[arg(long)]
+        index: Option<PathBuf>,
+    },
+    /// Count the effect sets each product can reach, see the `reachability` module
+    Reachability {
+        #[arg(
+            long,
+            required_unless_present = "reachable",
+            conflicts_with = "reachable"
+        )]
+        graph: Option<PathBuf>,
+        /// Reachable effect sets saved with `--output-file`, used instead of searching `--graph`
+        #[arg(long)]
+        reachable: Option<PathBuf>,
+        /// File to save the reachable effect sets of each product to, as bitmaps
+        #[arg(long)]
+        output_file: Option<PathBuf>,
+    },
+    /// Build an inverted index over a routes file, see the `route_index` module
+    Index {
+        #[arg(long)]
+        routes: PathBuf,
+        #[arg(long)]
+        output_file: PathBuf,
+    },
+    /// Write every route to a CSV or Parquet table, see the `export` module
+    Export {
+        #[arg(long)]
+        routes: PathBuf,
+        #[arg(long)]
+        output_file: PathBuf,
+        #[arg(long, value_enum, default_value_t = TableFormat::Csv)]
+        table_format: TableFormat,
+        #[arg(long, default_value_t = 0.)]
+        markup: f64,
+        #[arg(long, default_value_t = 999)]
+        max_price: Cost,
+    },
+    /// Write the effect sets reachable from a product as a DOT or GraphML graph, see the
+    /// `graph_export` module
+    ExportGraph {
+        #[arg(long)]
+        graph: PathBuf,
+        #[arg(long)]
+        drug: String,
+        /// Most substances to mix in
+        #[arg(long, required_unless_present = "max_cost")]
+        max_depth: Option<PathLength>,
+        /// Most to spend on substances
+        #[arg(long)]
+        max_cost: Option<Cost>,
+        #[arg(long)]
+        output_file: PathBuf,
+        #[arg(long, value_enum, default_value_t = GraphFormat::Dot)]
+        graph_format: GraphFormat,
     },
     Lookup {
         #[arg(long)]
@@ -81,7 +164,8 @@ enum Command {
         max_price: Cost,
         #[arg(long, default_value_t = 10)]
         max_results: usize,
-        #[arg(long, default_value_t = false)]
+        /// Deprecated alias for `--format ndjson`
+        #[arg(long, default_value_t = false, hide = true)]
         json: bool,
     },
     Metadata {
@@ -95,12 +179,66 @@ enum Command {
         #[arg(long)]
         routes: PathBuf,
     },
+    Simulate {
+        #[arg(long)]
+        drug: String,
+        #[arg(long)]
+        substances: String,
+    },
+    /// Serve route queries over HTTP, see the `server` module for the endpoints
+    Serve {
+        #[arg(long)]
+        routes: PathBuf,
+        /// Graph to answer simulations from, instead of applying the rules directly
+        #[arg(long)]
+        graph: Option<PathBuf>,
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        address: String,
+        #[arg(long, default_value_t = 4)]
+        workers: usize,
+    },
+    /// Rewrite the rules file with sorted keys and rules, for diff-friendly edits
+    NormalizeRules {
+        #[arg(long)]
+        output: PathBuf,
+    },
+    /// Query routes interactively, see the `repl` module for the commands
+    Repl {
+        #[arg(long)]
+        routes: PathBuf,
+        /// Graph to answer simulations from, instead of applying the rules directly
+        #[arg(long)]
+        graph: Option<PathBuf>,
+        /// File to load and save command history in
+        #[arg(long)]
+        history: Option<PathBuf>,
+    },
+    /// Replay a recipe book, see the `recipes` module for its format
+    VerifyRecipes {
+        #[arg(long)]
+        book: PathBuf,
+        /// Routes to check the recipes against for cheaper or shorter alternatives
+        #[arg(long)]
+        routes: Option<PathBuf>,
+    },
+    /// Compare `--rules` against a newer rules file, e.g. after a game update
+    DiffRules {
+        #[arg(long)]
+        new_rules: PathBuf,
+        /// Recipe to mix under both rules, as `drug: substance, substance, ...` (repeatable)
+        #[arg(long)]
+        recipe: Vec<String>,
+        /// Recipe book to mix under both rules, as for `verify-recipes`
+        #[arg(long)]
+        book: Option<PathBuf>,
+    },
 }
 
-fn generate<const N: u8, const K: u8>(
+fn generate(
     rules: &MixtureRules,
-    encoder: CombinatorialEncoder<N, K>,
+    encoder: CombinatorialEncoder,
     graph_path: &Path,
+    container: Option<Codec>,
 ) -> Result<(), Box<dyn Error>> {
     if graph_path.is_file() {
         println!("'{graph_path:?}' exists, refusing to overwrite");
@@ -113,106 +251,179 @@ fn generate<const N: u8, const K: u8>(
         .open(graph_path)?;
     let mut writer = BufWriter::new(file);
     let g = EffectGraph::new(rules, encoder);
-    g.serialize(&mut writer)?;
+    match container {
+        Some(codec) => g.serialize_container(&mut writer, codec)?,
+        None => g.serialize(&mut writer)?,
+    }
     writer.flush().map_err(Into::into)
 }
 
-fn shortest_path<const N: u8, const K: u8>(
-    starting: Effects,
-    graph: &EffectGraph<N, K>,
-) -> FlatPaths {
-    let costs = SUBSTANCES
-        .iter()
-        .copied()
-        .map(|s| substance_cost(s) as Cost)
-        .collect::<Vec<_>>();
-
-    multiobjective_shortest_path(graph, &costs, starting).into()
+struct GraphStats {
+    nodes: usize,
+    backlinks: usize,
 }
 
-fn trace_path(start: Label, paths: &FlatPaths) -> Vec<Substance> {
-    let mut path = Vec::with_capacity(start.length as usize);
-    let mut l = start;
-    while let Some((next, s)) = l.backlink() {
-        path.push(s);
-        l = *paths
-            .get(next as usize)
-            .iter()
-            .find(|candidate| candidate.length == l.length - 1)
-            .expect("should find connected path");
+impl GraphStats {
+    fn records(&self) -> Vec<MetadataRecord> {
+        [("nodes", self.nodes), ("backlinks", self.backlinks)]
+            .into_iter()
+            .map(|(metric, value)| MetadataRecord {
+                section: "graph",
+                drug: None,
+                metric,
+                key: None,
+                value: value as u64,
+            })
+            .collect()
+    }
+
+    fn print(&self) {
+        println!("---------\nGraph metadata:");
+        println!("size_of::<EffectGraph>() = {}", size_of::<EffectGraph>());
+        println!("Number of nodes = {}", self.nodes);
+        println!("Number of backlinks = {}", self.backlinks);
     }
-    // Since we started at the target and worked back to the root node, flip the order.
-    path.reverse();
-    path
 }
 
-fn lookup(index: u32, labels: &FlatPaths) -> Vec<Vec<Substance>> {
-    let starting_labels = labels.get(index as usize);
-    let mut paths = Vec::with_capacity(starting_labels.len());
-    for potential_path in starting_labels {
-        let path = trace_path(*potential_path, labels);
-        paths.push(path);
+fn graph_metadata(graph: &EffectGraph) -> GraphStats {
+    let num_nodes = graph.num_nodes();
+    let backlinks: usize = (0..num_nodes)
+        .map(|idx| graph.predecessors(idx as u32).len())
+        .sum();
+    GraphStats {
+        nodes: num_nodes,
+        backlinks,
     }
+}
 
-    paths
+/// Header of a graph or routes file, its size, and the size of the same contents as a plain
+/// savefile.
+struct FileInfo {
+    section: &'static str,
+    header: FileHeader,
+    file: u64,
+    raw: u64,
 }
 
-fn search_inexact<const N: u8, const K: u8>(
-    target_effects: Effects,
-    encoder: &CombinatorialEncoder<N, K>,
-    labels: &FlatPaths,
-) -> Option<((usize, Label), (usize, Label))> {
-    let mut lowest_cost = None;
-    let mut shortest = None;
-    for idx in 0..encoder.maximum_index() as usize {
-        let paths = labels.get(idx);
-        // only consider reachable effects
-        if paths.is_empty() {
-            continue;
-        }
-        let current_effects = Effects::from(encoder.decode(idx as u32));
-        if !current_effects.contains(target_effects) {
-            continue;
-        }
-        for path in paths {
-            if path.cost < lowest_cost.get_or_insert((idx, *path)).1.cost {
-                lowest_cost = Some((idx, *path));
-            }
-            if path.length < shortest.get_or_insert((idx, *path)).1.length {
-                shortest = Some((idx, *path));
-            }
+impl FileInfo {
+    fn new<T: savefile::WithSchema + savefile::Serialize>(
+        section: &'static str,
+        path: &Path,
+        header: &FileHeader,
+        version: u32,
+        value: &T,
+    ) -> Result<Self, Box<dyn Error>> {
+        Ok(Self {
+            section,
+            header: header.clone(),
+            file: std::fs::metadata(path)?.len(),
+            raw: container::raw_size(version, value)?,
+        })
+    }
+
+    fn records(&self) -> Vec<MetadataRecord> {
+        let header = [
+            ("rules_hash", self.header.rules_hash),
+            ("created", self.header.created),
+        ];
+        header
+            .into_iter()
+            .filter(|_| self.header.is_present())
+            .chain([("file_size", self.file), ("raw_size", self.raw)])
+            .map(|(metric, value)| MetadataRecord {
+                section: self.section,
+                drug: None,
+                metric,
+                key: None,
+                value,
+            })
+            .collect()
+    }
+
+    fn print(&self) {
+        let header = &self.header;
+        if header.is_present() {
+            println!(
+                "Built by version {} at {} (Unix time) from rules with hash {:016x}",
+                header.tool_version, header.created, header.rules_hash
+            );
+            println!(
+                "Built for up to {} of {} effects, with substance costs {:?}",
+                header.max_elements, header.num_elements, header.substance_costs
+            );
+        } else {
+            println!("No header, built by an older version");
         }
+        println!(
+            "File size = {} bytes ({} bytes as a plain savefile, {:.1}%)",
+            self.file,
+            self.raw,
+            100. * self.file as f64 / self.raw as f64
+        );
     }
-    lowest_cost.map(|(idx, path)| ((idx, path), shortest.unwrap()))
 }
 
-fn graph_metadata<const N: u8, const K: u8>(graph: &EffectGraph<N, K>) {
-    println!("---------\nGraph metadata:");
-    println!(
-        "size_of::<EffectGraph<N, K>>() = {}",
-        size_of::<EffectGraph<N, K>>()
-    );
+struct RouteStats {
+    title: &'static str,
+    drug: Drugs,
+    labels: usize,
+    counts: Vec<(usize, usize)>,
+    lengths: Vec<(PathLength, usize)>,
+    longest: Vec<(PathLength, usize)>,
+}
 
-    let num_nodes = graph.num_nodes();
-    println!("Number of nodes = {}", num_nodes);
+impl RouteStats {
+    fn records(&self) -> Vec<MetadataRecord> {
+        let record = |metric, key: Option<u64>, value: u64| MetadataRecord {
+            section: "routes",
+            drug: Some(self.drug),
+            metric,
+            key,
+            value,
+        };
+        let mut records = vec![record("labels", None, self.labels as u64)];
+        records.extend(
+            self.counts
+                .iter()
+                .map(|(k, v)| record("label_count", Some(*k as u64), *v as u64)),
+        );
+        records.extend(
+            self.lengths
+                .iter()
+                .map(|(k, v)| record("min_length", Some(*k as u64), *v as u64)),
+        );
+        records.extend(
+            self.longest
+                .iter()
+                .map(|(k, v)| record("longest_min_length", Some(*k as u64), *v as u64)),
+        );
+        records
+    }
 
-    let backlinks: usize = (0..num_nodes)
-        .map(|idx| graph.predecessors(idx as u32).len())
-        .sum();
-    println!("Number of backlinks = {}", backlinks);
-    println!();
+    fn print(&self) {
+        let RouteStats {
+            title,
+            labels,
+            counts,
+            lengths,
+            longest,
+            ..
+        } = self;
+        println!("{title}:\n  Number of labels: {labels}\n  Counts: {counts:?}");
+        println!("  Minimum Lengths: {lengths:?}");
+        println!("  Longest Minimum Lengths: {longest:?}");
+    }
 }
 
-fn routes_metadata(routes: &FlattenedResultsFile) {
-    println!("---------\nRoute metadata:");
-    println!("size_of::<Label>() = {}", size_of::<Label>());
+fn routes_metadata(routes: &FlattenedResultsFile) -> Vec<RouteStats> {
     let num_nodes = routes.price_multipliers.len();
-    for (title, paths) in [
-        ("Kush", &routes.kush),
-        ("Sour Diesel", &routes.sour_diesel),
-        ("Green Crack", &routes.green_crack),
-        ("GDP", &routes.granddaddy_purple),
-        ("Meth/Cocaine", &routes.meth_cocaine),
+    let mut stats = Vec::new();
+    for (title, drug, paths) in [
+        ("Kush", Drugs::OGKush, &routes.kush),
+        ("Sour Diesel", Drugs::SourDiesel, &routes.sour_diesel),
+        ("Green Crack", Drugs::GreenCrack, &routes.green_crack),
+        ("GDP", Drugs::GranddaddyPurple, &routes.granddaddy_purple),
+        ("Meth/Cocaine", Drugs::Meth, &routes.meth_cocaine),
     ] {
         let mut total = 0usize;
         let mut counts: HashMap<usize, usize> = HashMap::new();
@@ -234,203 +445,506 @@ fn routes_metadata(routes: &FlattenedResultsFile) {
 
         let mut counts = counts.into_iter().collect::<Vec<_>>();
         counts.sort();
-        println!("{title}:\n  Number of labels: {total}\n  Counts: {counts:?}");
 
         let mut lengths = lengths.into_iter().collect::<Vec<_>>();
         lengths.sort();
-        println!("  Minimum Lengths: {lengths:?}");
 
         let mut longest = longest.into_sorted_vec();
         longest.reverse();
-        println!("  Longest Minimum Lengths: {longest:?}");
+
+        stats.push(RouteStats {
+            title,
+            drug,
+            labels: total,
+            counts,
+            lengths,
+            longest,
+        });
     }
+    stats
+}
+
+/// Writes `records` to stdout in a machine-readable `format`.
+fn write_records<T: Serialize>(format: Format, records: &[T]) -> Result<(), Box<dyn Error>> {
+    let mut writer = RecordWriter::new(format, stdout().lock());
+    writer.write_all(records)?;
+    writer.finish().map(drop)
 }
 
-type CheckFun = fn(&FlattenedResultsFile, u32) -> Option<Vec<(Drugs, u32)>>;
+/// A drug and effect set index that fail a check, with the offending route for checks of
+/// individual routes.
+type Violation = (Drugs, u32, Vec<Substance>);
+
+type CheckFun =
+    fn(&MixtureRules, &CombinatorialEncoder, &FlattenedResultsFile) -> Option<Vec<Violation>>;
 
 fn check_pareto_optimality(
+    _rules: &MixtureRules,
+    _encoder: &CombinatorialEncoder,
     routes: &FlattenedResultsFile,
-    max_value: u32,
-) -> Option<Vec<(Drugs, u32)>> {
+) -> Option<Vec<Violation>> {
     let mut errors = Vec::new();
-    for (drug, paths) in [
-        (Drugs::OGKush, &routes.kush),
-        (Drugs::SourDiesel, &routes.sour_diesel),
-        (Drugs::GreenCrack, &routes.green_crack),
-        (Drugs::GranddaddyPurple, &routes.granddaddy_purple),
-        (Drugs::Meth, &routes.meth_cocaine),
-    ] {
-        errors.par_extend((0..max_value).into_par_iter().filter_map(|idx| {
-            let labels = paths.get(idx as usize);
-            if labels.is_empty() {
-                return None;
-            }
-            for (j, label) in labels[..labels.len() - 1].iter().enumerate() {
+    for (drug, paths) in routes.by_drug() {
+        errors.par_extend(paths.iter().par_bridge().filter_map(|(idx, labels)| {
+            for (j, label) in labels.iter().enumerate() {
                 for other_label in &labels[j + 1..] {
                     if label.cost >= other_label.cost && label.length >= other_label.length {
-                        return Some((drug, idx));
+                        return Some((drug, idx as u32, Vec::new()));
                     }
                 }
             }
             None
         }));
     }
-
-    if errors.is_empty() {
-        None
-    } else {
-        Some(errors)
-    }
+    violations(errors)
 }
 
-fn check_route_costs(routes: &FlattenedResultsFile, max_value: u32) -> Option<Vec<(Drugs, u32)>> {
+fn check_route_costs(
+    _rules: &MixtureRules,
+    _encoder: &CombinatorialEncoder,
+    routes: &FlattenedResultsFile,
+) -> Option<Vec<Violation>> {
     let mut errors = Vec::new();
-    for (drug, paths) in [
-        (Drugs::OGKush, &routes.kush),
-        (Drugs::SourDiesel, &routes.sour_diesel),
-        (Drugs::GreenCrack, &routes.green_crack),
-        (Drugs::GranddaddyPurple, &routes.granddaddy_purple),
-        (Drugs::Meth, &routes.meth_cocaine),
-    ] {
-        errors.par_extend((0..max_value).into_par_iter().filter_map(|idx| {
-            let labels = paths.get(idx as usize);
+    for (drug, paths) in routes.by_drug() {
+        errors.par_extend(paths.iter().par_bridge().filter_map(|(idx, labels)| {
             for label in labels {
-                let p = trace_path(*label, paths);
+                let Some(p) = trace_path(*label, paths) else {
+                    return Some((drug, idx as u32, Vec::new()));
+                };
                 let actual_cost: u16 = p.iter().map(|s| substance_cost(*s)).sum::<i64>() as u16;
                 if actual_cost != label.cost {
-                    return Some((drug, idx));
+                    return Some((drug, idx as u32, p));
                 }
             }
             None
         }));
     }
+    violations(errors)
+}
 
+/// Checks that every route, mixed into the drug's starting effects, ends at the effect set it is
+/// stored under. Reports the first route that does not for each effect set.
+fn check_route_replay(
+    rules: &MixtureRules,
+    encoder: &CombinatorialEncoder,
+    routes: &FlattenedResultsFile,
+) -> Option<Vec<Violation>> {
+    let mut errors = Vec::new();
+    for (drug, paths) in routes.by_drug() {
+        errors.par_extend(paths.iter().par_bridge().filter_map(|(idx, labels)| {
+            let idx = idx as u32;
+            labels
+                .iter()
+                .map(|label| trace_path(*label, paths))
+                .find(|path| {
+                    path.as_ref().is_none_or(|path| {
+                        let effects = rules.mix(drug, path);
+                        encoder.try_encode(effects.bits()) != Ok(idx)
+                    })
+                })
+                .map(|path| (drug, idx, path.unwrap_or_default()))
+        }));
+    }
+    violations(errors)
+}
+
+/// `None` if there are no `errors`, otherwise the errors in order of drug and effect set.
+fn violations(mut errors: Vec<Violation>) -> Option<Vec<Violation>> {
     if errors.is_empty() {
-        None
-    } else {
-        Some(errors)
+        return None;
     }
+    errors.sort_unstable_by_key(|(drug, idx, _)| (*drug, *idx));
+    Some(errors)
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let args = Args::parse();
+/// Loads graph, routes, index and reachability files, checking their headers against the rules,
+/// see the `file_header` module.
+struct FileLoader<'a> {
+    rules: &'a MixtureRules,
+    allow_mismatch: bool,
+}
+
+impl FileLoader<'_> {
+    fn graph(&self, path: impl AsRef<Path>) -> Result<EffectGraph, Box<dyn Error>> {
+        let graph = EffectGraph::load(&path, self.rules)?;
+        self.check(path.as_ref(), graph.header())?;
+        Ok(graph)
+    }
+
+    fn routes(&self, path: &Path) -> Result<FlattenedResultsFile, Box<dyn Error>> {
+        let routes = load_routes(path, &self.rules.encoder())?;
+        self.check(path, &routes.header)?;
+        Ok(routes)
+    }
+
+    fn index(&self, path: &Path) -> Result<RouteIndex, Box<dyn Error>> {
+        let index = RouteIndex::load(path, &self.rules.encoder())?;
+        self.check(path, index.header())?;
+        Ok(index)
+    }
+
+    fn reachable(&self, path: &Path) -> Result<ReachableSets, Box<dyn Error>> {
+        let sets = ReachableSets::load(path, &self.rules.encoder())?;
+        self.check(path, sets.header())?;
+        Ok(sets)
+    }
 
+    fn check(&self, path: &Path, header: &FileHeader) -> Result<(), Box<dyn Error>> {
+        if !header.is_present() {
+            eprintln!(
+                "Warning: {path:?} has no header, regenerate it to check it against the rules"
+            );
+            return Ok(());
+        }
+        let mismatches = header.mismatches(self.rules);
+        if mismatches.is_empty() {
+            return Ok(());
+        }
+        if self.allow_mismatch {
+            for mismatch in mismatches {
+                eprintln!("Warning: {path:?} was {mismatch}");
+            }
+            return Ok(());
+        }
+        Err(format!(
+            "{path:?} was {}\nRegenerate it, or pass --allow-mismatched-files to use it anyway",
+            mismatches.join("\nand ")
+        )
+        .into())
+    }
+}
+
+/// Loads everything the `serve` and `repl` commands query.
+fn load_state(
+    rules: MixtureRules,
+    routes: &Path,
+    graph: Option<PathBuf>,
+    allow_mismatch: bool,
+) -> Result<QueryState, Box<dyn Error>> {
+    let loader = FileLoader {
+        rules: &rules,
+        allow_mismatch,
+    };
+    let bar = ProgressBar::new_spinner();
+    bar.enable_steady_tick(Duration::from_millis(100));
+    let graph = match graph {
+        Some(g) => {
+            bar.set_message("Loading graph");
+            Some(bar.suspend(|| loader.graph(g))?)
+        }
+        None => None,
+    };
+    bar.set_message("Loading routes");
+    let routes = bar.suspend(|| loader.routes(routes))?;
+    bar.finish_and_clear();
+    Ok(QueryState::new(rules, routes, graph)?)
+}
+
+fn main() -> ExitCode {
+    match run(Args::parse()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(args: Args) -> Result<(), Box<dyn Error>> {
     let rules = parse_rules_file(args.rules)?;
-    let encoder = CombinatorialEncoder::<NUM_EFFECTS, MAX_EFFECTS>::new();
+    let encoder = rules.encoder();
+    let format = args.format;
+    let loader = FileLoader {
+        rules: &rules,
+        allow_mismatch: args.allow_mismatched_files,
+    };
+    let render = Renderer::new(
+        &rules,
+        match args.color {
+            ColorChoice::Auto => stdout_supports_color(),
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        },
+    );
 
     match args.command {
-        Command::Generate { graph } => {
+        Command::Generate { graph, container } => {
             let bar = ProgressBar::new_spinner();
             bar.set_message("Building graph");
             bar.enable_steady_tick(Duration::from_millis(100));
-            generate(&rules, encoder, graph.as_path())?;
+            generate(&rules, encoder, graph.as_path(), container)?;
             bar.finish_and_clear();
             Ok(())
         }
-        Command::ShortestPath { graph, output_file } => {
+        Command::ShortestPath {
+            graph,
+            output_file,
+            container,
+        } => {
+            let bar = ProgressBar::new_spinner();
+            bar.enable_steady_tick(Duration::from_millis(100));
+            bar.set_message("Loading graph");
+            let g = loader.graph(graph)?;
+            // Only replace the existing routes once the graph has loaded and matched the rules.
             let output_file = OpenOptions::new()
                 .write(true)
                 .create(true)
                 .truncate(true)
                 .open(output_file)?;
             let mut writer = BufWriter::new(output_file);
-            let bar = ProgressBar::new_spinner();
-            bar.enable_steady_tick(Duration::from_millis(100));
-            bar.set_message("Loading graph");
-            let g: EffectGraph<NUM_EFFECTS, MAX_EFFECTS> =
-                savefile::load_file(graph, GRAPH_VERSION)?;
 
             bar.set_style(
                 ProgressStyle::with_template("{wide_bar} {pos}/{len}\n{wide_msg}").unwrap(),
             );
             bar.set_message("Finding shortest paths");
             bar.set_length(5);
-            let mut paths = [
-                Drugs::OGKush,
-                Drugs::SourDiesel,
-                Drugs::GreenCrack,
-                Drugs::GranddaddyPurple,
-                Drugs::Meth,
-            ]
-            .iter()
-            .progress_with(bar.clone())
-            .copied()
-            .map(|d| shortest_path(inherent_effects(d), &g))
-            .collect::<Vec<_>>();
-
-            let meth_cocaine = paths.pop().expect("should not be empty");
-            let granddaddy_purple = paths.pop().expect("should not be empty");
-            let green_crack = paths.pop().expect("should not be empty");
-            let sour_diesel = paths.pop().expect("should not be empty");
-            let kush = paths.pop().expect("should not be empty");
+            let paths = STARTING_DRUGS
+                .iter()
+                .progress_with(bar.clone())
+                .copied()
+                .map(|d| shortest_path(rules.drug_effects(d), &g))
+                .collect::<Vec<_>>();
 
             bar.set_style(ProgressStyle::default_spinner());
             bar.set_message("Computing price multipliers");
-            let price_multipliers = (0..encoder.maximum_index())
-                .map(|idx| rules.price_multiplier(Effects::from(encoder.decode(idx))))
-                .map(|p| (p * 100.).round() as u16)
-                .collect::<Vec<_>>();
-
-            let paths = FlattenedResultsFile {
-                price_multipliers,
-                kush,
-                sour_diesel,
-                green_crack,
-                granddaddy_purple,
-                meth_cocaine,
-            };
+            let paths = FlattenedResultsFile::from_paths(
+                &rules,
+                &encoder,
+                paths.try_into().ok().expect("should have every drug"),
+            );
 
             bar.set_message("Serializing shortest paths");
-            savefile::save(&mut writer, SHORTEST_PATH_VERSION, &paths)?;
+            match container {
+                Some(codec) => paths.serialize_container(&mut writer, codec)?,
+                None => savefile::save(&mut writer, SHORTEST_PATH_VERSION, &paths)?,
+            }
             writer.flush()?;
             bar.finish_and_clear();
             Ok(())
         }
-        Command::Search { routes, effects } => {
+        Command::Search {
+            routes,
+            effects,
+            exclude,
+            index,
+        } => {
             let bar = ProgressBar::new_spinner();
             bar.enable_steady_tick(Duration::from_millis(100));
 
-            let target_effects =
-                bitflags::parser::from_str_strict(&effects).map_err(|e| e.to_string())?;
+            let target_effects = rules.effects().parse(&effects)?;
+            let excluded_effects = match exclude {
+                Some(exclude) => rules.effects().parse(&exclude)?,
+                None => Effects::empty(),
+            };
 
             bar.set_message("Loading routes");
-            let shortest_paths: FlattenedResultsFile =
-                savefile::load_file(routes, SHORTEST_PATH_VERSION)?;
+            let shortest_paths = loader.routes(&routes)?;
+            let index = match index {
+                Some(path) => {
+                    bar.set_message("Loading index");
+                    Some(loader.index(&path)?)
+                }
+                None => None,
+            };
 
             bar.set_message("Searching for matching routes");
+            let records = filtered_search_records(
+                &rules,
+                &encoder,
+                &shortest_paths,
+                index.as_ref(),
+                target_effects,
+                excluded_effects,
+            );
+            bar.finish_and_clear();
 
-            bar.set_message("Searching for matching routes");
-            for (drug, (lowest_cost, shortest), paths) in [
-                (Drugs::OGKush, &shortest_paths.kush),
-                (Drugs::SourDiesel, &shortest_paths.sour_diesel),
-                (Drugs::GreenCrack, &shortest_paths.green_crack),
-                (Drugs::GranddaddyPurple, &shortest_paths.granddaddy_purple),
-                (Drugs::Meth, &shortest_paths.meth_cocaine),
-            ]
-            .par_iter()
-            .filter_map(|(d, fp)| {
-                search_inexact(target_effects, &encoder, fp).map(|p| (*d, p, *fp))
-            })
-            .collect::<Vec<_>>()
-            {
-                bar.finish_and_clear();
-                println!("{drug:?}");
-                for (title, (idx, label)) in [("Lowest Cost", lowest_cost), ("Shortest", shortest)]
-                {
-                    let p = trace_path(label, paths);
+            if format != Format::Text {
+                return write_records(format, &records);
+            }
+
+            for record in records.chunks(2) {
+                println!("{}", record[0].drug);
+                for (title, record) in ["Lowest Cost", "Shortest"].iter().zip(record) {
                     println!(
-                            "  {title}:\n    Effects: {:?}\n    Cost: {}\n    Length: {}\n    Path: {:?}",
-                            Effects::from(encoder.decode(idx as u32)),
-                            label.cost,
-                            label.length,
-                            p
-                        )
+                        "  {title}:\n    Effects: {}\n    Cost: {}\n    Length: {}\n    Path: {}",
+                        render.effects(Effects::from(encoder.decode(record.index))),
+                        record.cost,
+                        record.length,
+                        render.substances(&record.ingredients)
+                    )
                 }
                 println!();
             }
 
             Ok(())
         }
+        Command::Reachability {
+            graph,
+            reachable,
+            output_file,
+        } => {
+            let bar = ProgressBar::new_spinner();
+            bar.enable_steady_tick(Duration::from_millis(100));
+            let sets = match (graph, reachable) {
+                (_, Some(reachable)) => {
+                    bar.set_message("Loading reachable effect sets");
+                    loader.reachable(&reachable)?
+                }
+                (Some(graph), None) => {
+                    bar.set_message("Loading graph");
+                    let graph = loader.graph(graph)?;
+                    bar.set_message("Finding reachable effect sets");
+                    ReachableSets::build(&rules, &graph)
+                }
+                (None, None) => unreachable!("clap requires --graph or --reachable"),
+            };
+            if let Some(output_file) = output_file {
+                bar.set_message("Serializing reachable effect sets");
+                let output_file = OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(output_file)?;
+                let mut writer = BufWriter::new(output_file);
+                sets.serialize(&mut writer)?;
+                writer.flush()?;
+            }
+
+            bar.set_message("Counting effects");
+            let records = reachability_records(&rules, &encoder, &sets);
+            bar.finish_and_clear();
+
+            if format != Format::Text {
+                return write_records(format, &records);
+            }
+
+            for record in records.iter().filter(|r| r.section == "size") {
+                if record.size == Some(0) {
+                    match record.drug {
+                        Some(d) => println!("{d}"),
+                        None => println!("Any product"),
+                    }
+                }
+                println!(
+                    "  {} effects: {} of {} reachable",
+                    record.size.unwrap_or_default(),
+                    record.reachable.unwrap_or_default(),
+                    record.effect_sets.unwrap_or_default()
+                );
+            }
+            let never = records
+                .iter()
+                .filter(|r| r.section == "never")
+                .flat_map(|r| r.effects.iter().map(String::as_str))
+                .collect::<Vec<_>>();
+            if !never.is_empty() {
+                println!("Never reachable: {}", never.join(", "));
+            }
+            let exclusive = records
+                .iter()
+                .filter(|r| r.section == "exclusive")
+                .collect::<Vec<_>>();
+            if !exclusive.is_empty() {
+                println!("Never together:");
+                for record in exclusive {
+                    println!("  {}", record.effects.join(", "));
+                }
+            }
+            Ok(())
+        }
+        Command::Index {
+            routes,
+            output_file,
+        } => {
+            let bar = ProgressBar::new_spinner();
+            bar.enable_steady_tick(Duration::from_millis(100));
+            bar.set_message("Loading routes");
+            let routes = loader.routes(&routes)?;
+
+            bar.set_message("Building index");
+            let index = RouteIndex::build(&encoder, &routes);
+
+            bar.set_message("Serializing index");
+            let output_file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(output_file)?;
+            let mut writer = BufWriter::new(output_file);
+            index.serialize(&mut writer)?;
+            writer.flush()?;
+            bar.finish_and_clear();
+            Ok(())
+        }
+        Command::Export {
+            routes,
+            output_file,
+            table_format,
+            markup,
+            max_price,
+        } => {
+            let bar = ProgressBar::new_spinner();
+            bar.enable_steady_tick(Duration::from_millis(100));
+            bar.set_message("Loading routes");
+            let routes = loader.routes(&routes)?;
+
+            bar.set_message("Exporting routes");
+            let output_file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(output_file)?;
+            let mut writer = BufWriter::new(output_file);
+            let records = export_records(&rules, &encoder, &routes, markup, max_price);
+            let rows = write_table(table_format, records, &mut writer)?;
+            writer.flush()?;
+            bar.finish_and_clear();
+            if format == Format::Text {
+                println!("Exported {rows} routes");
+            }
+            Ok(())
+        }
+        Command::ExportGraph {
+            graph,
+            drug,
+            max_depth,
+            max_cost,
+            output_file,
+            graph_format,
+        } => {
+            let drug = parse_drug(&drug)?;
+            let bar = ProgressBar::new_spinner();
+            bar.enable_steady_tick(Duration::from_millis(100));
+            bar.set_message("Loading graph");
+            let graph = loader.graph(graph)?;
+
+            bar.set_message("Extracting subgraph");
+            let limits = Limits {
+                max_depth,
+                max_cost,
+            };
+            let subgraph = Subgraph::extract(&rules, &graph, drug, limits);
+            let output_file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(output_file)?;
+            let mut writer = BufWriter::new(output_file);
+            match graph_format {
+                GraphFormat::Dot => write_dot(&rules, &subgraph, &mut writer)?,
+                GraphFormat::Graphml => write_graphml(&rules, &subgraph, &mut writer)?,
+            }
+            writer.flush()?;
+            bar.finish_and_clear();
+            if format == Format::Text {
+                println!(
+                    "Exported {} effect sets and {} edges",
+                    subgraph.nodes.len(),
+                    subgraph.edges.len()
+                );
+            }
+            Ok(())
+        }
         Command::Lookup {
             routes,
             effects,
@@ -440,45 +954,43 @@ fn main() -> Result<(), Box<dyn Error>> {
             bar.enable_steady_tick(Duration::from_millis(100));
 
             let index = match (index, effects) {
-                (Some(i), _) => i,
+                (Some(i), _) => encoder.try_decode(i).map(|_| i)?,
                 (None, Some(e)) => {
-                    let effects: Effects =
-                        bitflags::parser::from_str_strict(&e).map_err(|e| e.to_string())?;
-                    encoder.encode(effects.bits())
+                    let effects = rules.effects().parse(&e)?;
+                    encoder.try_encode(effects.bits())?
                 }
                 _ => panic!("index and effects cannot both be None"),
             };
 
             bar.set_message("Loading routes");
-            let shortest_paths: FlattenedResultsFile =
-                savefile::load_file(routes, SHORTEST_PATH_VERSION)?;
+            let shortest_paths = loader.routes(&routes)?;
+
+            let records = lookup_records(&rules, &encoder, &shortest_paths, index);
+            bar.finish_and_clear();
+
+            if format != Format::Text {
+                return write_records(format, &records);
+            }
 
-            println!("Effects: {:?}", Effects::from(encoder.decode(index)));
+            println!(
+                "Effects: {}",
+                render.effects(Effects::from(encoder.decode(index)))
+            );
             println!("Index: {index}");
 
-            for (drug, paths) in [
-                (Drugs::OGKush, &shortest_paths.kush),
-                (Drugs::SourDiesel, &shortest_paths.sour_diesel),
-                (Drugs::GreenCrack, &shortest_paths.green_crack),
-                (Drugs::GranddaddyPurple, &shortest_paths.granddaddy_purple),
-                (Drugs::Meth, &shortest_paths.meth_cocaine),
-            ]
-            .iter()
-            .map(|(d, fp)| (d, lookup(index, fp)))
-            .collect::<Vec<_>>()
-            {
-                println!("{drug:?}");
-                for path in paths {
-                    let cost: i64 = path.iter().copied().map(substance_cost).sum();
+            for (drug, _) in shortest_paths.by_drug() {
+                println!("{drug}");
+                for record in records.iter().filter(|r| r.drug == drug) {
                     println!(
-                        "  cost: {cost}, length: {}, substances: {path:?}",
-                        path.len()
+                        "  cost: {}, length: {}, substances: {}",
+                        record.cost,
+                        record.length,
+                        render.substances(&record.ingredients)
                     );
                 }
                 println!();
             }
 
-            bar.finish_and_clear();
             Ok(())
         }
         Command::Profit {
@@ -489,94 +1001,92 @@ fn main() -> Result<(), Box<dyn Error>> {
             max_results,
             json,
         } => {
-            let shortest_paths: FlattenedResultsFile =
-                savefile::load_file(routes, SHORTEST_PATH_VERSION)?;
-
-            let max_mixins = max_mixins.unwrap_or(PathLength::MAX);
-
-            for (drug, fp, results) in [
-                (Drugs::OGKush, &shortest_paths.kush),
-                (Drugs::SourDiesel, &shortest_paths.sour_diesel),
-                (Drugs::GreenCrack, &shortest_paths.green_crack),
-                (Drugs::GranddaddyPurple, &shortest_paths.granddaddy_purple),
-                (Drugs::Meth, &shortest_paths.meth_cocaine),
-                (Drugs::Cocaine, &shortest_paths.meth_cocaine),
-            ]
-            .par_iter()
-            .copied()
-            .map(|(d, fp)| {
-                let mut top = TopSet::new(max_results, PartialOrd::gt);
-                let base_price = base_price(d) * (1. + markup);
-                for idx in 0..encoder.maximum_index() as usize {
-                    let mult = shortest_paths.price_multipliers[idx] as f64 / 100.;
-                    let best = fp
-                        .get(idx)
-                        .iter()
-                        .filter(|label| label.length <= max_mixins)
-                        .min_by_key(|l| l.cost);
-                    if let Some(best) = best {
-                        let sell_price = max_price.min((base_price * mult).round() as Cost) as i32;
-                        let profit = sell_price - best.cost as i32;
-                        top.insert((profit, sell_price, idx, best));
-                    }
-                }
+            let format = if json { Format::Ndjson } else { format };
+            let shortest_paths = loader.routes(&routes)?;
 
-                (d, fp, top)
-            })
-            .collect::<Vec<_>>()
-            {
-                let mut results = results.into_sorted_vec();
-                results.reverse();
-
-                if !json {
-                    println!("\n{drug}");
-                }
+            let query = ProfitQuery {
+                max_mixins: max_mixins.unwrap_or(PathLength::MAX),
+                markup,
+                max_price,
+                max_results,
+            };
+            let results = profit_records(&rules, &encoder, &shortest_paths, &query, None);
 
-                for (profit, sell_price, idx, label) in results {
-                    let path = trace_path(*label, fp);
-                    if json {
-                        #[derive(Serialize)]
-                        struct Output<'s> {
-                            drug: Drugs,
-                            effects: Effects,
-                            sell_price: i32,
-                            cost: Cost,
-                            profit: i32,
-                            ingredients: &'s [Substance],
-                        }
+            if format != Format::Text {
+                let records = results.into_iter().flat_map(|(_, r)| r).collect::<Vec<_>>();
+                return write_records(format, &records);
+            }
 
-                        serde_json::to_writer(
-                            stdout(),
-                            &Output {
-                                drug,
-                                effects: Effects::from(encoder.decode(idx as u32)),
-                                sell_price,
-                                cost: label.cost,
-                                profit,
-                                ingredients: &path,
-                            },
-                        )?;
-                        println!();
-                    } else {
-                        println!(
-                            "{:?}\n  Sell Price: {sell_price}\n  Cost: {}\n  Profit: {profit}\n  Ingredients: {path:?}\n",
-                            Effects::from(encoder.decode(idx as u32)),
-                            label.cost,
-                        );
-                    }
+            for (drug, records) in results {
+                println!("\n{drug}");
+                for record in records {
+                    println!(
+                        "{}\n  Sell Price: {}\n  Cost: {}\n  Profit: {}\n  Ingredients: {}\n",
+                        render.effects(Effects::from(encoder.decode(record.index))),
+                        record.sell_price,
+                        record.cost,
+                        record.profit,
+                        render.substances(&record.ingredients),
+                    );
                 }
             }
             Ok(())
         }
         Command::Metadata { graph, routes } => {
-            if let Some(g) = graph {
-                let graph: EffectGraph<NUM_EFFECTS, MAX_EFFECTS> =
-                    savefile::load_file(g, GRAPH_VERSION)?;
-                graph_metadata(&graph);
+            // Describe mismatched files rather than refusing them.
+            let loader = FileLoader {
+                allow_mismatch: true,
+                ..loader
+            };
+            let graph_stats = match graph {
+                Some(g) => {
+                    let graph = loader.graph(&g)?;
+                    let sizes = FileInfo::new("graph", &g, graph.header(), GRAPH_VERSION, &graph)?;
+                    Some((graph_metadata(&graph), sizes))
+                }
+                None => None,
+            };
+            let route_stats = match routes {
+                Some(r) => {
+                    let routes = loader.routes(&r)?;
+                    let sizes = FileInfo::new(
+                        "routes",
+                        &r,
+                        &routes.header,
+                        SHORTEST_PATH_VERSION,
+                        &routes,
+                    )?;
+                    Some((routes_metadata(&routes), sizes))
+                }
+                None => None,
+            };
+
+            if format != Format::Text {
+                let records = graph_stats
+                    .iter()
+                    .flat_map(|(stats, sizes)| stats.records().into_iter().chain(sizes.records()))
+                    .chain(route_stats.iter().flat_map(|(stats, sizes)| {
+                        stats
+                            .iter()
+                            .flat_map(RouteStats::records)
+                            .chain(sizes.records())
+                    }))
+                    .collect::<Vec<_>>();
+                return write_records(format, &records);
             }
-            if let Some(r) = routes {
-                let routes = savefile::load_file(r, SHORTEST_PATH_VERSION)?;
-                routes_metadata(&routes);
+
+            if let Some((stats, sizes)) = graph_stats {
+                stats.print();
+                sizes.print();
+                println!();
+            }
+            if let Some((route_stats, sizes)) = route_stats {
+                println!("---------\nRoute metadata:");
+                println!("size_of::<Label>() = {}", size_of::<Label>());
+                sizes.print();
+                for stats in route_stats {
+                    stats.print();
+                }
             }
             Ok(())
         }
@@ -585,28 +1095,278 @@ fn main() -> Result<(), Box<dyn Error>> {
             bar.enable_steady_tick(Duration::from_millis(100));
 
             bar.set_message("Loading routes");
-            let routes = savefile::load_file(routes, SHORTEST_PATH_VERSION)?;
+            let routes = loader.routes(&routes)?;
             let cases: &[(&str, CheckFun)] = &[
                 ("pareto optimality", check_pareto_optimality),
                 ("path cost", check_route_costs),
+                ("path replay", check_route_replay),
             ];
-            let max_idx = encoder.maximum_index();
+            let mut records = Vec::new();
             for (label, fun) in cases {
                 bar.set_message(format!("Checking {label}"));
-                let results = fun(&routes, max_idx);
+                let results = fun(&rules, &encoder, &routes);
+                if format == Format::Text {
+                    bar.suspend(|| {
+                        println!(
+                            "{label}: {} violations found",
+                            results.as_ref().map(|r| r.len()).unwrap_or(0)
+                        );
+                        if let Some(r) = &results {
+                            println!("First violations (up to 10):");
+                            for (drug, idx, path) in r.iter().take(10) {
+                                let path = path
+                                    .iter()
+                                    .map(|s| render.substance(*s).to_string())
+                                    .collect::<Vec<_>>();
+                                println!("{drug} {idx}: {}", path.join(", "));
+                            }
+                        }
+                    });
+                    continue;
+                }
+
+                let results = results.unwrap_or_default();
+                for drug in STARTING_DRUGS {
+                    let violations = results.iter().filter(|(d, _, _)| *d == drug);
+                    records.push(SanityRecord {
+                        check: label,
+                        drug,
+                        violations: violations.clone().count(),
+                        examples: violations
+                            .clone()
+                            .take(10)
+                            .map(|(_, idx, _)| *idx)
+                            .collect(),
+                        example_paths: violations
+                            .take(10)
+                            .map(|(_, _, path)| {
+                                path.iter()
+                                    .map(|s| format!("{s:?}"))
+                                    .collect::<Vec<_>>()
+                                    .join(",")
+                            })
+                            .collect(),
+                    });
+                }
+            }
+            bar.finish_and_clear();
+
+            if format != Format::Text {
+                return write_records(format, &records);
+            }
+            Ok(())
+        }
+        Command::Simulate { drug, substances } => {
+            let drug = parse_drug(&drug)?;
+            let substances = rules.parse_substances(&substances)?;
+            let records = simulate(&rules, None, drug, &substances);
+
+            if format != Format::Text {
+                return write_records(format, &records);
+            }
+
+            let (start, steps) = records.split_first().expect("should include the drug");
+            println!("{drug}: {}", render.effects(rules.drug_effects(drug)));
+            let mut effects = rules.drug_effects(drug);
+            for (record, substance) in steps.iter().zip(substances) {
+                effects = rules.apply(substance, effects);
                 println!(
-                    "{label}: {} violations found",
-                    results.as_ref().map(|r| r.len()).unwrap_or(0)
+                    "  {}. {}: {}",
+                    record.step,
+                    render.substance(substance),
+                    render.effects(effects)
                 );
-                if let Some(r) = results {
-                    println!("First violations (up to 10):");
-                    for rr in r.into_iter().take(10) {
-                        println!("{rr:?}");
-                    }
+            }
+
+            let last = steps.last().unwrap_or(start);
+            println!("Sell Price: {}\nCost: {}", last.sell_price, last.cost);
+            Ok(())
+        }
+        Command::Serve {
+            routes,
+            graph,
+            address,
+            workers,
+        } => {
+            let state = load_state(rules, &routes, graph, args.allow_mismatched_files)?;
+            let server = QueryServer::bind(address.as_str(), state).map_err(|e| e.to_string())?;
+
+            match server.local_addr() {
+                Some(addr) => eprintln!("Listening on http://{addr}"),
+                None => eprintln!("Listening on {address}"),
+            }
+            server.run(workers)?;
+            Ok(())
+        }
+        Command::Repl {
+            routes,
+            graph,
+            history,
+        } => {
+            let color = render.color();
+            let state = load_state(rules, &routes, graph, args.allow_mismatched_files)?;
+            repl::run(Session::new(state, color, format), history.as_deref())
+        }
+        Command::VerifyRecipes { book, routes } => {
+            let recipes = RecipeBook::load(book)?.resolve(&rules)?;
+            let routes = match routes {
+                Some(path) => {
+                    let bar = ProgressBar::new_spinner();
+                    bar.enable_steady_tick(Duration::from_millis(100));
+                    bar.set_message("Loading routes");
+                    let routes = loader.routes(&path)?;
+                    bar.finish_and_clear();
+                    Some(routes)
+                }
+                None => None,
+            };
+            let records = recipes
+                .iter()
+                .map(|recipe| verify_recipe(&rules, &encoder, routes.as_ref(), recipe))
+                .collect::<Vec<_>>();
+
+            if format != Format::Text {
+                return write_records(format, &records);
+            }
+
+            let substances = |substances: &[_]| {
+                substances
+                    .iter()
+                    .map(|s| render.substance(*s).to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            };
+            for record in &records {
+                println!(
+                    "{} ({}: {})",
+                    record.name,
+                    record.drug,
+                    substances(&record.ingredients)
+                );
+                println!("  Effects: {}", record.effects.join(", "));
+                println!(
+                    "  Sell Price: {}, Cost: {}, Length: {}",
+                    record.sell_price, record.cost, record.length
+                );
+                if let (Some(cost), Some(length)) = (record.better_cost, record.better_length) {
+                    println!(
+                        "  Dominated by {} (Cost: {cost}, Length: {length})",
+                        substances(&record.better_ingredients)
+                    );
                 }
             }
+            Ok(())
+        }
+        Command::DiffRules {
+            new_rules,
+            recipe,
+            book,
+        } => {
+            let new = parse_rules_file(new_rules)?;
+            let mut recipes = recipe
+                .iter()
+                .map(|line| parse_recipe(&rules, line))
+                .collect::<Result<Vec<_>, _>>()?;
+            if let Some(path) = book {
+                recipes.extend(
+                    RecipeBook::load(path)?
+                        .resolve(&rules)?
+                        .into_iter()
+                        .map(|r| (r.drug, r.substances)),
+                );
+            }
 
+            let bar = ProgressBar::new_spinner();
+            bar.enable_steady_tick(Duration::from_millis(100));
+            bar.set_message("Comparing transitions");
+            let transitions = transition_diff(&rules, &new);
+            bar.finish_and_clear();
+            let diff = RulesDiff {
+                rules: rule_changes(&rules, &new),
+                transitions: transitions.clone().unwrap_or_default(),
+                recipes: recipes
+                    .iter()
+                    .map(|(drug, ingredients)| recipe_diff(&rules, &new, *drug, ingredients))
+                    .collect(),
+            };
+            if let Err(e) = transitions {
+                eprintln!("Skipping transitions: {e}");
+            }
+
+            if format != Format::Text {
+                return write_records(format, &diff.records());
+            }
+
+            println!("Rules: {} changed", diff.rules.len());
+            for change in &diff.rules {
+                println!(
+                    "  {} {}",
+                    render.substance(change.substance),
+                    change.change.as_str()
+                );
+                if let Some(before) = &change.before {
+                    println!("    - {before}");
+                }
+                if let Some(after) = &change.after {
+                    println!("    + {after}");
+                }
+            }
+            if !diff.transitions.is_empty() {
+                let differing = diff.transitions.iter().map(|t| t.differing).sum::<u64>();
+                let total = diff.transitions.iter().map(|t| t.transitions).sum::<u64>();
+                println!("Transitions: {differing} of {total} differ");
+                for t in diff.transitions.iter().filter(|t| t.differing > 0) {
+                    println!(
+                        "  {}: {} of {}",
+                        render.substance(t.substance),
+                        t.differing,
+                        t.transitions
+                    );
+                    for idx in &t.examples {
+                        let effects = Effects::from(encoder.decode(*idx));
+                        println!(
+                            "    {}: {} -> {}",
+                            render.effects(effects),
+                            render.effects(rules.apply(t.substance, effects)),
+                            new.effects().display(new.apply(t.substance, effects))
+                        );
+                    }
+                }
+            }
+            if !diff.recipes.is_empty() {
+                println!("Recipes:");
+                for recipe in &diff.recipes {
+                    let ingredients = recipe
+                        .ingredients
+                        .iter()
+                        .map(|s| render.substance(*s).to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    println!(
+                        "  {}: {ingredients}{}",
+                        recipe.drug,
+                        if recipe.changed() { "" } else { " (unchanged)" }
+                    );
+                    println!(
+                        "    - {} ({})",
+                        recipe.before.join(", "),
+                        recipe.before_price
+                    );
+                    println!("    + {} ({})", recipe.after.join(", "), recipe.after_price);
+                }
+            }
             Ok(())
         }
+        Command::NormalizeRules { output } => {
+            let file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(output)?;
+            let mut writer = BufWriter::new(file);
+            serde_json::to_writer_pretty(&mut writer, &rules)?;
+            writeln!(writer)?;
+            writer.flush().map_err(Into::into)
+        }
     }
 }
@@ -1,31 +1,72 @@
 use savefile_derive::Savefile;
 use serde::{Deserialize, Serialize};
+use std::iter::FusedIterator;
+use std::ops::Range;
+use std::sync::OnceLock;
 
 /// The Combinatorial Encoder uses a combinatorial number system to uniquely identify a particular
 /// combination of K elements out of a set of N possibilities using a single integer. This mapping
-/// is contiguous, one-to-one, and preserves the lexicographical order of combinations. That is, if
-/// `A` and `B` are two combinations and `A < B` (lexicographically), then `encode(A) < encode(B)`.
+/// is contiguous and one-to-one. Combinations are ordered by size, and combinations of the same
+/// size are in colexicographic order, comparing their largest elements first. That is, if `A` and
+/// `B` are two combinations of the same size and `A < B` (colexicographically, or equivalently as
+/// bitsets compared as integers), then `encode(A) < encode(B)`.
 ///
 /// Let $i = 0, ..., N-1$ represent the elements of the set of N possibilities. For a given
 /// combination of $k$ elements, let $0 <= c_1 < c_2 < ... < c_k$. Then the combinatorial index is
 /// given by the following integer $N$:
 /// $$ N = nCr(c_k, k) + ... + nCr(c_1, 1) $$
 /// where $nCr$ is the notation for "n choose r". This encoding works for a particular, fixed value
-/// of $k$. To handle combinations of up to length `MAX_K`, the encoding is offset so that lower
+/// of $k$. To handle combinations of up to length `max_k`, the encoding is offset so that lower
 /// values of $k$ precede larger values of $k$. E.g., the encoding for ${}$ (i.e., `nCr(N, 0)`) is
 /// 0, ${0}, {1}, ..., {N-1}$ are mapped to $1, ..., N$, and ${0, 1}, {0, 2}, ...$ are mapped to
 /// $N+1, ..., N + nCr(N, 2)$.
 ///
 /// We assume that the combination is represented as bitflags within a `u64` and provide methods for
-/// _encoding_ (combination -> index) and _decoding_ (index -> combination).
-#[derive(Savefile, Serialize, Deserialize)]
-pub struct CombinatorialEncoder<const N: u8, const MAX_K: u8> {
+/// _encoding_ (combination -> index) and _decoding_ (index -> combination). The number of elements
+/// `n` and the maximum combination length `max_k` are runtime parameters, typically taken from the
+/// rules file.
+#[derive(Debug, Clone, PartialEq, Eq, Savefile, Serialize, Deserialize)]
+pub struct CombinatorialEncoder {
+    /// Number of elements in the set of possibilities.
+    #[savefile_versions = "3.."]
+    #[savefile_default_val = "34"]
+    n: u8,
+    /// Maximum number of elements in an encoded combination.
+    #[savefile_versions = "3.."]
+    #[savefile_default_val = "8"]
+    max_k: u8,
     /// Binomial coefficients (i.e., Pascal's triangle) stored in column-major order
     binom: Vec<u32>,
-    /// Offsets for combinations of length $k < MAX_K$.
+    /// Offsets for combinations of length $k < max_k$.
     size_offsets: Vec<u32>,
+    /// Binomial coefficients padded with zeros for the batch methods, built on first use.
+    #[savefile_ignore]
+    #[savefile_introspect_ignore]
+    #[serde(skip)]
+    columns: Columns,
 }
 
+/// `C(e, k)` for every element `0 <= e <= n` and `1 <= k <= max_k`, stored as one column of `n + 1`
+/// entries per `k`. Unlike the triangle, coefficients with `e < k` are stored as zeros, so lookups
+/// need no bounds checks and each column is sorted for binary searches.
+#[derive(Debug, Clone, Default)]
+struct Columns(OnceLock<Vec<u32>>);
+
+// The table is derived from the other fields, so it never makes two encoders differ.
+impl PartialEq for Columns {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for Columns {}
+
+// Required of ignored fields, the default never allows the packed optimisation.
+impl savefile::Packed for Columns {}
+
+/// Largest number of elements that fit in the `u64` bitset representation.
+pub const MAX_ELEMENTS: u8 = u64::BITS as u8;
+
 /// Computes the index into the column-major flat array for the given row/column of pascal's triangle.
 fn triangle_index(row: u8, column: u8, n: u8) -> usize {
     column as usize * (2 * (n as usize) - (column as usize) + 1) / 2 + (row as usize)
@@ -39,54 +80,122 @@ fn binomial_coeff(triangle: &[u32], row: u8, column: u8, n: u8) -> u32 {
     }
 }
 
-impl<const N: u8, const MAX_K: u8> Default for CombinatorialEncoder<N, MAX_K> {
-    fn default() -> Self {
-        Self::new()
-    }
-}
+impl CombinatorialEncoder {
+    /// Creates an encoder for combinations of up to `max_k` elements out of `n`.
+    ///
+    /// Panics if the parameters are invalid, see [`CombinatorialEncoder::validate`].
+    pub fn new(n: u8, max_k: u8) -> Self {
+        if let Err(e) = Self::validate(n, max_k) {
+            panic!("invalid encoder parameters: {e}");
+        }
 
-impl<const N: u8, const MAX_K: u8> CombinatorialEncoder<N, MAX_K> {
-    pub fn new() -> Self {
-        let n = N as usize;
-        let mut binom = vec![0u32; (n + 1) * (n + 2) / 2];
+        let mut binom = vec![0u32; (n as usize + 1) * (n as usize + 2) / 2];
 
         // Build pascal's triangle using the relationship: (n choose k) = (n-1 choose k-1) + (n-1 choose k)
         // Add the (0,0)th entry.
         binom[0] = 1;
 
-        for row in 1..=N {
+        // Columns beyond `max_k` are never read, so they are allowed to saturate for large `n`.
+        for row in 1..=n {
             for column in 0..=row {
-                binom[triangle_index(row, column, N)] = if column == 0 || column == row {
-                    1
-                } else {
-                    binomial_coeff(&binom, row - 1, column - 1, N)
-                        + binomial_coeff(&binom, row - 1, column, N)
-                };
+                binom[triangle_index(row, column, n)] =
+                    if column == 0 || column == row {
+                        1
+                    } else {
+                        binomial_coeff(&binom, row - 1, column - 1, n)
+                            .saturating_add(binomial_coeff(&binom, row - 1, column, n))
+                    };
             }
         }
 
-        let mut size_offsets = vec![0; (MAX_K + 2) as usize];
+        let mut size_offsets = vec![0; (max_k + 2) as usize];
         let mut running_total = 0u32;
-        for k in 0..MAX_K + 1 {
+        for k in 0..max_k + 1 {
             size_offsets[k as usize] = running_total;
-            running_total += binom[triangle_index(N, k, N)];
+            running_total += binom[triangle_index(n, k, n)];
         }
         // Record maximum value as well
-        size_offsets[MAX_K as usize + 1] = running_total;
+        size_offsets[max_k as usize + 1] = running_total;
 
         Self {
+            n,
+            max_k,
             binom,
             size_offsets,
+            columns: Columns::default(),
         }
     }
 
+    /// Checks that combinations of up to `max_k` out of `n` elements can be represented, i.e.,
+    /// that the elements fit in a `u64` bitset and the total number of combinations fits in a
+    /// `u32` index.
+    pub fn validate(n: u8, max_k: u8) -> Result<(), String> {
+        if n > MAX_ELEMENTS {
+            return Err(format!(
+                "cannot encode more than {MAX_ELEMENTS} elements, got {n}"
+            ));
+        }
+        if max_k > n {
+            return Err(format!(
+                "maximum combination length {max_k} exceeds the number of elements {n}"
+            ));
+        }
+
+        // Compute the binomial coefficients n choose k incrementally to check for overflow. Every
+        // intermediate coefficient fits in a u32, so the multiplication cannot overflow a u64.
+        let mut total = 1u32;
+        let mut coeff = 1u64;
+        for k in 1..=max_k as u64 {
+            coeff = coeff * (n as u64 + 1 - k) / k;
+            total = u32::try_from(coeff)
+                .ok()
+                .and_then(|c| total.checked_add(c))
+                .ok_or_else(|| {
+                    format!(
+                        "combinations of up to {max_k} out of {n} elements overflow a u32 index"
+                    )
+                })?;
+        }
+        Ok(())
+    }
+
+    /// Number of elements in the set of possibilities.
+    pub fn num_elements(&self) -> u8 {
+        self.n
+    }
+
+    /// Maximum number of elements in an encoded combination.
+    pub fn max_elements(&self) -> u8 {
+        self.max_k
+    }
+
     /// Encodes a combination (represented as a bitset) as an integer.
+    ///
+    /// Panics if the combination cannot be encoded, see [`CombinatorialEncoder::try_encode`].
     pub fn encode(&self, bitset: u64) -> u32 {
+        match self.try_encode(bitset) {
+            Ok(index) => index,
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    /// Encodes a combination (represented as a bitset) as an integer, failing if it has more than
+    /// `max_k` elements or elements outside of the `n` possibilities.
+    pub fn try_encode(&self, bitset: u64) -> Result<u32, String> {
         let k = bitset.count_ones() as usize;
-        assert!(
-            k <= MAX_K as usize,
-            "can only encode up to MAX_K items in a combination"
-        );
+        if k > self.max_k as usize {
+            return Err(format!(
+                "cannot combine {k} elements, at most {} are allowed",
+                self.max_k
+            ));
+        }
+        if self.n < MAX_ELEMENTS && bitset >> self.n != 0 {
+            return Err(format!(
+                "element {} is out of range, there are only {}",
+                u64::BITS - 1 - bitset.leading_zeros(),
+                self.n
+            ));
+        }
 
         let mut local_idx = 0;
         let mut remaining = bitset;
@@ -98,16 +207,34 @@ impl<const N: u8, const MAX_K: u8> CombinatorialEncoder<N, MAX_K> {
             local_idx += if elem < counter as u32 {
                 0
             } else {
-                self.binom[triangle_index(elem as u8, counter, N)]
+                self.binom[triangle_index(elem as u8, counter, self.n)]
             };
             counter += 1;
         }
 
-        self.size_offsets[k] + local_idx
+        Ok(self.size_offsets[k] + local_idx)
     }
 
     /// Decodes an integer into a combination.
+    ///
+    /// Panics if the index is out of range, see [`CombinatorialEncoder::try_decode`].
     pub fn decode(&self, index: u32) -> u64 {
+        match self.try_decode(index) {
+            Ok(bitset) => bitset,
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    /// Decodes an integer into a combination, failing if it is not below
+    /// [`CombinatorialEncoder::maximum_index`].
+    pub fn try_decode(&self, index: u32) -> Result<u64, String> {
+        if index >= self.maximum_index() {
+            return Err(format!(
+                "index {index} is out of range, there are only {} combinations",
+                self.maximum_index()
+            ));
+        }
+
         let mut k = self
             .size_offsets
             .partition_point(|x| *x <= index)
@@ -117,14 +244,14 @@ impl<const N: u8, const MAX_K: u8> CombinatorialEncoder<N, MAX_K> {
 
         let mut local_idx = index - self.size_offsets[k as usize];
         while k > 0 {
-            let mut elem = N;
-            let mut value = self.binom[triangle_index(elem, k, N)];
+            let mut elem = self.n;
+            let mut value = self.binom[triangle_index(elem, k, self.n)];
             while value > local_idx {
                 elem -= 1;
                 value = if elem < k {
                     0
                 } else {
-                    self.binom[triangle_index(elem, k, N)]
+                    self.binom[triangle_index(elem, k, self.n)]
                 };
             }
             bitset |= 1 << elem;
@@ -132,17 +259,300 @@ impl<const N: u8, const MAX_K: u8> CombinatorialEncoder<N, MAX_K> {
             k -= 1
         }
 
-        bitset
+        Ok(bitset)
     }
 
+    /// Number of combinations, i.e., one past the largest index.
     pub fn maximum_index(&self) -> u32 {
-        self.size_offsets[(MAX_K + 1) as usize]
+        self.size_offsets[(self.max_k + 1) as usize]
+    }
+
+    /// Indices of the combinations with exactly `k` elements, empty if `k > max_k`. The index of a
+    /// combination minus the start of its range is its rank within its size class.
+    pub fn size_range(&self, k: u8) -> Range<u32> {
+        if k > self.max_k {
+            let end = self.maximum_index();
+            return end..end;
+        }
+        self.size_offsets[k as usize]..self.size_offsets[k as usize + 1]
+    }
+
+    fn column_stride(&self) -> usize {
+        self.n as usize + 1
+    }
+
+    fn columns(&self) -> &[u32] {
+        self.columns.0.get_or_init(|| {
+            (1..=self.max_k)
+                .flat_map(|k| (0..=self.n).map(move |e| binomial_coeff(&self.binom, e, k, self.n)))
+                .collect()
+        })
+    }
+
+    /// Encodes every combination in `bitsets` into the same position of `indices`. Equivalent to
+    /// calling [`CombinatorialEncoder::encode`] on each, but faster for more than a few
+    /// combinations.
+    ///
+    /// This is a scalar loop: it gains by building the column table and checking bounds once per
+    /// batch. Each combination takes one table lookup per set bit, so lanes would need gathers of
+    /// different lengths, which stable Rust cannot vectorise portably.
+    ///
+    /// Panics if the slices differ in length or a combination cannot be encoded.
+    pub fn encode_many(&self, bitsets: &[u64], indices: &mut [u32]) {
+        assert_eq!(
+            bitsets.len(),
+            indices.len(),
+            "slices must have equal lengths"
+        );
+        let columns = self.columns();
+        let stride = self.column_stride();
+        for (bitset, index) in bitsets.iter().copied().zip(indices) {
+            let k = bitset.count_ones() as usize;
+            if k > self.max_k as usize || (self.n < MAX_ELEMENTS && bitset >> self.n != 0) {
+                panic!(
+                    "{}",
+                    self.try_encode(bitset).expect_err("should be invalid")
+                );
+            }
+
+            *index = self.size_offsets[k] + local_index(columns, stride, bitset);
+        }
+    }
+
+    /// Decodes every index in `indices` into the same position of `bitsets`. Equivalent to
+    /// calling [`CombinatorialEncoder::decode`] on each, but faster for more than a few indices.
+    ///
+    /// Like [`CombinatorialEncoder::encode_many`], this is a scalar loop over the shared table. Each
+    /// index takes a chain of binary searches, each bounded by the element found before it, which
+    /// does not map onto lanes either.
+    ///
+    /// Panics if the slices differ in length or an index is out of range.
+    pub fn decode_many(&self, indices: &[u32], bitsets: &mut [u64]) {
+        assert_eq!(
+            indices.len(),
+            bitsets.len(),
+            "slices must have equal lengths"
+        );
+        let columns = self.columns();
+        let stride = self.column_stride();
+        for (index, bitset) in indices.iter().copied().zip(bitsets) {
+            if index >= self.maximum_index() {
+                panic!(
+                    "{}",
+                    self.try_decode(index).expect_err("should be out of range")
+                );
+            }
+            let k = self.size_offsets.partition_point(|x| *x <= index) - 1;
+
+            let mut local_idx = index - self.size_offsets[k];
+            let mut upper = self.n as usize;
+            *bitset = 0;
+            for k in (1..=k).rev() {
+                // The largest element whose coefficient fits, below the previous element.
+                let column = &columns[(k - 1) * stride..(k - 1) * stride + upper];
+                let elem = column.partition_point(|c| *c <= local_idx) - 1;
+                *bitset |= 1 << elem;
+                local_idx -= column[elem];
+                upper = elem;
+            }
+        }
+    }
+
+    /// Every combination in order of its index, without decoding each one.
+    pub fn combinations(&self) -> Combinations {
+        Combinations {
+            n: self.n,
+            index: 0,
+            end: self.maximum_index(),
+            bitset: 0,
+        }
+    }
+
+    /// Every combination of exactly `k` elements in order of its index. Empty if `k > max_k`.
+    pub fn combinations_of_size(&self, k: u8) -> Combinations {
+        let range = self.size_range(k);
+        Combinations {
+            n: self.n,
+            index: range.start,
+            end: range.end,
+            bitset: u64::BITS
+                .checked_sub(k as u32)
+                .and_then(|shift| u64::MAX.checked_shr(shift))
+                .unwrap_or(0),
+        }
+    }
+
+    /// Every combination containing all elements of `bitset`, in order of its index. Empty if
+    /// `bitset` cannot be encoded.
+    pub fn supersets(&self, bitset: u64) -> MaskedCombinations<'_> {
+        let valid = self.try_encode(bitset).is_ok();
+        let free = if self.n == MAX_ELEMENTS {
+            !bitset
+        } else {
+            !bitset & ((1 << self.n) - 1)
+        };
+        MaskedCombinations::new(
+            self,
+            bitset,
+            free,
+            if valid {
+                Some(self.max_k - bitset.count_ones() as u8)
+            } else {
+                None
+            },
+        )
+    }
+
+    /// Every encodable combination of elements of `bitset`, i.e., with at most `max_k` of them, in
+    /// order of its index. Elements beyond `n` are ignored.
+    pub fn subsets(&self, bitset: u64) -> MaskedCombinations<'_> {
+        let bitset = if self.n == MAX_ELEMENTS {
+            bitset
+        } else {
+            bitset & ((1 << self.n) - 1)
+        };
+        MaskedCombinations::new(self, 0, bitset, Some(self.max_k))
     }
 }
 
+/// Sum of the coefficients of the elements of `bitset`, i.e., its index within its size class.
+fn local_index(columns: &[u32], stride: usize, bitset: u64) -> u32 {
+    let mut local_idx = 0;
+    let mut remaining = bitset;
+    let mut column = 0;
+    while remaining != 0 {
+        local_idx += columns[column + remaining.trailing_zeros() as usize];
+        remaining &= remaining - 1;
+        column += stride;
+    }
+    local_idx
+}
+
+/// Spreads the low bits of `compact` over the set bits of `mask`, lowest first.
+fn scatter(mut compact: u64, mut mask: u64) -> u64 {
+    let mut bitset = 0;
+    while compact != 0 && mask != 0 {
+        if compact & 1 != 0 {
+            bitset |= mask & mask.wrapping_neg();
+        }
+        compact >>= 1;
+        mask &= mask - 1;
+    }
+    bitset
+}
+
+/// Iterator over `(index, combination)` pairs for the combinations made of a fixed set of elements
+/// plus any others from a mask, see [`CombinatorialEncoder::supersets`] and
+/// [`CombinatorialEncoder::subsets`].
+///
+/// The extra elements are enumerated as combinations of the mask's bit positions, by size and then
+/// in colexicographic order. Spreading them over the mask preserves that order, so the results are
+/// in index order too.
+#[derive(Debug, Clone)]
+pub struct MaskedCombinations<'e> {
+    encoder: &'e CombinatorialEncoder,
+    fixed: u64,
+    mask: u64,
+    /// Current combination of the mask's bit positions, `None` once exhausted.
+    compact: Option<u64>,
+    max_extra: u32,
+}
+
+impl<'e> MaskedCombinations<'e> {
+    fn new(
+        encoder: &'e CombinatorialEncoder,
+        fixed: u64,
+        mask: u64,
+        max_extra: Option<u8>,
+    ) -> Self {
+        Self {
+            encoder,
+            fixed,
+            mask,
+            compact: max_extra.map(|_| 0),
+            max_extra: max_extra.map_or(0, u32::from),
+        }
+    }
+}
+
+impl Iterator for MaskedCombinations<'_> {
+    type Item = (u32, u64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let compact = self.compact?;
+        let bitset = self.fixed | scatter(compact, self.mask);
+        let encoder = self.encoder;
+        let k = bitset.count_ones() as usize;
+        let index = encoder.size_offsets[k]
+            + local_index(encoder.columns(), encoder.column_stride(), bitset);
+
+        let width = self.mask.count_ones();
+        self.compact = (compact.count_ones() < width)
+            .then(|| next_combination(compact, width as u8))
+            .filter(|next| next.count_ones() <= self.max_extra);
+        Some((index, bitset))
+    }
+}
+
+impl FusedIterator for MaskedCombinations<'_> {}
+
+/// Iterator over `(index, combination)` pairs in index order, see
+/// [`CombinatorialEncoder::combinations`].
+#[derive(Debug, Clone)]
+pub struct Combinations {
+    n: u8,
+    index: u32,
+    end: u32,
+    bitset: u64,
+}
+
+/// The combination following `bitset` in encoding order: the next larger bitset with the same
+/// number of elements (Gosper's hack), or the smallest one with an element more once those run
+/// out.
+fn next_combination(bitset: u64, n: u8) -> u64 {
+    let k = bitset.count_ones();
+    if k > 0 {
+        let lowest = bitset & bitset.wrapping_neg();
+        if let Some(ripple) = bitset.checked_add(lowest) {
+            let next = (((ripple ^ bitset) >> 2) / lowest) | ripple;
+            if n == MAX_ELEMENTS || next >> n == 0 {
+                return next;
+            }
+        }
+    }
+    u64::MAX >> (u64::BITS - k - 1)
+}
+
+impl Iterator for Combinations {
+    type Item = (u32, u64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.index >= self.end {
+            return None;
+        }
+        let item = (self.index, self.bitset);
+        self.index += 1;
+        if self.index < self.end {
+            self.bitset = next_combination(self.bitset, self.n);
+        }
+        Some(item)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = (self.end - self.index) as usize;
+        (remaining, Some(remaining))
+    }
+}
+
+impl ExactSizeIterator for Combinations {}
+
+impl FusedIterator for Combinations {}
+
 #[cfg(test)]
 mod tests {
-    use crate::combinatorial::{triangle_index, CombinatorialEncoder};
+    use crate::combinatorial::{triangle_index, CombinatorialEncoder, MAX_ELEMENTS};
+    use proptest::prelude::*;
+    use proptest::sample::subsequence;
 
     #[test]
     fn test_triangle_index() {
@@ -174,7 +584,7 @@ mod tests {
 
     #[test]
     fn test_five_rows() {
-        let encoder = CombinatorialEncoder::<5, 5>::new();
+        let encoder = CombinatorialEncoder::new(5, 5);
         // Below is the row-major ordering
         // let expected = vec![
         //     1, // row 0
@@ -193,7 +603,7 @@ mod tests {
 
     #[test]
     fn test_five_four() {
-        let encoder = CombinatorialEncoder::<5, 4>::new();
+        let encoder = CombinatorialEncoder::new(5, 4);
         // Below is the row-major order
         // let expected = vec![
         //     1, // row 0
@@ -212,7 +622,7 @@ mod tests {
 
     #[test]
     fn test_four_two() {
-        let encoder = CombinatorialEncoder::<4, 2>::new();
+        let encoder = CombinatorialEncoder::new(4, 2);
         // Below is the row-major order
         // let expected = vec![
         //     1, // row 0
@@ -228,7 +638,7 @@ mod tests {
 
     #[test]
     fn test_roundtrip() {
-        let encoder = CombinatorialEncoder::<4, 4>::new();
+        let encoder = CombinatorialEncoder::new(4, 4);
         let items = [
             0,                                 // 4 choose 0
             1 << 0,                            // 4 choose 1
@@ -261,7 +671,7 @@ mod tests {
 
     #[test]
     fn test_roundtrip_4_2() {
-        let encoder = CombinatorialEncoder::<4, 2>::new();
+        let encoder = CombinatorialEncoder::new(4, 2);
         let items = [
             0,               // 4 choose 0
             1 << 0,          // 4 choose 1
@@ -289,7 +699,7 @@ mod tests {
 
     #[test]
     fn test_expected_size_34_8() {
-        let encoder = CombinatorialEncoder::<34, 8>::new();
+        let encoder = CombinatorialEncoder::new(34, 8);
         assert_eq!(
             encoder.size_offsets,
             [
@@ -311,4 +721,177 @@ mod tests {
             1 + 34 + 561 + 5984 + 46376 + 278256 + 1344904 + 5379616 + 18156204
         )
     }
+
+    #[test]
+    fn test_validate() {
+        assert!(CombinatorialEncoder::validate(34, 8).is_ok());
+        assert!(CombinatorialEncoder::validate(64, 4).is_ok());
+        // More elements than bits in the bitset
+        assert!(CombinatorialEncoder::validate(65, 1).is_err());
+        // Cap larger than the number of elements
+        assert!(CombinatorialEncoder::validate(4, 5).is_err());
+        // Too many combinations to index with a u32
+        assert!(CombinatorialEncoder::validate(64, 8).is_err());
+    }
+
+    /// Valid encoder parameters, with `n` up to `max_n`.
+    fn parameters(max_n: u8) -> impl Strategy<Value = (u8, u8)> {
+        (1..=max_n)
+            .prop_flat_map(|n| (Just(n), 0..=n.min(8)))
+            .prop_filter("parameters must be valid", |(n, k)| {
+                CombinatorialEncoder::validate(*n, *k).is_ok()
+            })
+    }
+
+    /// Valid encoder parameters, with `n` up to `max_n`, together with `count` combinations of up to
+    /// `max_k` elements.
+    fn combinations(
+        max_n: u8,
+        count: usize,
+    ) -> impl Strategy<Value = (CombinatorialEncoder, Vec<u64>)> {
+        parameters(max_n).prop_flat_map(move |(n, k)| {
+            let combination = subsequence((0..n).collect::<Vec<_>>(), 0..=k as usize)
+                .prop_map(|elements| elements.iter().fold(0u64, |b, e| b | 1 << e));
+            (
+                Just(CombinatorialEncoder::new(n, k)),
+                proptest::collection::vec(combination, count),
+            )
+        })
+    }
+
+    proptest! {
+        #[test]
+        fn test_decode_encode_identity((n, k) in parameters(16)) {
+            let encoder = CombinatorialEncoder::new(n, k);
+            for index in 0..encoder.maximum_index() {
+                let bitset = encoder.try_decode(index).map_err(TestCaseError::fail)?;
+                prop_assert!(bitset.count_ones() <= k as u32);
+                prop_assert!(n == MAX_ELEMENTS || bitset >> n == 0);
+                prop_assert_eq!(encoder.try_encode(bitset), Ok(index));
+            }
+            prop_assert!(encoder.try_decode(encoder.maximum_index()).is_err());
+        }
+
+        #[test]
+        fn test_encode_decode_identity((encoder, bitsets) in combinations(MAX_ELEMENTS, 16)) {
+            for bitset in bitsets {
+                let index = encoder.try_encode(bitset).map_err(TestCaseError::fail)?;
+                prop_assert!(index < encoder.maximum_index());
+                prop_assert_eq!(encoder.try_decode(index), Ok(bitset));
+            }
+        }
+
+        #[test]
+        fn test_batch_matches_scalar((encoder, bitsets) in combinations(MAX_ELEMENTS, 64)) {
+            let mut indices = vec![0; bitsets.len()];
+            encoder.encode_many(&bitsets, &mut indices);
+            let expected = bitsets.iter().map(|b| encoder.encode(*b)).collect::<Vec<_>>();
+            prop_assert_eq!(&indices, &expected);
+
+            let mut decoded = vec![0; indices.len()];
+            encoder.decode_many(&indices, &mut decoded);
+            prop_assert_eq!(decoded, bitsets);
+        }
+
+        #[test]
+        fn test_combinations_in_order((n, k) in parameters(16)) {
+            let encoder = CombinatorialEncoder::new(n, k);
+            let combinations = encoder.combinations();
+            prop_assert_eq!(combinations.len(), encoder.maximum_index() as usize);
+            for (expected, (index, bitset)) in combinations.enumerate() {
+                prop_assert_eq!(index, expected as u32);
+                prop_assert_eq!(bitset, encoder.decode(index));
+            }
+        }
+
+        #[test]
+        fn test_size_classes((n, k) in parameters(16)) {
+            let encoder = CombinatorialEncoder::new(n, k);
+            for size in 0..=k + 1 {
+                let range = encoder.size_range(size);
+                let expected = encoder
+                    .combinations()
+                    .filter(|(_, bitset)| bitset.count_ones() == size as u32)
+                    .collect::<Vec<_>>();
+                let actual = encoder.combinations_of_size(size).collect::<Vec<_>>();
+                prop_assert_eq!(actual.len(), range.len());
+                prop_assert_eq!(actual.first().map(|(i, _)| *i), (!range.is_empty()).then_some(range.start));
+                prop_assert_eq!(actual, expected);
+            }
+            prop_assert_eq!(encoder.combinations_of_size(u8::MAX).count(), 0);
+        }
+
+        #[test]
+        fn test_supersets_and_subsets(
+            (encoder, bitsets) in combinations(16, 1),
+            larger in any::<u64>(),
+        ) {
+            let set = bitsets[0];
+            let supersets = encoder
+                .combinations()
+                .filter(|(_, bitset)| bitset & set == set)
+                .collect::<Vec<_>>();
+            prop_assert_eq!(encoder.supersets(set).collect::<Vec<_>>(), supersets);
+
+            // Subsets of an arbitrary set, possibly with more than `max_k` elements or some beyond
+            // `n`.
+            let subsets = encoder
+                .combinations()
+                .filter(|(_, bitset)| bitset & !larger == 0)
+                .collect::<Vec<_>>();
+            prop_assert_eq!(encoder.subsets(larger).collect::<Vec<_>>(), subsets);
+
+            // Sets that cannot be encoded have no supersets.
+            prop_assert_eq!(encoder.supersets(larger | 1 << 63).count(), 0);
+        }
+
+        #[test]
+        fn test_order((encoder, bitsets) in combinations(MAX_ELEMENTS, 2)) {
+            let (a, b) = (bitsets[0], bitsets[1]);
+            let (ia, ib) = (encoder.encode(a), encoder.encode(b));
+            // Smaller combinations come first, then colexicographic order within each size.
+            prop_assert_eq!(
+                (a.count_ones(), a).cmp(&(b.count_ones(), b)),
+                ia.cmp(&ib),
+                "encode({:b}) = {}, encode({:b}) = {}", a, ia, b, ib
+            );
+        }
+
+        #[test]
+        fn test_rejects_invalid((n, k) in parameters(MAX_ELEMENTS - 1), extra in any::<u64>()) {
+            let encoder = CombinatorialEncoder::new(n, k);
+            // Elements beyond `n` are never accepted.
+            let out_of_range = extra | 1 << n;
+            prop_assert!(encoder.try_encode(out_of_range).is_err());
+
+            // Neither are more than `max_k` elements.
+            if k < n {
+                let too_many = (1u64 << (k + 1)) - 1;
+                prop_assert!(encoder.try_encode(too_many).is_err());
+            }
+            prop_assert!(encoder.try_decode(encoder.maximum_index()).is_err());
+            prop_assert!(encoder.try_decode(u32::MAX).is_err());
+        }
+    }
+
+    #[test]
+    fn test_combinations_all_elements() {
+        // Every element in use exercises the overflow handling of the bitset arithmetic.
+        let encoder = CombinatorialEncoder::new(MAX_ELEMENTS, 2);
+        let mut count = 0;
+        for (index, bitset) in encoder.combinations() {
+            assert_eq!(bitset, encoder.decode(index), "index {index}");
+            count += 1;
+        }
+        assert_eq!(count, encoder.maximum_index());
+
+        assert!(encoder.subsets(u64::MAX).eq(encoder.combinations()));
+        let top = 1 << (MAX_ELEMENTS - 1);
+        let supersets = encoder.supersets(top).map(|(_, b)| b).collect::<Vec<_>>();
+        assert_eq!(supersets.len(), MAX_ELEMENTS as usize);
+        assert_eq!(supersets[0], top);
+        assert!(supersets[1..]
+            .iter()
+            .all(|b| b & top != 0 && b.count_ones() == 2));
+    }
 }
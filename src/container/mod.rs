@@ -0,0 +1,273 @@
+//! An optional compact container for graph and routes files.
+//!
+//! Savefile writes arrays as they are laid out in memory. A container instead holds a compact form
+//! of the contents, in which successors and offsets are delta coded as varints: most successors
+//! are the node itself or a near neighbour, so they take a byte or two. With the `compression`
+//! feature the container can also be compressed with zstd; without it, the `zstd` codec does not
+//! exist, and loading a compressed container fails.
+//!
+//! A container starts with [`MAGIC`] and a [`Codec`] byte, followed by the savefile of the compact
+//! form. Loading checks for the magic bytes, so plain savefiles still load as before.
+
+use savefile::{SavefileError, WithSchema};
+use std::io::{Read, Write};
+
+pub const MAGIC: [u8; 4] = *b"S1MC";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Codec {
+    /// Delta and varint coding only
+    Varint = 0,
+    /// Delta and varint coding, then zstd
+    #[cfg(feature = "compression")]
+    Zstd = 1,
+}
+
+/// The contents of a file, as saved with plain savefile or in a container.
+pub enum Loaded<T, C> {
+    Plain(T),
+    Compact(C),
+}
+
+/// Writes `compact` to a container, in the savefile format `version`.
+pub fn save<C: WithSchema + savefile::Serialize>(
+    writer: &mut impl Write,
+    version: u32,
+    compact: &C,
+    codec: Codec,
+) -> Result<(), SavefileError> {
+    writer.write_all(&MAGIC)?;
+    writer.write_all(&[codec as u8])?;
+    match codec {
+        Codec::Varint => savefile::save(writer, version, compact),
+        #[cfg(feature = "compression")]
+        Codec::Zstd => {
+            let mut encoder = zstd::Encoder::new(writer, 0)?;
+            savefile::save(&mut encoder, version, compact)?;
+            encoder.finish()?;
+            Ok(())
+        }
+    }
+}
+
+/// Reads either a plain savefile of `T` or a container of its compact form `C`.
+pub fn load<T, C>(reader: &mut impl Read, version: u32) -> Result<Loaded<T, C>, SavefileError>
+where
+    T: WithSchema + savefile::Deserialize,
+    C: WithSchema + savefile::Deserialize,
+{
+    let mut magic = [0u8; MAGIC.len()];
+    let mut read = 0;
+    while read < magic.len() {
+        match reader.read(&mut magic[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    if magic[..read] != MAGIC {
+        let mut reader = (&magic[..read]).chain(reader);
+        return Ok(Loaded::Plain(savefile::load(&mut reader, version)?));
+    }
+
+    let mut codec = [0u8];
+    reader.read_exact(&mut codec)?;
+    match codec[0] {
+        0 => Ok(Loaded::Compact(savefile::load(reader, version)?)),
+        #[cfg(feature = "compression")]
+        1 => {
+            let mut decoder = zstd::Decoder::new(reader)?;
+            Ok(Loaded::Compact(savefile::load(&mut decoder, version)?))
+        }
+        #[cfg(not(feature = "compression"))]
+        1 => Err(SavefileError::CompressionSupportNotCompiledIn),
+        c => Err(SavefileError::GeneralError {
+            msg: format!("unknown container codec {c}"),
+        }),
+    }
+}
+
+/// Number of bytes `value` takes as a plain savefile.
+pub fn raw_size<T: WithSchema + savefile::Serialize>(
+    version: u32,
+    value: &T,
+) -> Result<u64, SavefileError> {
+    struct Counter(u64);
+
+    impl Write for Counter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0 += buf.len() as u64;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut counter = Counter(0);
+    savefile::save(&mut counter, version, value)?;
+    Ok(counter.0)
+}
+
+/// Maps small negative and positive numbers to small unsigned ones, for varint coding.
+pub fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+pub fn unzigzag(value: u64) -> i64 {
+    (value >> 1) as i64 ^ -((value & 1) as i64)
+}
+
+/// Appends `value` in seven-bit groups, least significant first, with the high bit set on all but
+/// the last.
+pub fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// The varints in `bytes`, see [`write_varint`].
+pub fn varints(bytes: &[u8]) -> Varints<'_> {
+    Varints { bytes }
+}
+
+pub struct Varints<'a> {
+    bytes: &'a [u8],
+}
+
+impl Varints<'_> {
+    /// The next varint, or an error if the bytes ran out before it ended.
+    pub fn read(&mut self) -> Result<u64, SavefileError> {
+        self.next().ok_or(SavefileError::ShortRead)
+    }
+}
+
+impl Iterator for Varints<'_> {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        let mut value = 0;
+        for (i, byte) in self.bytes.iter().copied().enumerate().take(10) {
+            value |= ((byte & 0x7f) as u64) << (7 * i);
+            if byte & 0x80 == 0 {
+                self.bytes = &self.bytes[i + 1..];
+                return Some(value);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::container::{self, unzigzag, varints, write_varint, zigzag, Codec, Loaded};
+    use crate::effect_graph::{CompactGraph, EffectGraph, GRAPH_VERSION};
+    use crate::mixing::Drugs;
+    use crate::query::tests::{small_routes, small_rules};
+    use crate::query::{read_routes, FlattenedResultsFile};
+    use proptest::prelude::*;
+    use std::error::Error;
+
+    proptest! {
+        #[test]
+        fn test_varint_roundtrip(values in proptest::collection::vec(any::<i64>(), 0..64)) {
+            let mut bytes = Vec::new();
+            for value in &values {
+                write_varint(&mut bytes, zigzag(*value));
+            }
+            let decoded = varints(&bytes).map(unzigzag).collect::<Vec<_>>();
+            prop_assert_eq!(decoded, values);
+        }
+    }
+
+    #[test]
+    fn test_small_values() {
+        let mut bytes = Vec::new();
+        for value in [0, -1, 1, -64, 63] {
+            write_varint(&mut bytes, zigzag(value));
+        }
+        assert_eq!(bytes, [0, 1, 2, 127, 126]);
+        write_varint(&mut bytes, 300);
+        assert_eq!(&bytes[5..], [0xac, 0x02]);
+        // A truncated varint is not read.
+        assert_eq!(varints(&[0x80]).next(), None);
+    }
+
+    fn codecs() -> Vec<Codec> {
+        vec![
+            Codec::Varint,
+            #[cfg(feature = "compression")]
+            Codec::Zstd,
+        ]
+    }
+
+    #[test]
+    fn test_graph_container() -> Result<(), Box<dyn Error>> {
+        let rules = small_rules(3)?;
+        let graph = EffectGraph::new(&rules, rules.encoder());
+        let raw = container::raw_size(GRAPH_VERSION, &graph)?;
+        for codec in codecs() {
+            let mut bytes = Vec::new();
+            let saved = EffectGraph::new(&rules, rules.encoder());
+            let header = saved.header().clone();
+            saved.serialize_container(&mut bytes, codec)?;
+            assert!((bytes.len() as u64) < raw, "{codec:?}");
+            let loaded =
+                match container::load::<EffectGraph, CompactGraph>(&mut &bytes[..], GRAPH_VERSION)?
+                {
+                    Loaded::Compact(compact) => EffectGraph::try_from(compact)?,
+                    Loaded::Plain(_) => panic!("container loaded as a plain savefile"),
+                };
+            assert_eq!(loaded.num_nodes(), graph.num_nodes());
+            assert_eq!(*loaded.header(), header);
+            for idx in 0..graph.num_nodes() as u32 {
+                assert_eq!(loaded.successors(idx), graph.successors(idx));
+                assert_eq!(loaded.predecessors(idx), graph.predecessors(idx));
+            }
+            // A truncated container is an error, not a short graph.
+            let truncated = &bytes[..bytes.len() - 4];
+            assert!(container::load::<EffectGraph, CompactGraph>(
+                &mut &truncated[..],
+                GRAPH_VERSION
+            )
+            .ok()
+            .and_then(|l| match l {
+                Loaded::Compact(compact) => EffectGraph::try_from(compact).ok(),
+                Loaded::Plain(_) => None,
+            })
+            .is_none());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_routes_container() -> Result<(), Box<dyn Error>> {
+        let (rules, graph, routes) = small_routes(2)?;
+        let encoder = rules.encoder();
+        for codec in codecs() {
+            let mut bytes = Vec::new();
+            let saved = FlattenedResultsFile::build(&rules, &graph);
+            let header = saved.header.clone();
+            saved.serialize_container(&mut bytes, codec)?;
+            let loaded = read_routes(&bytes, &encoder)?;
+            assert_eq!(loaded.header, header);
+            for drug in [Drugs::OGKush, Drugs::Meth] {
+                for idx in 0..encoder.maximum_index() as usize {
+                    assert_eq!(
+                        loaded.paths(drug).get(idx),
+                        routes.paths(drug).get(idx),
+                        "{codec:?}"
+                    );
+                    assert_eq!(
+                        loaded.price_multipliers.get(idx),
+                        routes.price_multipliers.get(idx)
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,36 +1,46 @@
 use crate::combinatorial::CombinatorialEncoder;
+use crate::container::{self, unzigzag, varints, write_varint, zigzag, Codec, Loaded};
+use crate::file_header::FileHeader;
 use crate::flat_storage::FlatStorage;
 use crate::mixing::{Effects, MixtureRules, Substance, SUBSTANCES};
 use savefile::SavefileError;
 use savefile_derive::Savefile;
-use std::io::Write;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, Write};
+use std::path::Path;
 
 type EffectIndex = u32;
 
-pub const GRAPH_VERSION: u32 = 2;
+pub const GRAPH_VERSION: u32 = 4;
 
 #[derive(Savefile)]
-pub struct EffectGraph<const N: u8, const K: u8> {
+pub struct EffectGraph {
+    /// What the graph was built from, empty for graphs from before version 4.
+    #[savefile_versions = "4.."]
+    header: FileHeader,
     successors: Vec<[EffectIndex; SUBSTANCES.len()]>,
     predecessors: FlatStorage<EffectIndex>,
-    encoder: CombinatorialEncoder<N, K>,
+    encoder: CombinatorialEncoder,
 }
 
-impl<const N: u8, const K: u8> EffectGraph<N, K> {
-    pub fn new(rules: &MixtureRules, encoder: CombinatorialEncoder<N, K>) -> Self {
+impl EffectGraph {
+    pub fn new(rules: &MixtureRules, encoder: CombinatorialEncoder) -> Self {
         let n_combinations = encoder.maximum_index();
         let mut successors = vec![[0u32; SUBSTANCES.len()]; n_combinations as usize];
         let mut predecessors = vec![Vec::new(); n_combinations as usize];
 
-        for idx in 0..n_combinations {
-            let effects = Effects::from_bits(encoder.decode(idx)).expect("failed to decode effect");
-            let row = &mut successors[idx as usize];
+        let mut mixed = [0u64; SUBSTANCES.len()];
+        for (idx, bits) in encoder.combinations() {
+            let effects = Effects::from_bits(bits).expect("failed to decode effect");
+            // Link to the effects after applying each substance
             for (s_idx, substance) in SUBSTANCES.iter().copied().enumerate() {
-                // Add a link to the effects after applying the substance
-                let new_effects = rules.apply(substance, effects);
-                let new_idx = encoder.encode(new_effects.bits());
-                row[s_idx] = new_idx;
+                mixed[s_idx] = rules.apply(substance, effects).bits();
+            }
+            let row = &mut successors[idx as usize];
+            encoder.encode_many(&mixed, row);
 
+            for new_idx in row.iter().copied() {
                 // If we don't loop back to ourselves, add a backlink to the predecessors.
                 if new_idx == idx {
                     continue;
@@ -45,20 +55,50 @@ impl<const N: u8, const K: u8> EffectGraph<N, K> {
         let predecessors = predecessors.into();
 
         Self {
+            header: FileHeader::new(rules),
             successors,
             predecessors,
             encoder,
         }
     }
 
+    /// Loads a graph, saved plainly or in a container, from `path` and checks that it was built
+    /// for the effect count and cap of `rules`.
+    pub fn load<P: AsRef<Path>>(path: P, rules: &MixtureRules) -> Result<Self, Box<dyn Error>> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let graph = match container::load::<Self, CompactGraph>(&mut reader, GRAPH_VERSION)? {
+            Loaded::Plain(graph) => graph,
+            Loaded::Compact(compact) => compact.try_into()?,
+        };
+        rules.check_encoder(&graph.encoder)?;
+        Ok(graph)
+    }
+
     pub fn serialize(&self, writer: &mut impl Write) -> Result<(), SavefileError> {
         savefile::save(writer, GRAPH_VERSION, self)
     }
 
+    /// Writes the graph in a container, see [`crate::container`].
+    pub fn serialize_container(
+        self,
+        writer: &mut impl Write,
+        codec: Codec,
+    ) -> Result<(), SavefileError> {
+        container::save(writer, GRAPH_VERSION, &CompactGraph::from(self), codec)
+    }
+
     pub fn num_nodes(&self) -> usize {
         self.successors.len()
     }
 
+    pub fn header(&self) -> &FileHeader {
+        &self.header
+    }
+
+    pub fn encoder(&self) -> &CombinatorialEncoder {
+        &self.encoder
+    }
+
     pub fn encode(&self, effects: Effects) -> EffectIndex {
         self.encoder.encode(effects.bits())
     }
@@ -75,18 +115,111 @@ impl<const N: u8, const K: u8> EffectGraph<N, K> {
         self.predecessors.get(id as usize)
     }
 
+    /// Every edge into `id` from another node, as the predecessor and the substance mixed in.
+    /// A predecessor appears once for each substance that leads to `id`.
     pub fn predecessors_with_substances(
         &self,
         id: EffectIndex,
-    ) -> impl Iterator<Item = (EffectIndex, Substance)> + use<'_, N, K> {
-        self.predecessors.get(id as usize).iter().map(move |n| {
-            (
-                *n,
-                SUBSTANCES[self.successors[*n as usize]
+    ) -> impl Iterator<Item = (EffectIndex, Substance)> + use<'_> {
+        self.predecessors
+            .get(id as usize)
+            .iter()
+            .flat_map(move |n| {
+                self.successors[*n as usize]
                     .iter()
-                    .position(|n2| *n2 == id)
-                    .expect("failed to find matching substance")],
-            )
+                    .zip(SUBSTANCES)
+                    .filter(move |(n2, _)| **n2 == id)
+                    .map(move |(_, s)| (*n, *s))
+            })
+    }
+}
+
+/// [`EffectGraph`] as stored in a container. Successors and predecessors are stored relative to
+/// their node, or to the previous predecessor, zigzag and varint coded.
+#[derive(Savefile)]
+pub struct CompactGraph {
+    #[savefile_versions = "4.."]
+    header: FileHeader,
+    num_elements: u8,
+    max_elements: u8,
+    successors: Vec<u8>,
+    predecessor_counts: Vec<u8>,
+    predecessors: Vec<u8>,
+}
+
+impl From<EffectGraph> for CompactGraph {
+    fn from(graph: EffectGraph) -> Self {
+        let mut successors = Vec::new();
+        let mut predecessor_counts = Vec::new();
+        let mut predecessors = Vec::new();
+        for (idx, row) in graph.successors.iter().enumerate() {
+            for next in row {
+                write_varint(&mut successors, zigzag(*next as i64 - idx as i64));
+            }
+            let row = graph.predecessors.get(idx);
+            write_varint(&mut predecessor_counts, row.len() as u64);
+            let mut previous = idx as i64;
+            for pred in row {
+                write_varint(&mut predecessors, zigzag(*pred as i64 - previous));
+                previous = *pred as i64;
+            }
+        }
+        Self {
+            header: graph.header,
+            num_elements: graph.encoder.num_elements(),
+            max_elements: graph.encoder.max_elements(),
+            successors,
+            predecessor_counts,
+            predecessors,
+        }
+    }
+}
+
+impl TryFrom<CompactGraph> for EffectGraph {
+    type Error = SavefileError;
+
+    fn try_from(compact: CompactGraph) -> Result<Self, SavefileError> {
+        CombinatorialEncoder::validate(compact.num_elements, compact.max_elements)
+            .map_err(|msg| SavefileError::GeneralError { msg })?;
+        let encoder = CombinatorialEncoder::new(compact.num_elements, compact.max_elements);
+        let nodes = encoder.maximum_index() as usize;
+        let node = |idx: usize, delta: u64| {
+            EffectIndex::try_from(idx as i64 + unzigzag(delta))
+                .ok()
+                .filter(|next| (*next as usize) < nodes)
+                .ok_or_else(|| SavefileError::GeneralError {
+                    msg: "corrupt graph in container".into(),
+                })
+        };
+
+        let mut deltas = varints(&compact.successors);
+        let mut successors = vec![[0; SUBSTANCES.len()]; nodes];
+        for (idx, row) in successors.iter_mut().enumerate() {
+            for next in row {
+                *next = node(idx, deltas.read()?)?;
+            }
+        }
+
+        let mut counts = varints(&compact.predecessor_counts);
+        let mut deltas = varints(&compact.predecessors);
+        let mut lengths = Vec::with_capacity(nodes);
+        let mut values = Vec::new();
+        for idx in 0..nodes {
+            let count = counts.read()?;
+            let mut previous = idx;
+            for _ in 0..count {
+                let pred = node(previous, deltas.read()?)?;
+                values.push(pred);
+                previous = pred as usize;
+            }
+            lengths.push(count);
+        }
+
+        Ok(Self {
+            header: compact.header,
+            successors,
+            predecessors: FlatStorage::from_row_lengths(values, lengths)?,
+            encoder,
         })
     }
 }
@@ -0,0 +1,286 @@
+//! Data-defined description of the effects known to a rules file.
+//!
+//! Each effect is identified by a bit in the [`Effects`] bitset. The registry maps that bit to the
+//! two-letter abbreviation used throughout the rules file, the display name from
+//! `effect_abbreviations`, the colour from `effect_color` and the price multiplier from
+//! `effect_price`.
+
+use crate::mixing::Effects;
+use crate::parsing::{NameTable, ParseError};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+
+/// Bit assignments for rules files that do not carry an explicit `effect_bits` table. This is the
+/// order the effects were originally declared in, so graphs and routes generated before the
+/// registry existed remain valid.
+const LEGACY_BITS: &[&str] = &[
+    "Ag", "At", "Ba", "Be", "Ca", "Cd", "Cy", "Di", "El", "En", "Eu", "Ex", "Fc", "Fo", "Gi", "Gl",
+    "Je", "La", "Lf", "Mu", "Pa", "Re", "Sc", "Se", "Sh", "Si", "Sl", "Sm", "Sn", "Sp", "To", "Tp",
+    "Tt", "Zo",
+];
+
+/// Returns the bit historically assigned to the effect with the given abbreviation.
+pub fn legacy_bit(abbreviation: &str) -> Option<u8> {
+    LEGACY_BITS
+        .iter()
+        .position(|a| *a == abbreviation)
+        .map(|b| b as u8)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EffectInfo {
+    /// Two-letter code used by the rules file, e.g. `Ag`.
+    pub abbreviation: String,
+    /// Human-readable name, e.g. `Anti-Gravity`.
+    pub name: String,
+    /// Hex colour code, e.g. `#235BCB`, if the rules file provides one.
+    pub color: Option<String>,
+    /// Position of the effect in the [`Effects`] bitset.
+    pub bit: u8,
+    /// Amount added to the base price multiplier when the effect is present.
+    pub price_multiplier: f64,
+}
+
+impl EffectInfo {
+    pub fn effect(&self) -> Effects {
+        Effects::from_bits_retain(1 << self.bit)
+    }
+
+    /// The display name with everything but letters and digits removed, e.g. `AntiGravity`. This
+    /// matches the Rust identifiers the effects were originally declared with.
+    pub fn identifier(&self) -> String {
+        self.name.chars().filter(|c| c.is_alphanumeric()).collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EffectRegistry {
+    /// Effects ordered by bit index. Bits are contiguous, so `effects[i].bit == i`.
+    effects: Vec<EffectInfo>,
+    by_abbreviation: HashMap<String, u8>,
+    names: NameTable<u8>,
+}
+
+impl EffectRegistry {
+    /// Builds a registry, checking that abbreviations are unique and that the bit indices are
+    /// exactly `0..effects.len()`.
+    pub fn new(mut effects: Vec<EffectInfo>) -> Result<Self, String> {
+        effects.sort_by_key(|e| e.bit);
+        let mut by_abbreviation = HashMap::with_capacity(effects.len());
+        for (idx, effect) in effects.iter().enumerate() {
+            if effect.bit as usize != idx {
+                return Err(format!(
+                    "effect bits must be unique and contiguous, but '{}' has bit {} where {idx} was expected",
+                    effect.abbreviation, effect.bit
+                ));
+            }
+            if by_abbreviation
+                .insert(effect.abbreviation.clone(), effect.bit)
+                .is_some()
+            {
+                return Err(format!("duplicate effect '{}'", effect.abbreviation));
+            }
+        }
+
+        let mut names = NameTable::new("effect");
+        for effect in &effects {
+            names.insert(
+                effect.bit,
+                &effect.name,
+                [effect.abbreviation.as_str(), &effect.identifier()],
+            );
+        }
+
+        Ok(Self {
+            effects,
+            by_abbreviation,
+            names,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.effects.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.effects.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &EffectInfo> {
+        self.effects.iter()
+    }
+
+    pub fn get(&self, bit: u8) -> Option<&EffectInfo> {
+        self.effects.get(bit as usize)
+    }
+
+    /// The set of every effect in the registry.
+    pub fn all(&self) -> Effects {
+        self.effects
+            .iter()
+            .fold(Effects::empty(), |a, e| a | e.effect())
+    }
+
+    pub fn by_abbreviation(&self, abbreviation: &str) -> Option<&EffectInfo> {
+        self.by_abbreviation
+            .get(abbreviation)
+            .map(|b| &self.effects[*b as usize])
+    }
+
+    /// Finds an effect by its abbreviation, display name or identifier, ignoring case and
+    /// punctuation.
+    pub fn lookup(&self, name: &str) -> Result<&EffectInfo, ParseError> {
+        self.names.get(name).map(|b| &self.effects[b as usize])
+    }
+
+    /// Parses a comma- or `|`-separated list of effects, each given by abbreviation, display name
+    /// or identifier, e.g. `anti-gravity, Fo, ThoughtProvoking`.
+    pub fn parse(&self, s: &str) -> Result<Effects, ParseError> {
+        Ok(self
+            .names
+            .get_list(s)?
+            .into_iter()
+            .fold(Effects::empty(), |a, b| {
+                a | self.effects[b as usize].effect()
+            }))
+    }
+
+    /// Iterates over the registry entries for each effect in `effects`, in bit order.
+    pub fn members(&self, effects: Effects) -> impl Iterator<Item = &EffectInfo> {
+        self.effects
+            .iter()
+            .filter(move |e| effects.contains(e.effect()))
+    }
+
+    /// Display names of each effect in `effects`, in bit order.
+    pub fn names(&self, effects: Effects) -> Vec<&str> {
+        self.members(effects).map(|e| e.name.as_str()).collect()
+    }
+
+    pub fn price_multiplier(&self, effects: Effects) -> f64 {
+        let base = 1.0;
+        let mut multiplier = 0.;
+
+        let mut remaining = effects.bits();
+        while remaining != 0 {
+            let bit = remaining.trailing_zeros();
+            remaining &= remaining - 1;
+            if let Some(effect) = self.effects.get(bit as usize) {
+                multiplier += effect.price_multiplier;
+            }
+        }
+
+        base + multiplier
+    }
+
+    /// Formats `effects` as a comma-separated list of display names.
+    pub fn display(&self, effects: Effects) -> EffectsDisplay<'_> {
+        EffectsDisplay {
+            registry: self,
+            effects,
+        }
+    }
+}
+
+pub struct EffectsDisplay<'r> {
+    registry: &'r EffectRegistry,
+    effects: Effects,
+}
+
+impl EffectsDisplay<'_> {
+    /// Writes the effects with `name` writing each known one, followed by the bits of any unknown
+    /// effects in hex, or `(none)` if there are no effects.
+    pub fn fmt_with(
+        &self,
+        f: &mut Formatter<'_>,
+        name: impl Fn(&mut Formatter<'_>, &EffectInfo) -> std::fmt::Result,
+    ) -> std::fmt::Result {
+        let mut first = true;
+        for effect in self.registry.members(self.effects) {
+            if !first {
+                write!(f, ", ")?;
+            }
+            first = false;
+            name(f, effect)?;
+        }
+        let unknown = self.effects.bits() & !self.registry.all().bits();
+        if unknown != 0 {
+            if !first {
+                write!(f, ", ")?;
+            }
+            write!(f, "{unknown:#x}")?;
+        } else if first {
+            write!(f, "(none)")?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for EffectsDisplay<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.fmt_with(f, |f, effect| write!(f, "{}", effect.name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::effect_registry::{legacy_bit, EffectInfo, EffectRegistry};
+    use crate::mixing::Effects;
+
+    fn info(abbreviation: &str, name: &str, bit: u8) -> EffectInfo {
+        EffectInfo {
+            abbreviation: abbreviation.to_string(),
+            name: name.to_string(),
+            color: None,
+            bit,
+            price_multiplier: 0.5,
+        }
+    }
+
+    #[test]
+    fn test_lookup_and_display() {
+        let registry = EffectRegistry::new(vec![
+            info("Lf", "Long Faced", 1),
+            info("Ag", "Anti-Gravity", 0),
+        ])
+        .unwrap();
+
+        let ag = Effects::from_bits_retain(1);
+        let lf = Effects::from_bits_retain(2);
+        for name in ["Ag", "Anti-Gravity", "AntiGravity", "anti gravity", "AG"] {
+            assert_eq!(registry.lookup(name).map(|e| e.effect()), Ok(ag));
+        }
+        assert_eq!(registry.parse("LongFaced | Ag"), Ok(ag | lf));
+        assert_eq!(registry.parse("long faced, ag"), Ok(ag | lf));
+
+        let err = registry.parse("Ag, Long Faded").unwrap_err();
+        assert_eq!(err.input, "Long Faded");
+        assert_eq!(err.suggestions, ["Long Faced"]);
+
+        assert_eq!(
+            registry.display(ag | lf).to_string(),
+            "Anti-Gravity, Long Faced"
+        );
+        assert_eq!(registry.display(Effects::empty()).to_string(), "(none)");
+        assert_eq!(registry.price_multiplier(ag | lf), 2.0);
+    }
+
+    #[test]
+    fn test_invalid_bits() {
+        // Gap in the bit indices
+        assert!(EffectRegistry::new(vec![info("Ag", "Anti-Gravity", 1)]).is_err());
+        // Duplicate abbreviation
+        assert!(EffectRegistry::new(vec![
+            info("Ag", "Anti-Gravity", 0),
+            info("Ag", "Anti-Gravity", 1)
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_legacy_bits() {
+        assert_eq!(legacy_bit("Ag"), Some(0));
+        assert_eq!(legacy_bit("Zo"), Some(33));
+        assert_eq!(legacy_bit("Xx"), None);
+    }
+}
@@ -0,0 +1,316 @@
+//! Exports every route in a routes file as a table, for analysis in e.g. pandas or DuckDB.
+//!
+//! There is one row, an [`ExportRecord`], for each product, reachable effect set and Pareto label.
+//! Rows are produced lazily by [`export_records`] and written as they are produced, so exporting a
+//! large routes file does not hold the table in memory. Tables are written as CSV, or with the
+//! `parquet` feature as Parquet, in row groups of [`PARQUET_BATCH_ROWS`] rows. In Parquet, the
+//! effects and ingredients are lists rather than `;`-joined strings.
+
+use crate::combinatorial::CombinatorialEncoder;
+use crate::mixing::{base_price, Effects, MixtureRules, DRUGS};
+use crate::mosp::Cost;
+use crate::output::{ExportRecord, Format, RecordWriter};
+use crate::query::{effect_names, trace_path, FlattenedResultsFile, CHECKED_ROUTES};
+use std::error::Error;
+use std::io::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum TableFormat {
+    /// Comma-separated values with a header row
+    Csv,
+    /// Apache Parquet. Requires the `parquet` feature
+    Parquet,
+}
+
+/// Number of rows buffered before they are written to a Parquet file as a row group.
+pub const PARQUET_BATCH_ROWS: usize = 64 * 1024;
+
+/// Every route in `routes`, for each product in turn, with sale prices after `markup` capped at
+/// `max_price`, as for [`crate::query::ProfitQuery`].
+pub fn export_records<'a>(
+    rules: &'a MixtureRules,
+    encoder: &'a CombinatorialEncoder,
+    routes: &'a FlattenedResultsFile,
+    markup: f64,
+    max_price: Cost,
+) -> impl Iterator<Item = ExportRecord> + 'a {
+    DRUGS.iter().copied().flat_map(move |drug| {
+        let paths = routes.paths(drug);
+        let base_price = base_price(drug) * (1. + markup);
+        paths.iter().flat_map(move |(idx, labels)| {
+            let effects = Effects::from(encoder.decode(idx as u32));
+            let names = effect_names(rules, effects);
+            let multiplier = *routes
+                .price_multipliers
+                .get(idx)
+                .expect("effect sets with routes should have a price")
+                as f64
+                / 100.;
+            labels.iter().map(move |label| ExportRecord {
+                drug,
+                index: idx as u32,
+                effects: names.clone(),
+                effect_count: effects.bits().count_ones() as u8,
+                price_multiplier: multiplier,
+                cost: label.cost,
+                length: label.length,
+                sell_price: max_price.min((base_price * multiplier).round() as Cost) as i32,
+                ingredients: trace_path(*label, paths).expect(CHECKED_ROUTES),
+            })
+        })
+    })
+}
+
+/// Writes `records` to `writer` as a table, returning the number of rows.
+pub fn write_table<W: Write + Send>(
+    format: TableFormat,
+    records: impl IntoIterator<Item = ExportRecord>,
+    writer: W,
+) -> Result<usize, Box<dyn Error>> {
+    let mut rows = 0;
+    match format {
+        TableFormat::Csv => {
+            let mut writer = RecordWriter::new(Format::Csv, writer);
+            for record in records {
+                writer.write(&record)?;
+                rows += 1;
+            }
+            writer.finish()?;
+        }
+        #[cfg(feature = "parquet")]
+        TableFormat::Parquet => {
+            let mut writer = parquet_table::ParquetWriter::new(writer)?;
+            for record in records {
+                writer.write(&record)?;
+                rows += 1;
+            }
+            writer.finish()?;
+        }
+        #[cfg(not(feature = "parquet"))]
+        TableFormat::Parquet => {
+            return Err("Parquet output requires building with the `parquet` feature".into());
+        }
+    }
+    Ok(rows)
+}
+
+/// Name of a drug or substance as written in the other output formats, e.g. `OGKush`.
+#[cfg(feature = "parquet")]
+fn identifier(value: impl serde::Serialize) -> String {
+    match serde_json::to_value(value) {
+        Ok(serde_json::Value::String(s)) => s,
+        other => unreachable!("identifiers serialize as strings, not {other:?}"),
+    }
+}
+
+#[cfg(feature = "parquet")]
+mod parquet_table {
+    use crate::export::{identifier, PARQUET_BATCH_ROWS};
+    use crate::output::ExportRecord;
+    use arrow_array::builder::{
+        Float64Builder, Int32Builder, ListBuilder, StringBuilder, UInt16Builder, UInt32Builder,
+        UInt8Builder,
+    };
+    use arrow_array::{ArrayRef, RecordBatch};
+    use arrow_schema::{DataType, Field, Schema, SchemaRef};
+    use parquet::arrow::ArrowWriter;
+    use std::error::Error;
+    use std::io::Write;
+    use std::sync::Arc;
+
+    /// Column builders for the rows of the next row group.
+    #[derive(Default)]
+    struct Columns {
+        drug: StringBuilder,
+        index: UInt32Builder,
+        effects: ListBuilder<StringBuilder>,
+        effect_count: UInt8Builder,
+        price_multiplier: Float64Builder,
+        cost: UInt16Builder,
+        length: UInt8Builder,
+        sell_price: Int32Builder,
+        ingredients: ListBuilder<StringBuilder>,
+    }
+
+    pub struct ParquetWriter<W: Write + Send> {
+        schema: SchemaRef,
+        writer: ArrowWriter<W>,
+        columns: Columns,
+        rows: usize,
+    }
+
+    impl<W: Write + Send> ParquetWriter<W> {
+        pub fn new(writer: W) -> Result<Self, Box<dyn Error>> {
+            let list =
+                |name| Field::new_list(name, Field::new_list_field(DataType::Utf8, true), false);
+            let schema = Arc::new(Schema::new(vec![
+                Field::new("drug", DataType::Utf8, false),
+                Field::new("index", DataType::UInt32, false),
+                list("effects"),
+                Field::new("effect_count", DataType::UInt8, false),
+                Field::new("price_multiplier", DataType::Float64, false),
+                Field::new("cost", DataType::UInt16, false),
+                Field::new("length", DataType::UInt8, false),
+                Field::new("sell_price", DataType::Int32, false),
+                list("ingredients"),
+            ]));
+            Ok(Self {
+                writer: ArrowWriter::try_new(writer, schema.clone(), None)?,
+                schema,
+                columns: Columns::default(),
+                rows: 0,
+            })
+        }
+
+        pub fn write(&mut self, record: &ExportRecord) -> Result<(), Box<dyn Error>> {
+            let columns = &mut self.columns;
+            columns.drug.append_value(identifier(record.drug));
+            columns.index.append_value(record.index);
+            columns
+                .effects
+                .append_value(record.effects.iter().map(Some));
+            columns.effect_count.append_value(record.effect_count);
+            columns
+                .price_multiplier
+                .append_value(record.price_multiplier);
+            columns.cost.append_value(record.cost);
+            columns.length.append_value(record.length);
+            columns.sell_price.append_value(record.sell_price);
+            columns
+                .ingredients
+                .append_value(record.ingredients.iter().map(|s| Some(identifier(s))));
+            self.rows += 1;
+            if self.rows == PARQUET_BATCH_ROWS {
+                self.flush()?;
+            }
+            Ok(())
+        }
+
+        /// Writes the buffered rows as a row group.
+        fn flush(&mut self) -> Result<(), Box<dyn Error>> {
+            if self.rows == 0 {
+                return Ok(());
+            }
+            let columns = &mut self.columns;
+            let arrays: Vec<ArrayRef> = vec![
+                Arc::new(columns.drug.finish()),
+                Arc::new(columns.index.finish()),
+                Arc::new(columns.effects.finish()),
+                Arc::new(columns.effect_count.finish()),
+                Arc::new(columns.price_multiplier.finish()),
+                Arc::new(columns.cost.finish()),
+                Arc::new(columns.length.finish()),
+                Arc::new(columns.sell_price.finish()),
+                Arc::new(columns.ingredients.finish()),
+            ];
+            self.writer
+                .write(&RecordBatch::try_new(self.schema.clone(), arrays)?)?;
+            self.writer.flush()?;
+            self.rows = 0;
+            Ok(())
+        }
+
+        /// Writes the remaining rows and the file footer.
+        pub fn finish(mut self) -> Result<(), Box<dyn Error>> {
+            self.flush()?;
+            self.writer.close()?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::export::{export_records, write_table, TableFormat};
+    use crate::mixing::Drugs;
+    use crate::query::lookup_records;
+    use crate::query::tests::small_routes;
+    use std::error::Error;
+
+    #[test]
+    fn test_export() -> Result<(), Box<dyn Error>> {
+        let (rules, _, routes) = small_routes(2)?;
+        let encoder = rules.encoder();
+
+        let records = export_records(&rules, &encoder, &routes, 0., 999).collect::<Vec<_>>();
+        let labels = routes
+            .by_drug()
+            .iter()
+            .map(|(_, paths)| paths.iter().map(|(_, labels)| labels.len()).sum::<usize>())
+            .sum::<usize>();
+        // Cocaine is listed separately from Meth.
+        let meth = routes
+            .paths(Drugs::Meth)
+            .iter()
+            .map(|(_, l)| l.len())
+            .sum::<usize>();
+        assert_eq!(records.len(), labels + meth);
+
+        // Rows agree with lookups of the same effect set.
+        for record in records.iter().step_by(97) {
+            let lookup = lookup_records(&rules, &encoder, &routes, record.index);
+            let drug = match record.drug {
+                Drugs::Cocaine => Drugs::Meth,
+                d => d,
+            };
+            assert!(lookup.iter().any(|r| r.drug == drug
+                && r.effects == record.effects
+                && r.cost == record.cost
+                && r.length == record.length
+                && r.ingredients == record.ingredients));
+            assert_eq!(record.effect_count as usize, record.effects.len());
+            assert_eq!(
+                rules
+                    .mix(record.drug, &record.ingredients)
+                    .bits()
+                    .count_ones(),
+                record.effect_count as u32
+            );
+        }
+        let meth = records.iter().find(|r| r.drug == Drugs::Meth).unwrap();
+        let cocaine = records
+            .iter()
+            .find(|r| r.drug == Drugs::Cocaine && r.index == meth.index)
+            .unwrap();
+        assert!(cocaine.sell_price > meth.sell_price);
+        // Prices are capped as in profit queries.
+        assert!(export_records(&rules, &encoder, &routes, 2., 50).all(|r| r.sell_price <= 50));
+
+        let mut csv = Vec::new();
+        let rows = write_table(
+            TableFormat::Csv,
+            export_records(&rules, &encoder, &routes, 0., 999),
+            &mut csv,
+        )?;
+        assert_eq!(rows, records.len());
+        let csv = String::from_utf8(csv)?;
+        assert_eq!(csv.lines().count(), rows + 1);
+        assert!(csv.starts_with(
+            "drug,index,effects,effect_count,price_multiplier,cost,length,sell_price,ingredients\n"
+        ));
+        Ok(())
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn test_parquet() -> Result<(), Box<dyn Error>> {
+        use parquet::file::reader::{FileReader, SerializedFileReader};
+
+        let (rules, _, routes) = small_routes(2)?;
+        let encoder = rules.encoder();
+
+        let path = std::env::temp_dir().join(format!("export-{}.parquet", std::process::id()));
+        let rows = write_table(
+            TableFormat::Parquet,
+            export_records(&rules, &encoder, &routes, 0., 999),
+            std::fs::File::create(&path)?,
+        )?;
+        let reader = SerializedFileReader::new(std::fs::File::open(&path)?);
+        std::fs::remove_file(&path)?;
+        let metadata = reader?.metadata().file_metadata().clone();
+        assert_eq!(metadata.num_rows() as usize, rows);
+        assert_eq!(metadata.schema_descr().num_columns(), 9);
+        Ok(())
+    }
+}
@@ -0,0 +1,135 @@
+//! A header recording what a graph or routes file was built from.
+//!
+//! Graph and routes files only depend on the rules through their contents, so a file built from an
+//! older rules file still loads after a game update and silently gives wrong answers. Each file
+//! therefore starts with a [`FileHeader`] holding a hash of the rules, the substance costs, the
+//! encoder parameters, when it was built and by which version of the tool. Commands compare it
+//! with `--rules` before using the file, see [`FileHeader::mismatches`]. Index and reachability
+//! files carry a header too, copied from the routes file or taken from the rules they were built
+//! with.
+//!
+//! The rules are hashed in the form `normalize-rules` writes, so reformatting or reordering a rules
+//! file does not change the hash, but any edit to its contents does.
+
+use crate::mixing::{substance_cost, MixtureRules, SUBSTANCES};
+use savefile_derive::Savefile;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Savefile, Serialize, Deserialize)]
+pub struct FileHeader {
+    /// FNV-1a hash of the canonical rules, see [`rules_hash`].
+    pub rules_hash: u64,
+    /// Cost of each substance, in the order of [`SUBSTANCES`].
+    pub substance_costs: Vec<i64>,
+    pub num_elements: u8,
+    pub max_elements: u8,
+    /// Seconds since the Unix epoch, or zero where the time is unavailable.
+    pub created: u64,
+    /// Version of the tool that built the file.
+    pub tool_version: String,
+}
+
+impl FileHeader {
+    /// A header for a file built now from `rules`.
+    pub fn new(rules: &MixtureRules) -> Self {
+        Self {
+            rules_hash: rules_hash(rules),
+            substance_costs: substance_costs(),
+            num_elements: rules.num_effects(),
+            max_elements: rules.max_effects(),
+            created: now(),
+            tool_version: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+
+    /// Files from before headers were added load with an empty header.
+    pub fn is_present(&self) -> bool {
+        !self.tool_version.is_empty()
+    }
+
+    /// Ways in which the file was built from something other than `rules`, empty if it matches.
+    pub fn mismatches(&self, rules: &MixtureRules) -> Vec<String> {
+        let mut mismatches = Vec::new();
+        if self.rules_hash != rules_hash(rules) {
+            mismatches.push(format!(
+                "built from rules with hash {:016x}, but the rules hash to {:016x}",
+                self.rules_hash,
+                rules_hash(rules)
+            ));
+        }
+        if self.substance_costs != substance_costs() {
+            mismatches.push(format!(
+                "built with substance costs {:?}, but they are now {:?}",
+                self.substance_costs,
+                substance_costs()
+            ));
+        }
+        if (self.num_elements, self.max_elements) != (rules.num_effects(), rules.max_effects()) {
+            mismatches.push(format!(
+                "built for up to {} of {} effects, but the rules define up to {} of {}",
+                self.max_elements,
+                self.num_elements,
+                rules.max_effects(),
+                rules.num_effects()
+            ));
+        }
+        mismatches
+    }
+}
+
+/// FNV-1a hash of `rules` as written by `normalize-rules`.
+pub fn rules_hash(rules: &MixtureRules) -> u64 {
+    let json = serde_json::to_vec(rules).expect("rules should always serialize");
+    json.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+fn substance_costs() -> Vec<i64> {
+    SUBSTANCES.iter().copied().map(substance_cost).collect()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+#[cfg(target_arch = "wasm32")]
+fn now() -> u64 {
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::file_header::{rules_hash, FileHeader};
+    use crate::mixing::MixtureRules;
+    use crate::query::tests::small_rules;
+    use std::error::Error;
+
+    #[test]
+    fn test_mismatches() -> Result<(), Box<dyn Error>> {
+        let rules = small_rules(2)?;
+        let header = FileHeader::new(&rules);
+        assert!(header.is_present());
+        assert!(header.mismatches(&rules).is_empty());
+        assert!(!FileHeader::default().is_present());
+
+        // The hash is of the contents, not the formatting.
+        let reparsed = MixtureRules::from_value(serde_json::to_value(&rules)?)?;
+        assert_eq!(rules_hash(&reparsed), header.rules_hash);
+
+        // A different cap changes the rules and the encoder.
+        let mismatches = header.mismatches(&small_rules(3)?);
+        assert_eq!(mismatches.len(), 2, "{mismatches:?}");
+
+        let mut value = rules.to_value();
+        value["effect_price"]["Ca"] = "0.5".into();
+        let repriced = MixtureRules::from_value(value)?;
+        let mismatches = header.mismatches(&repriced);
+        assert_eq!(mismatches.len(), 1, "{mismatches:?}");
+        assert!(mismatches[0].contains("hash"));
+        Ok(())
+    }
+}
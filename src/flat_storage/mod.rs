@@ -1,5 +1,8 @@
+use crate::container::{varints, write_varint};
+use savefile::SavefileError;
 use savefile_derive::Savefile;
 use serde::{Deserialize, Serialize};
+use std::ops::Range;
 
 fn convert_offsets(v: Vec<usize>) -> Vec<u32> {
     v.into_iter().map(|x| x as u32).collect()
@@ -36,9 +39,511 @@ impl<T: 'static> From<Vec<Vec<T>>> for FlatStorage<T> {
 }
 
 impl<T: 'static> FlatStorage<T> {
+    /// Number of rows.
+    pub fn len(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Rows of the given lengths, taken in order from `items`.
+    pub fn from_row_lengths(
+        items: Vec<T>,
+        lengths: impl IntoIterator<Item = u64>,
+    ) -> Result<Self, SavefileError> {
+        let mut offsets = vec![0u32];
+        for length in lengths {
+            let end = *offsets.last().expect("should not be empty") as u64 + length;
+            offsets.push(u32::try_from(end).map_err(|_| corrupt("row lengths"))?);
+        }
+        if *offsets.last().expect("should not be empty") as usize != items.len() {
+            return Err(corrupt("row lengths"));
+        }
+        Ok(Self {
+            paths: items,
+            offsets,
+        })
+    }
+
+    fn row_lengths(&self) -> impl Iterator<Item = u64> + '_ {
+        self.offsets.windows(2).map(|w| (w[1] - w[0]) as u64)
+    }
+
     pub fn get(&self, idx: usize) -> &[T] {
         let offset = self.offsets[idx];
         let length = self.offsets[idx + 1] - offset;
         &self.paths[offset as usize..(offset + length) as usize]
     }
 }
+
+/// A set of indices below a fixed bound, one bit each, e.g. the effect sets a product can reach.
+#[derive(Debug, Clone, PartialEq, Eq, Savefile, Serialize, Deserialize)]
+pub struct Bitmap {
+    words: Vec<u64>,
+}
+
+impl Bitmap {
+    /// An empty bitmap over the indices `0..len`.
+    pub fn new(len: u32) -> Self {
+        Self {
+            words: vec![0; len.div_ceil(u64::BITS) as usize],
+        }
+    }
+
+    pub fn insert(&mut self, index: u32) {
+        self.words[(index / u64::BITS) as usize] |= 1 << (index % u64::BITS);
+    }
+
+    pub fn contains(&self, index: u32) -> bool {
+        self.words[(index / u64::BITS) as usize] & 1 << (index % u64::BITS) != 0
+    }
+
+    /// Number of indices in the set.
+    pub fn count(&self) -> usize {
+        self.words.iter().map(|w| w.count_ones() as usize).sum()
+    }
+
+    /// Number of indices in the set within `range`.
+    pub fn count_range(&self, range: Range<u32>) -> usize {
+        if range.is_empty() {
+            return 0;
+        }
+        let (first, last) = (range.start / u64::BITS, (range.end - 1) / u64::BITS);
+        (first..=last)
+            .map(|i| {
+                let mut word = self.words[i as usize];
+                if i == first {
+                    word &= u64::MAX << (range.start % u64::BITS);
+                }
+                if i == last {
+                    word &= u64::MAX >> (u64::BITS - 1 - (range.end - 1) % u64::BITS);
+                }
+                word.count_ones() as usize
+            })
+            .sum()
+    }
+
+    /// Adds every index in `other`.
+    pub fn union(&mut self, other: &Self) {
+        for (word, other) in self.words.iter_mut().zip(&other.words) {
+            *word |= other;
+        }
+    }
+
+    /// Removes every index not in `other`.
+    pub fn intersect(&mut self, other: &Self) {
+        for (word, other) in self.words.iter_mut().zip(&other.words) {
+            *word &= other;
+        }
+    }
+
+    /// Removes every index in `other`.
+    pub fn subtract(&mut self, other: &Self) {
+        for (word, other) in self.words.iter_mut().zip(&other.words) {
+            *word &= !other;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.words.fill(0);
+    }
+
+    /// The indices in the set, in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = u32> + '_ {
+        set_bits(&self.words)
+    }
+}
+
+/// Indices of the set bits of `words`, in ascending order.
+fn set_bits(words: &[u64]) -> impl Iterator<Item = u32> + '_ {
+    words.iter().enumerate().flat_map(|(i, word)| {
+        let base = i as u32 * u64::BITS;
+        let mut remaining = *word;
+        std::iter::from_fn(move || {
+            if remaining == 0 {
+                return None;
+            }
+            let bit = remaining.trailing_zeros();
+            remaining &= remaining - 1;
+            Some(base + bit)
+        })
+    })
+}
+
+/// A set of row indices below `len`, with the number of set bits before each word so that the
+/// rank of an index, i.e., its position among the set indices, takes constant time.
+#[derive(Debug, Clone, Default, Savefile, Serialize, Deserialize)]
+pub struct RankBitmap {
+    len: u32,
+    words: Vec<u64>,
+    ranks: Vec<u32>,
+}
+
+impl RankBitmap {
+    /// A bitmap over `0..len` with the `indices` set, which must be ascending.
+    pub fn from_sorted(len: u32, indices: impl IntoIterator<Item = u32>) -> Self {
+        let mut bitmap = Bitmap::new(len);
+        for index in indices {
+            assert!(index < len, "index {index} out of range for {len} rows");
+            bitmap.insert(index);
+        }
+        let ranks = ranks(&bitmap.words);
+        Self {
+            len,
+            words: bitmap.words,
+            ranks,
+        }
+    }
+
+    /// A bitmap over `0..len` with the bits of `words` set.
+    pub fn from_words(len: u32, words: Vec<u64>) -> Result<Self, SavefileError> {
+        let spare = words.len() as u64 * u64::BITS as u64 - len as u64;
+        if words.len() != len.div_ceil(u64::BITS) as usize
+            || words
+                .last()
+                .is_some_and(|w| spare > 0 && w >> (u64::BITS as u64 - spare) != 0)
+        {
+            return Err(corrupt("bitmap"));
+        }
+        let ranks = ranks(&words);
+        Ok(Self { len, words, ranks })
+    }
+
+    /// Number of rows, set or not.
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Number of set rows.
+    pub fn count(&self) -> usize {
+        match (self.ranks.last(), self.words.last()) {
+            (Some(rank), Some(word)) => (*rank + word.count_ones()) as usize,
+            _ => 0,
+        }
+    }
+
+    /// Whether every row set here is also set in `other`, which must have the same length.
+    pub fn is_subset(&self, other: &Self) -> bool {
+        self.len == other.len
+            && self
+                .words
+                .iter()
+                .zip(&other.words)
+                .all(|(word, other)| word & !other == 0)
+    }
+
+    /// Position of `index` among the set rows, or `None` if it is not set or out of range.
+    pub fn rank(&self, index: usize) -> Option<usize> {
+        if index >= self.len as usize {
+            return None;
+        }
+        let (word, bit) = (index / u64::BITS as usize, index % u64::BITS as usize);
+        let word_bits = self.words[word];
+        if word_bits & 1 << bit == 0 {
+            return None;
+        }
+        let below = word_bits & ((1 << bit) - 1);
+        Some(self.ranks[word] as usize + below.count_ones() as usize)
+    }
+
+    /// The set rows, in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        set_bits(&self.words).map(|index| index as usize)
+    }
+}
+
+/// Number of set bits before each word.
+fn ranks(words: &[u64]) -> Vec<u32> {
+    words
+        .iter()
+        .scan(0u32, |rank, word| {
+            let before = *rank;
+            *rank += word.count_ones();
+            Some(before)
+        })
+        .collect()
+}
+
+fn corrupt(what: &str) -> SavefileError {
+    SavefileError::GeneralError {
+        msg: format!("corrupt {what} in container"),
+    }
+}
+
+/// A [`FlatStorage`] in which most rows are empty. Only the other rows are stored, and a row is
+/// found by its rank among them.
+#[derive(Savefile, Serialize, Deserialize)]
+pub struct SparseStorage<T>
+where
+    T: 'static,
+{
+    present: RankBitmap,
+    rows: FlatStorage<T>,
+}
+
+impl<T: 'static> From<Vec<Vec<T>>> for SparseStorage<T> {
+    fn from(ragged: Vec<Vec<T>>) -> Self {
+        let present = RankBitmap::from_sorted(
+            ragged.len() as u32,
+            (0..ragged.len() as u32).filter(|i| !ragged[*i as usize].is_empty()),
+        );
+        let rows = ragged
+            .into_iter()
+            .filter(|row| !row.is_empty())
+            .collect::<Vec<_>>();
+        Self {
+            present,
+            rows: rows.into(),
+        }
+    }
+}
+
+/// Drops the empty rows of a dense table. The stored items stay as they are.
+impl<T: 'static> From<FlatStorage<T>> for SparseStorage<T> {
+    fn from(dense: FlatStorage<T>) -> Self {
+        let rows = dense.offsets.len() - 1;
+        let non_empty = |i: &usize| dense.offsets[*i] != dense.offsets[*i + 1];
+        let present =
+            RankBitmap::from_sorted(rows as u32, (0..rows).filter(non_empty).map(|i| i as u32));
+        let mut offsets = Vec::with_capacity(present.count() + 1);
+        offsets.push(0);
+        offsets.extend((0..rows).filter(non_empty).map(|i| dense.offsets[i + 1]));
+        Self {
+            present,
+            rows: FlatStorage {
+                paths: dense.paths,
+                offsets,
+            },
+        }
+    }
+}
+
+impl<T: 'static> SparseStorage<T> {
+    /// The row `idx`, empty if it is not stored.
+    pub fn get(&self, idx: usize) -> &[T] {
+        match self.present.rank(idx) {
+            Some(rank) => self.rows.get(rank),
+            None => &[],
+        }
+    }
+
+    /// Number of rows, stored or not.
+    pub fn len(&self) -> usize {
+        self.present.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.present.is_empty()
+    }
+
+    /// Rows that are not empty.
+    pub fn present(&self) -> &RankBitmap {
+        &self.present
+    }
+
+    /// The stored rows with their indices, in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &[T])> + '_ {
+        self.present
+            .iter()
+            .enumerate()
+            .map(|(rank, idx)| (idx, self.rows.get(rank)))
+    }
+}
+
+/// [`SparseStorage`] as stored in a container, see [`crate::container`]. Ranks are recomputed on
+/// load and row lengths are varint coded.
+#[derive(Savefile)]
+pub struct CompactStorage<T>
+where
+    T: 'static,
+{
+    len: u32,
+    present: Vec<u64>,
+    row_lengths: Vec<u8>,
+    items: Vec<T>,
+}
+
+impl<T: 'static> From<SparseStorage<T>> for CompactStorage<T> {
+    fn from(sparse: SparseStorage<T>) -> Self {
+        let mut row_lengths = Vec::new();
+        for length in sparse.rows.row_lengths() {
+            write_varint(&mut row_lengths, length);
+        }
+        Self {
+            len: sparse.present.len,
+            present: sparse.present.words,
+            row_lengths,
+            items: sparse.rows.paths,
+        }
+    }
+}
+
+impl<T: 'static> TryFrom<CompactStorage<T>> for SparseStorage<T> {
+    type Error = SavefileError;
+
+    fn try_from(compact: CompactStorage<T>) -> Result<Self, SavefileError> {
+        let present = RankBitmap::from_words(compact.len, compact.present)?;
+        let mut lengths = varints(&compact.row_lengths);
+        let lengths = (0..present.count())
+            .map(|_| lengths.read())
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self {
+            present,
+            rows: FlatStorage::from_row_lengths(compact.items, lengths)?,
+        })
+    }
+}
+
+/// A vector in which most entries are missing. Only the others are stored, in order.
+#[derive(Debug, Clone, Default, Savefile, Serialize, Deserialize)]
+pub struct SparseVec<T>
+where
+    T: 'static,
+{
+    present: RankBitmap,
+    values: Vec<T>,
+}
+
+/// Keeps every entry of a dense vector.
+impl<T: 'static> From<Vec<T>> for SparseVec<T> {
+    fn from(values: Vec<T>) -> Self {
+        Self {
+            present: RankBitmap::from_sorted(values.len() as u32, 0..values.len() as u32),
+            values,
+        }
+    }
+}
+
+impl<T: 'static> SparseVec<T> {
+    /// A vector of `len` entries, of which only `entries` are present, in ascending order.
+    pub fn from_sorted(len: u32, entries: impl IntoIterator<Item = (u32, T)>) -> Self {
+        let (indices, values): (Vec<_>, Vec<_>) = entries.into_iter().unzip();
+        Self {
+            present: RankBitmap::from_sorted(len, indices),
+            values,
+        }
+    }
+
+    pub fn get(&self, idx: usize) -> Option<&T> {
+        self.present.rank(idx).map(|rank| &self.values[rank])
+    }
+
+    /// Entries that are present.
+    pub fn present(&self) -> &RankBitmap {
+        &self.present
+    }
+
+    /// Number of entries, present or not.
+    pub fn len(&self) -> usize {
+        self.present.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.present.is_empty()
+    }
+}
+
+/// [`SparseVec`] as stored in a container, see [`crate::container`]. Ranks are recomputed on load.
+#[derive(Savefile)]
+pub struct CompactVec<T>
+where
+    T: 'static,
+{
+    len: u32,
+    present: Vec<u64>,
+    values: Vec<T>,
+}
+
+impl<T: 'static> From<SparseVec<T>> for CompactVec<T> {
+    fn from(sparse: SparseVec<T>) -> Self {
+        Self {
+            len: sparse.present.len,
+            present: sparse.present.words,
+            values: sparse.values,
+        }
+    }
+}
+
+impl<T: 'static> TryFrom<CompactVec<T>> for SparseVec<T> {
+    type Error = SavefileError;
+
+    fn try_from(compact: CompactVec<T>) -> Result<Self, SavefileError> {
+        let present = RankBitmap::from_words(compact.len, compact.present)?;
+        if present.count() != compact.values.len() {
+            return Err(corrupt("values"));
+        }
+        Ok(Self {
+            present,
+            values: compact.values,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::flat_storage::{Bitmap, FlatStorage, RankBitmap, SparseStorage, SparseVec};
+
+    #[test]
+    fn test_rank() {
+        let indices = [0, 3, 63, 64, 130, 199];
+        let bitmap = RankBitmap::from_sorted(200, indices);
+        assert_eq!(bitmap.len(), 200);
+        assert_eq!(bitmap.count(), indices.len());
+        for i in 0..210 {
+            assert_eq!(
+                bitmap.rank(i),
+                indices.iter().position(|j| *j as usize == i),
+                "rank({i})"
+            );
+        }
+        assert_eq!(RankBitmap::from_sorted(0, []).count(), 0);
+
+        let mut plain = Bitmap::new(200);
+        indices.iter().for_each(|i| plain.insert(*i));
+        assert!(plain.iter().map(|i| i as usize).eq(bitmap.iter()));
+        assert_eq!(plain.count(), bitmap.count());
+        assert_eq!(plain.count_range(3..65), 3);
+        assert_eq!(plain.count_range(4..4), 0);
+    }
+
+    #[test]
+    fn test_sparse_matches_dense() {
+        let ragged = (0..150u32)
+            .map(|i| match i % 7 {
+                0 => vec![i, i + 1],
+                3 => vec![i],
+                _ => Vec::new(),
+            })
+            .collect::<Vec<_>>();
+        let dense = FlatStorage::from(ragged.clone());
+        let sparse = SparseStorage::from(ragged.clone());
+        let converted = SparseStorage::from(FlatStorage::from(ragged.clone()));
+        assert_eq!(sparse.len(), ragged.len());
+        assert_eq!(sparse.present().count(), 150 / 7 * 2 + 1);
+        for (i, row) in ragged.iter().enumerate() {
+            assert_eq!(dense.get(i), row.as_slice());
+            assert_eq!(sparse.get(i), row.as_slice());
+            assert_eq!(converted.get(i), row.as_slice());
+        }
+        assert!(sparse.get(ragged.len()).is_empty());
+        let stored = ragged
+            .iter()
+            .enumerate()
+            .filter(|(_, row)| !row.is_empty())
+            .map(|(i, row)| (i, row.as_slice()))
+            .collect::<Vec<_>>();
+        assert_eq!(sparse.iter().collect::<Vec<_>>(), stored);
+
+        let values = SparseVec::from_sorted(10, [(2, 'a'), (7, 'b')]);
+        assert_eq!(values.get(2), Some(&'a'));
+        assert_eq!(values.get(7), Some(&'b'));
+        assert_eq!(values.get(3), None);
+        assert_eq!(SparseVec::from(vec!['x'; 3]).get(2), Some(&'x'));
+    }
+}
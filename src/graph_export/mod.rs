@@ -0,0 +1,397 @@
+//! Exports part of an [`EffectGraph`] for visualisation, e.g. to explain how mixing chains work.
+//!
+//! The whole graph is far too large to draw, so [`Subgraph::extract`] keeps only the effect sets
+//! reachable from a product within a number of substances, a total cost, or both. The subgraph is
+//! written as Graphviz DOT with [`write_dot`] or as GraphML with [`write_graphml`]. Nodes are
+//! labelled with their effects and sale price, and edges with the substances that lead from one
+//! effect set to the other; a substance that leaves the effects unchanged is not drawn.
+
+use crate::effect_graph::EffectGraph;
+use crate::mixing::{
+    base_price, substance_cost, Drugs, Effects, MixtureRules, Substance, SUBSTANCES,
+};
+use crate::mosp::{Cost, EffectIndex, PathLength};
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum GraphFormat {
+    /// Graphviz DOT
+    Dot,
+    /// GraphML, e.g. for Gephi or yEd
+    Graphml,
+}
+
+/// How far from the starting effects to explore. An effect set is kept if some sequence of at most
+/// `max_depth` substances costing at most `max_cost` in total reaches it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Limits {
+    pub max_depth: Option<PathLength>,
+    pub max_cost: Option<Cost>,
+}
+
+pub struct SubgraphNode {
+    pub index: EffectIndex,
+    pub effects: Effects,
+    /// Fewest substances needed to reach the effect set.
+    pub depth: PathLength,
+    /// Lowest cost of reaching the effect set within the depth limit.
+    pub cost: Cost,
+    /// Sale price without markup.
+    pub price: f64,
+}
+
+/// Substances leading from the node at position `from` to the node at position `to`.
+pub struct SubgraphEdge {
+    pub from: usize,
+    pub to: usize,
+    pub substances: Vec<Substance>,
+}
+
+pub struct Subgraph {
+    pub drug: Drugs,
+    /// The starting effects come first, followed by the other nodes in the order they were found.
+    pub nodes: Vec<SubgraphNode>,
+    pub edges: Vec<SubgraphEdge>,
+}
+
+impl Subgraph {
+    /// The effect sets reachable from `drug` within `limits`, and the edges between them.
+    ///
+    /// Each round extends the routes found in the previous one by one substance, keeping the
+    /// lowest cost of reaching each effect set, until the depth limit or until no cost improves.
+    /// Without any limit this is every effect set reachable from `drug`.
+    pub fn extract(rules: &MixtureRules, graph: &EffectGraph, drug: Drugs, limits: Limits) -> Self {
+        let max_depth = limits.max_depth.unwrap_or(PathLength::MAX);
+        let max_cost = limits.max_cost.unwrap_or(Cost::MAX);
+        let start = graph.encode(rules.drug_effects(drug));
+
+        // (depth, cost) of each reached node, keyed by index.
+        let mut reached: HashMap<EffectIndex, (PathLength, Cost)> =
+            HashMap::from([(start, (0, 0))]);
+        let mut order = vec![start];
+        // Nodes improved in the previous round, with their costs at the end of it. Costs lowered
+        // during a round take one more substance, so they are only extended in the next round.
+        let mut frontier: Vec<(EffectIndex, Cost)> = vec![(start, 0)];
+        let mut depth: PathLength = 0;
+        while !frontier.is_empty() && depth < max_depth {
+            depth += 1;
+            let mut improved = Vec::new();
+            let mut seen = HashSet::new();
+            for (node, cost) in frontier {
+                for (next, substance) in graph.successors(node).iter().copied().zip(SUBSTANCES) {
+                    let next_cost = cost.saturating_add(substance_cost(*substance) as Cost);
+                    if next == node || next_cost > max_cost {
+                        continue;
+                    }
+                    match reached.get_mut(&next) {
+                        Some((_, best)) if *best <= next_cost => continue,
+                        Some((_, best)) => *best = next_cost,
+                        None => {
+                            reached.insert(next, (depth, next_cost));
+                            order.push(next);
+                        }
+                    }
+                    if seen.insert(next) {
+                        improved.push(next);
+                    }
+                }
+            }
+            frontier = improved
+                .into_iter()
+                .map(|node| (node, reached[&node].1))
+                .collect();
+        }
+
+        let position = order
+            .iter()
+            .enumerate()
+            .map(|(position, index)| (*index, position))
+            .collect::<HashMap<_, _>>();
+        let nodes = order
+            .iter()
+            .map(|index| {
+                let effects = graph.decode(*index).expect("graph nodes should decode");
+                let (depth, cost) = reached[index];
+                SubgraphNode {
+                    index: *index,
+                    effects,
+                    depth,
+                    cost,
+                    price: (base_price(drug) * rules.price_multiplier(effects)).round(),
+                }
+            })
+            .collect();
+
+        let mut edges = Vec::new();
+        for (from, index) in order.iter().enumerate() {
+            let mut targets: Vec<(usize, Vec<Substance>)> = Vec::new();
+            for (next, substance) in graph.successors(*index).iter().zip(SUBSTANCES) {
+                let Some(to) = position.get(next).copied().filter(|to| *to != from) else {
+                    continue;
+                };
+                match targets.iter_mut().find(|(t, _)| *t == to) {
+                    Some((_, substances)) => substances.push(*substance),
+                    None => targets.push((to, vec![*substance])),
+                }
+            }
+            edges.extend(targets.into_iter().map(|(to, substances)| SubgraphEdge {
+                from,
+                to,
+                substances,
+            }));
+        }
+
+        Self { drug, nodes, edges }
+    }
+}
+
+fn node_label(rules: &MixtureRules, node: &SubgraphNode) -> String {
+    let names = rules.effects().names(node.effects);
+    let effects = if names.is_empty() {
+        "(no effects)".to_string()
+    } else {
+        names.join(", ")
+    };
+    format!("{effects}\n${}", node.price)
+}
+
+fn edge_label(rules: &MixtureRules, edge: &SubgraphEdge) -> String {
+    edge.substances
+        .iter()
+        .map(|s| rules.substance_name(*s))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Writes `subgraph` as a Graphviz DOT digraph, with the starting effects drawn as a double circle.
+pub fn write_dot(
+    rules: &MixtureRules,
+    subgraph: &Subgraph,
+    writer: &mut impl Write,
+) -> std::io::Result<()> {
+    let quote = |s: &str| {
+        let escaped = s.replace('\\', "\\\\").replace('"', "\\\"");
+        format!("\"{}\"", escaped.replace('\n', "\\n"))
+    };
+    writeln!(writer, "digraph {} {{", quote(&subgraph.drug.to_string()))?;
+    writeln!(writer, "  node [shape=box];")?;
+    for (position, node) in subgraph.nodes.iter().enumerate() {
+        let shape = if position == 0 {
+            " shape=doublecircle"
+        } else {
+            ""
+        };
+        writeln!(
+            writer,
+            "  n{} [label={} depth={} cost={}{shape}];",
+            node.index,
+            quote(&node_label(rules, node)),
+            node.depth,
+            node.cost
+        )?;
+    }
+    for edge in &subgraph.edges {
+        writeln!(
+            writer,
+            "  n{} -> n{} [label={}];",
+            subgraph.nodes[edge.from].index,
+            subgraph.nodes[edge.to].index,
+            quote(&edge_label(rules, edge))
+        )?;
+    }
+    writeln!(writer, "}}")
+}
+
+/// Writes `subgraph` as GraphML, with the labels, prices, depths and costs as data keys.
+pub fn write_graphml(
+    rules: &MixtureRules,
+    subgraph: &Subgraph,
+    writer: &mut impl Write,
+) -> std::io::Result<()> {
+    let escape = |s: &str| {
+        s.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+            .replace('"', "&quot;")
+    };
+    writeln!(writer, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(
+        writer,
+        r#"<graphml xmlns="http://graphml.graphdrawing.org/xmlns">"#
+    )?;
+    for (id, on, name, kind) in [
+        ("label", "node", "label", "string"),
+        ("effects", "node", "effects", "string"),
+        ("price", "node", "price", "double"),
+        ("depth", "node", "depth", "int"),
+        ("cost", "node", "cost", "int"),
+        ("substances", "edge", "substances", "string"),
+    ] {
+        writeln!(
+            writer,
+            r#"  <key id="{id}" for="{on}" attr.name="{name}" attr.type="{kind}"/>"#
+        )?;
+    }
+    writeln!(
+        writer,
+        r#"  <graph id="{}" edgedefault="directed">"#,
+        escape(&subgraph.drug.to_string())
+    )?;
+    for node in &subgraph.nodes {
+        writeln!(writer, r#"    <node id="n{}">"#, node.index)?;
+        let effects = rules.effects().names(node.effects).join(", ");
+        for (key, value) in [
+            ("label", node_label(rules, node)),
+            ("effects", effects),
+            ("price", node.price.to_string()),
+            ("depth", node.depth.to_string()),
+            ("cost", node.cost.to_string()),
+        ] {
+            writeln!(
+                writer,
+                r#"      <data key="{key}">{}</data>"#,
+                escape(&value)
+            )?;
+        }
+        writeln!(writer, "    </node>")?;
+    }
+    for edge in &subgraph.edges {
+        writeln!(
+            writer,
+            r#"    <edge source="n{}" target="n{}">"#,
+            subgraph.nodes[edge.from].index, subgraph.nodes[edge.to].index
+        )?;
+        writeln!(
+            writer,
+            r#"      <data key="substances">{}</data>"#,
+            escape(&edge_label(rules, edge))
+        )?;
+        writeln!(writer, "    </edge>")?;
+    }
+    writeln!(writer, "  </graph>")?;
+    writeln!(writer, "</graphml>")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::effect_graph::EffectGraph;
+    use crate::graph_export::{write_dot, write_graphml, Limits, Subgraph};
+    use crate::mixing::{substance_cost, Drugs, SUBSTANCES};
+    use crate::mosp::Cost;
+    use crate::query::tests::small_rules;
+    use crate::reachability::reachable_from;
+    use std::collections::HashMap;
+    use std::error::Error;
+
+    #[test]
+    fn test_extract() -> Result<(), Box<dyn Error>> {
+        let rules = small_rules(2)?;
+        let graph = EffectGraph::new(&rules, rules.encoder());
+
+        let one = Subgraph::extract(
+            &rules,
+            &graph,
+            Drugs::OGKush,
+            Limits {
+                max_depth: Some(1),
+                max_cost: None,
+            },
+        );
+        let start = graph.encode(rules.drug_effects(Drugs::OGKush));
+        assert_eq!(one.nodes[0].index, start);
+        let mut successors = graph.successors(start).to_vec();
+        successors.push(start);
+        successors.sort();
+        successors.dedup();
+        let mut indices = one.nodes.iter().map(|n| n.index).collect::<Vec<_>>();
+        indices.sort();
+        assert_eq!(indices, successors);
+        assert!(one.nodes[1..].iter().all(|n| n.depth == 1 && n.cost > 0));
+        assert!(one.edges.iter().all(|e| e.from != e.to));
+        // Every substance that changes the starting effects is drawn from the start.
+        let from_start = one.edges.iter().filter(|e| e.from == 0);
+        let changing = graph.successors(start).iter().filter(|n| **n != start);
+        assert_eq!(
+            from_start.map(|e| e.substances.len()).sum::<usize>(),
+            changing.count()
+        );
+
+        // Cheap substances only: Cuke and Banana cost 2.
+        let cheap = Subgraph::extract(
+            &rules,
+            &graph,
+            Drugs::OGKush,
+            Limits {
+                max_depth: None,
+                max_cost: Some(2),
+            },
+        );
+        assert!(cheap.nodes.iter().all(|n| n.cost <= 2));
+        assert!(cheap.nodes.len() < one.nodes.len());
+
+        // Without limits, everything reachable is included.
+        let all = Subgraph::extract(&rules, &graph, Drugs::OGKush, Limits::default());
+        assert_eq!(
+            all.nodes.len(),
+            reachable_from(&graph, rules.drug_effects(Drugs::OGKush)).count()
+        );
+
+        let mut dot = Vec::new();
+        write_dot(&rules, &one, &mut dot)?;
+        let dot = String::from_utf8(dot)?;
+        assert!(dot.starts_with("digraph \"OG Kush\" {"));
+        assert_eq!(dot.matches(" -> ").count(), one.edges.len());
+
+        let mut graphml = Vec::new();
+        write_graphml(&rules, &one, &mut graphml)?;
+        let graphml = String::from_utf8(graphml)?;
+        assert_eq!(graphml.matches("<node ").count(), one.nodes.len());
+        assert_eq!(graphml.matches("<edge ").count(), one.edges.len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_combined_limits() -> Result<(), Box<dyn Error>> {
+        let rules = small_rules(3)?;
+        let graph = EffectGraph::new(&rules, rules.encoder());
+        let start = graph.encode(rules.drug_effects(Drugs::OGKush));
+
+        for (max_depth, max_cost) in [(1, 3), (2, 5), (3, 11), (3, 20), (4, 12)] {
+            // Cheapest cost of reaching each node with at most `max_depth` substances.
+            let mut cheapest = HashMap::from([(start, 0)]);
+            for _ in 0..max_depth {
+                let mut next = cheapest.clone();
+                for (node, cost) in &cheapest {
+                    let successors = graph.successors(*node).iter().zip(SUBSTANCES);
+                    for (to, substance) in successors {
+                        let cost = cost + substance_cost(*substance) as Cost;
+                        if cost <= max_cost && next.get(to).is_none_or(|c| cost < *c) {
+                            next.insert(*to, cost);
+                        }
+                    }
+                }
+                cheapest = next;
+            }
+
+            let subgraph = Subgraph::extract(
+                &rules,
+                &graph,
+                Drugs::OGKush,
+                Limits {
+                    max_depth: Some(max_depth),
+                    max_cost: Some(max_cost),
+                },
+            );
+            let extracted = subgraph
+                .nodes
+                .iter()
+                .map(|n| (n.index, n.cost))
+                .collect::<HashMap<_, _>>();
+            assert_eq!(extracted, cheapest, "depth {max_depth}, cost {max_cost}");
+            assert!(subgraph.nodes.iter().all(|n| n.depth <= max_depth));
+        }
+        Ok(())
+    }
+}
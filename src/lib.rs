@@ -1,5 +1,24 @@
 pub mod combinatorial;
+pub mod container;
 pub mod effect_graph;
+pub mod effect_registry;
+pub mod export;
+pub mod file_header;
 pub mod flat_storage;
+pub mod graph_export;
 pub mod mixing;
 pub mod mosp;
+pub mod output;
+pub mod parsing;
+pub mod query;
+pub mod reachability;
+pub mod recipes;
+pub mod render;
+#[cfg(feature = "cli")]
+pub mod repl;
+pub mod route_index;
+pub mod rules_diff;
+#[cfg(feature = "cli")]
+pub mod server;
+#[cfg(feature = "wasm")]
+pub mod wasm;
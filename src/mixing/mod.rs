@@ -1,54 +1,31 @@
+use crate::combinatorial::CombinatorialEncoder;
+use crate::effect_registry::{legacy_bit, EffectInfo, EffectRegistry};
+use crate::parsing::{NameTable, ParseError};
 use bitflags::bitflags;
 use savefile_derive::Savefile;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::fmt::Display;
 use std::fs::File;
 use std::hash::{Hash, Hasher};
-use std::io::BufReader;
+use std::io::{BufReader, Read};
 use std::path::Path;
+use std::str::FromStr;
 use topological_sort::TopologicalSort;
 
+/// Maximum number of effects on a product, used when the rules file does not specify one.
 pub const MAX_EFFECTS: u8 = 8;
-pub const NUM_EFFECTS: u8 = 34;
+
+fn default_max_effects() -> u8 {
+    MAX_EFFECTS
+}
 
 bitflags! {
+    /// A set of effects, one bit per effect. The meaning of each bit is defined at runtime by the
+    /// [`EffectRegistry`] loaded from the rules file.
     #[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Copy, Clone, Serialize, Deserialize)]
     pub struct Effects: u64 {
-        const AntiGravity = 1 << 0;
-        const Athletic = 1 << 1;
-        const Balding = 1 << 2;
-        const BrightEyed = 1 << 3;
-        const Calming = 1 << 4;
-        const CalorieDense = 1 << 5;
-        const Cyclopean = 1 << 6;
-        const Disorienting = 1 << 7;
-        const Electrifying = 1 << 8;
-        const Energizing = 1 << 9;
-        const Euphoric = 1 << 10;
-        const Explosive = 1 << 11;
-        const Focused = 1 << 12;
-        const Foggy = 1 << 13;
-        const Gingeritis = 1 << 14;
-        const Glowing = 1 << 15;
-        const Jennerising = 1 << 16;
-        const Laxative = 1 << 17;
-        const LongFaced = 1 << 18;
-        const Munchies = 1 << 19;
-        const Paranoia = 1 << 20;
-        const Refreshing = 1 << 21;
-        const Schizophrenia = 1 << 22;
-        const Sedating = 1 << 23;
-        const Shrinking = 1 << 24;
-        const SeizureInducing = 1 << 25;
-        const Slippery = 1 << 26;
-        const Smelly = 1 << 27;
-        const Sneaky = 1 << 28;
-        const Spicy = 1 << 29;
-        const Toxic = 1 << 30;
-        const ThoughtProvoking = 1 << 31;
-        const TropicThunder = 1 << 32;
-        const Zombifying = 1 << 33;
+        const _ = !0;
     }
 }
 
@@ -60,7 +37,7 @@ impl Hash for Effects {
 
 impl From<u64> for Effects {
     fn from(val: u64) -> Effects {
-        Effects::from_bits(val).unwrap()
+        Effects::from_bits_retain(val)
     }
 }
 
@@ -180,14 +157,22 @@ impl Display for Drugs {
     }
 }
 
-pub fn inherent_effects(drug: Drugs) -> Effects {
-    match drug {
-        Drugs::OGKush => Effects::Calming,
-        Drugs::SourDiesel => Effects::Refreshing,
-        Drugs::GreenCrack => Effects::Energizing,
-        Drugs::GranddaddyPurple => Effects::Sedating,
-        _ => Effects::empty(),
+pub const DRUGS: &[Drugs] = &[
+    Drugs::OGKush,
+    Drugs::SourDiesel,
+    Drugs::GreenCrack,
+    Drugs::GranddaddyPurple,
+    Drugs::Meth,
+    Drugs::Cocaine,
+];
+
+/// Parses a drug given by display name or identifier, e.g. `og kush` or `GranddaddyPurple`.
+pub fn parse_drug(s: &str) -> Result<Drugs, ParseError> {
+    let mut table = NameTable::new("drug");
+    for drug in DRUGS.iter().copied() {
+        table.insert(drug, &drug.to_string(), [format!("{drug:?}").as_str()]);
     }
+    table.get(s)
 }
 
 // Define our Rule structure
@@ -197,16 +182,18 @@ pub struct Rule {
     pub if_not_present: Effects,
     pub remove: Effects,
     pub add: Effects,
+    /// The individual `(from, to)` replacements that make up `remove` and `add`.
+    pub replace: Vec<(Effects, Effects)>,
 }
 
-// JSON input structures for deserialization
-#[derive(Deserialize)]
+// JSON structures for (de)serialization. Maps are ordered so that written files are stable.
+#[derive(Serialize, Deserialize)]
 struct ReplaceMap {
     #[serde(flatten)]
-    entries: HashMap<String, String>,
+    entries: BTreeMap<String, String>,
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 struct RuleJson {
     if_present: Vec<String>,
     if_not_present: Vec<String>,
@@ -214,23 +201,45 @@ struct RuleJson {
     replace: ReplaceMap,
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 struct EffectJson {
     substance: String,
     effect: Vec<String>,
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 struct RulesFile {
+    /// Display names of each substance, keyed by the substance's letter code.
+    #[serde(default)]
+    substances: BTreeMap<String, String>,
     effects: Vec<EffectJson>,
+    effect_abbreviations: BTreeMap<String, String>,
+    effect_price: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    effect_color: BTreeMap<String, String>,
+    /// Optional bit index for each effect abbreviation. When absent, the legacy assignment from
+    /// [`legacy_bit`] is used.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    effect_bits: Option<BTreeMap<String, u8>>,
+    weed_types: BTreeMap<String, Vec<String>>,
+    #[serde(default = "default_max_effects")]
+    max_effects: u8,
     rules: Vec<RuleJson>,
-    effect_price: HashMap<String, String>,
+    /// Fields this crate does not use, e.g. `ranks`, kept so they survive being written back out.
+    #[serde(flatten)]
+    extra: BTreeMap<String, serde_json::Value>,
 }
 
 pub struct MixtureRules {
     replacement_rules: [Vec<Rule>; SUBSTANCES.len()],
     inherent_effects: [Effects; SUBSTANCES.len()],
-    price_mults: [f64; NUM_EFFECTS as usize],
+    drug_effects: [Effects; DRUGS.len()],
+    effects: EffectRegistry,
+    substance_names: [String; SUBSTANCES.len()],
+    substance_table: NameTable<Substance>,
+    max_effects: u8,
+    /// Unused fields of the rules file, written back out unchanged.
+    extra: BTreeMap<String, serde_json::Value>,
 }
 
 impl MixtureRules {
@@ -247,25 +256,90 @@ impl MixtureRules {
         }
 
         let n_effects = effects.bits().count_ones();
-        if n_effects < MAX_EFFECTS as u32 {
+        if n_effects < self.max_effects as u32 {
             effects.insert(inherent_effects);
         }
         effects
     }
 
     pub fn price_multiplier(&self, effects: Effects) -> f64 {
-        let base = 1.0;
-        let mut multiplier = 0.;
-
-        // auto vectorization go brrr
-        let e = effects.bits();
-        for i in 0..NUM_EFFECTS {
-            if e & (1 << i) != 0 {
-                multiplier += self.price_mults[i as usize];
-            }
-        }
+        self.effects.price_multiplier(effects)
+    }
+
+    /// Effects of mixing `substances` into `drug` in order.
+    pub fn mix(&self, drug: Drugs, substances: &[Substance]) -> Effects {
+        substances
+            .iter()
+            .fold(self.drug_effects(drug), |effects, s| {
+                self.apply(*s, effects)
+            })
+    }
+
+    /// The replacement rules for a substance, in the order they are applied.
+    pub fn substance_rules(&self, substance: Substance) -> &[Rule] {
+        &self.replacement_rules[substance as usize]
+    }
+
+    /// The effects a substance adds when there is room for them.
+    pub fn inherent_effects(&self, substance: Substance) -> Effects {
+        self.inherent_effects[substance as usize]
+    }
+
+    /// The effects a drug starts out with before any substances are mixed in.
+    pub fn drug_effects(&self, drug: Drugs) -> Effects {
+        self.drug_effects[drug as usize]
+    }
+
+    /// The display name of a substance, e.g. `Flu Medicine`.
+    pub fn substance_name(&self, substance: Substance) -> &str {
+        &self.substance_names[substance as usize]
+    }
+
+    /// Parses a single substance given by display name or identifier, e.g. `Flu Medicine`.
+    pub fn parse_substance(&self, s: &str) -> Result<Substance, ParseError> {
+        self.substance_table.get(s)
+    }
+
+    /// Parses a comma- or `|`-separated sequence of substances, each given by display name or
+    /// identifier, e.g. `cuke, Flu Medicine, MegaBean`. Order and repetitions are preserved.
+    pub fn parse_substances(&self, s: &str) -> Result<Vec<Substance>, ParseError> {
+        self.substance_table.get_list(s)
+    }
+
+    /// The effects defined by the rules file.
+    pub fn effects(&self) -> &EffectRegistry {
+        &self.effects
+    }
+
+    /// Number of distinct effects defined by the rules file.
+    pub fn num_effects(&self) -> u8 {
+        self.effects.len() as u8
+    }
+
+    /// Maximum number of effects a product can carry.
+    pub fn max_effects(&self) -> u8 {
+        self.max_effects
+    }
+
+    /// Creates an encoder covering every effect set reachable under these rules.
+    pub fn encoder(&self) -> CombinatorialEncoder {
+        CombinatorialEncoder::new(self.num_effects(), self.max_effects)
+    }
 
-        base + multiplier
+    /// Checks that `encoder` was built for the same effect count and cap as these rules.
+    pub fn check_encoder(&self, encoder: &CombinatorialEncoder) -> Result<(), String> {
+        if encoder.num_elements() != self.num_effects()
+            || encoder.max_elements() != self.max_effects
+        {
+            return Err(format!(
+                "encoder covers up to {} of {} effects, but the rules define up to {} of {}",
+                encoder.max_elements(),
+                encoder.num_elements(),
+                self.max_effects,
+                self.num_effects()
+            ));
+        }
+        Ok(())
     }
 }
 
@@ -275,10 +349,285 @@ pub fn parse_rules_file<P: AsRef<Path>>(
 ) -> Result<MixtureRules, Box<dyn std::error::Error>> {
     // Open the file
     let file = File::open(path)?;
-    let reader = BufReader::new(file);
+    MixtureRules::from_reader(BufReader::new(file))
+}
+
+impl MixtureRules {
+    /// Parses a rules file from any reader.
+    pub fn from_reader(reader: impl Read) -> Result<Self, Box<dyn std::error::Error>> {
+        build_rules(serde_json::from_reader(reader)?)
+    }
+
+    /// Builds rules from an already-parsed rules file.
+    pub fn from_value(value: serde_json::Value) -> Result<Self, Box<dyn std::error::Error>> {
+        build_rules(serde_json::from_value(value)?)
+    }
+
+    /// Starts an empty rule set to be filled in programmatically.
+    pub fn builder() -> MixtureRulesBuilder {
+        MixtureRulesBuilder::default()
+    }
+}
+
+impl FromStr for MixtureRules {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(json: &str) -> Result<Self, Self::Err> {
+        build_rules(serde_json::from_str(json)?)
+    }
+}
+
+impl MixtureRules {
+    /// Converts the rules back to the rules file schema, e.g. to apply a patch and parse the
+    /// result with [`MixtureRules::from_value`].
+    pub fn to_value(&self) -> serde_json::Value {
+        serde_json::to_value(self).expect("rules should always serialize")
+    }
+
+    fn abbreviations(&self, effects: Effects) -> Vec<String> {
+        self.effects
+            .members(effects)
+            .map(|e| e.abbreviation.clone())
+            .collect()
+    }
+
+    fn to_rules_file(&self) -> RulesFile {
+        let codes = || SUBSTANCES.iter().map(|s| (*s, substance_code(*s)));
+
+        let mut rules = Vec::new();
+        for (substance, code) in codes() {
+            for rule in &self.replacement_rules[substance as usize] {
+                let entries = rule
+                    .replace
+                    .iter()
+                    .map(|(from, to)| {
+                        let abbreviation = |e| self.abbreviations(e).concat();
+                        (abbreviation(*from), abbreviation(*to))
+                    })
+                    .collect();
+                rules.push(RuleJson {
+                    if_present: self.abbreviations(rule.if_present),
+                    if_not_present: self.abbreviations(rule.if_not_present),
+                    requires_substance: code.to_string(),
+                    replace: ReplaceMap { entries },
+                });
+            }
+        }
+        rules.sort_by(|a, b| {
+            (&a.requires_substance, &a.if_present, &a.if_not_present).cmp(&(
+                &b.requires_substance,
+                &b.if_present,
+                &b.if_not_present,
+            ))
+        });
+
+        let legacy = self
+            .effects
+            .iter()
+            .all(|e| legacy_bit(&e.abbreviation) == Some(e.bit));
+        let per_effect = |f: fn(&EffectInfo) -> Option<String>| {
+            self.effects
+                .iter()
+                .filter_map(|e| Some((e.abbreviation.clone(), f(e)?)))
+                .collect::<BTreeMap<_, _>>()
+        };
+
+        RulesFile {
+            substances: codes()
+                .map(|(s, code)| (code.to_string(), self.substance_name(s).to_string()))
+                .collect(),
+            effects: codes()
+                .map(|(s, code)| EffectJson {
+                    substance: code.to_string(),
+                    effect: self.abbreviations(self.inherent_effects[s as usize]),
+                })
+                .collect(),
+            effect_abbreviations: per_effect(|e| Some(e.name.clone())),
+            effect_price: per_effect(|e| Some(e.price_multiplier.to_string())),
+            effect_color: per_effect(|e| e.color.clone()),
+            effect_bits: (!legacy).then(|| {
+                self.effects
+                    .iter()
+                    .map(|e| (e.abbreviation.clone(), e.bit))
+                    .collect()
+            }),
+            weed_types: DRUGS
+                .iter()
+                .map(|d| (d.to_string(), self.abbreviations(self.drug_effects(*d))))
+                .collect(),
+            max_effects: self.max_effects,
+            rules,
+            extra: self.extra.clone(),
+        }
+    }
+}
+
+/// Writes the rules in the same schema as the rules file. Maps are sorted by key and rules by
+/// substance, so the output is stable across runs.
+impl Serialize for MixtureRules {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.to_rules_file().serialize(serializer)
+    }
+}
+
+/// Builds [`MixtureRules`] in code rather than from a rules file, e.g. for small synthetic rule
+/// sets in tests. Effects are referred to by abbreviation and assigned bits in the order they are
+/// declared. Nothing is validated until [`MixtureRulesBuilder::build`], which applies the same
+/// checks as parsing a rules file.
+///
+/// ```
+/// use schedule1::mixing::{Drugs, MixtureRules, Substance};
+///
+/// let rules = MixtureRules::builder()
+///     .effect("Ca", "Calming", 0.1)
+///     .effect("En", "Energizing", 0.22)
+///     .inherent_effects(Substance::Cuke, &["En"])
+///     .rule(Substance::Cuke, &["Ca"], &["En"], &[("Ca", "En")])
+///     .drug_effects(Drugs::OGKush, &["Ca"])
+///     .max_effects(2)
+///     .build()
+///     .unwrap();
+/// let kush = rules.drug_effects(Drugs::OGKush);
+/// assert_eq!(rules.apply(Substance::Cuke, kush), rules.effects().parse("En").unwrap());
+/// ```
+pub struct MixtureRulesBuilder {
+    file: RulesFile,
+}
+
+impl Default for MixtureRulesBuilder {
+    fn default() -> Self {
+        Self {
+            file: RulesFile {
+                substances: BTreeMap::new(),
+                effects: Vec::new(),
+                effect_abbreviations: BTreeMap::new(),
+                effect_price: BTreeMap::new(),
+                effect_color: BTreeMap::new(),
+                effect_bits: Some(BTreeMap::new()),
+                weed_types: BTreeMap::new(),
+                max_effects: MAX_EFFECTS,
+                rules: Vec::new(),
+                extra: BTreeMap::new(),
+            },
+        }
+    }
+}
+
+impl MixtureRulesBuilder {
+    /// Declares an effect, which takes the next free bit.
+    pub fn effect(mut self, abbreviation: &str, name: &str, price_multiplier: f64) -> Self {
+        let bits = self.file.effect_bits.get_or_insert_with(BTreeMap::new);
+        let bit = bits.len() as u8;
+        bits.insert(abbreviation.to_string(), bit);
+        self.file
+            .effect_abbreviations
+            .insert(abbreviation.to_string(), name.to_string());
+        self.file
+            .effect_price
+            .insert(abbreviation.to_string(), price_multiplier.to_string());
+        self
+    }
+
+    /// Sets the effects a substance adds when there is room for them.
+    pub fn inherent_effects(mut self, substance: Substance, effects: &[&str]) -> Self {
+        let code = substance_code(substance).to_string();
+        self.file.effects.retain(|e| e.substance != code);
+        self.file.effects.push(EffectJson {
+            substance: code,
+            effect: to_strings(effects),
+        });
+        self
+    }
+
+    /// Adds a replacement rule: when `substance` is mixed into a product that has every effect in
+    /// `if_present` and is missing at least one in `if_not_present`, each `(from, to)` pair in
+    /// `replace` is applied.
+    pub fn rule(
+        mut self,
+        substance: Substance,
+        if_present: &[&str],
+        if_not_present: &[&str],
+        replace: &[(&str, &str)],
+    ) -> Self {
+        self.file.rules.push(RuleJson {
+            if_present: to_strings(if_present),
+            if_not_present: to_strings(if_not_present),
+            requires_substance: substance_code(substance).to_string(),
+            replace: ReplaceMap {
+                entries: replace
+                    .iter()
+                    .map(|(from, to)| (from.to_string(), to.to_string()))
+                    .collect(),
+            },
+        });
+        self
+    }
 
-    // Parse the JSON
-    let rules_file: RulesFile = serde_json::from_reader(reader)?;
+    /// Sets the effects a drug starts out with.
+    pub fn drug_effects(mut self, drug: Drugs, effects: &[&str]) -> Self {
+        self.file
+            .weed_types
+            .insert(drug.to_string(), to_strings(effects));
+        self
+    }
+
+    /// Sets the display name of a substance, which otherwise defaults to its identifier.
+    pub fn substance_name(mut self, substance: Substance, name: &str) -> Self {
+        self.file
+            .substances
+            .insert(substance_code(substance).to_string(), name.to_string());
+        self
+    }
+
+    /// Sets the most effects a product can carry; defaults to [`MAX_EFFECTS`].
+    pub fn max_effects(mut self, max_effects: u8) -> Self {
+        self.file.max_effects = max_effects;
+        self
+    }
+
+    pub fn build(self) -> Result<MixtureRules, Box<dyn std::error::Error>> {
+        build_rules(self.file)
+    }
+}
+
+fn to_strings(strings: &[&str]) -> Vec<String> {
+    strings.iter().map(|s| s.to_string()).collect()
+}
+
+fn build_rules(rules_file: RulesFile) -> Result<MixtureRules, Box<dyn std::error::Error>> {
+    let num_effects = u8::try_from(rules_file.effect_abbreviations.len())
+        .map_err(|_| "too many effects in rules file")?;
+    let max_effects = rules_file.max_effects;
+    CombinatorialEncoder::validate(num_effects, max_effects)?;
+
+    // Build the effect registry
+    let mut effect_infos = Vec::with_capacity(num_effects as usize);
+    for (abbreviation, name) in &rules_file.effect_abbreviations {
+        let bit = match &rules_file.effect_bits {
+            Some(bits) => bits.get(abbreviation).copied(),
+            None => legacy_bit(abbreviation),
+        }
+        .ok_or_else(|| format!("no bit index for effect '{abbreviation}'"))?;
+        let price_multiplier = match rules_file.effect_price.get(abbreviation) {
+            Some(price) => price.parse::<f64>()?,
+            None => 0.,
+        };
+        effect_infos.push(EffectInfo {
+            abbreviation: abbreviation.clone(),
+            name: name.clone(),
+            color: rules_file.effect_color.get(abbreviation).cloned(),
+            bit,
+            price_multiplier,
+        });
+    }
+    let registry = EffectRegistry::new(effect_infos)?;
+    if let Some(unknown) = rules_file
+        .effect_price
+        .keys()
+        .find(|e| registry.by_abbreviation(e).is_none())
+    {
+        return Err(format!("price given for unknown effect '{unknown}'").into());
+    }
 
     // Convert to our internal representation
     let mut replacement_rules = [const { Vec::new() }; SUBSTANCES.len()];
@@ -290,24 +639,20 @@ pub fn parse_rules_file<P: AsRef<Path>>(
         };
 
         // Parse the effects
-        let if_present = rule_json
-            .if_present
-            .iter()
-            .map(|s| string_to_effect(s))
-            .fold(Effects::empty(), |a, b| a | b);
+        let if_present = strings_to_effects(&registry, &rule_json.if_present)?;
 
-        let if_not_present = rule_json
-            .if_not_present
-            .iter()
-            .map(|s| string_to_effect(s))
-            .fold(Effects::empty(), |a, b| a | b);
+        let if_not_present = strings_to_effects(&registry, &rule_json.if_not_present)?;
 
         // Parse the replacements
         let mut remove = Effects::empty();
         let mut add = Effects::empty();
+        let mut replace = Vec::with_capacity(rule_json.replace.entries.len());
         for (from, to) in rule_json.replace.entries.iter() {
-            remove |= string_to_effect(from);
-            add |= string_to_effect(to);
+            let from = string_to_effect(&registry, from)?;
+            let to = string_to_effect(&registry, to)?;
+            remove |= from;
+            add |= to;
+            replace.push((from, to));
         }
 
         let rule = Rule {
@@ -315,6 +660,7 @@ pub fn parse_rules_file<P: AsRef<Path>>(
             if_not_present,
             remove,
             add,
+            replace,
         };
 
         // Add to our HashMap
@@ -330,9 +676,7 @@ pub fn parse_rules_file<P: AsRef<Path>>(
         }
         let mut new_order = Vec::with_capacity(rules.len());
         for effects in ts {
-            if let Some(r) = rules.iter().find(|r| r.if_present == effects) {
-                new_order.push(r.clone());
-            }
+            new_order.extend(rules.iter().filter(|r| r.if_present == effects).cloned());
         }
         *rules = new_order;
     }
@@ -340,28 +684,46 @@ pub fn parse_rules_file<P: AsRef<Path>>(
     // Convert inherent effects
     let mut inherent_effects = [Effects::empty(); SUBSTANCES.len()];
     for effect_json in &rules_file.effects {
-        let substance = string_to_substance(&effect_json.substance).unwrap();
-        let effects = effect_json
-            .effect
-            .iter()
-            .map(|s| string_to_effect(s))
-            .fold(Effects::empty(), |a, b| a | b);
+        let substance = string_to_substance(&effect_json.substance)
+            .ok_or_else(|| format!("unknown substance '{}'", effect_json.substance))?;
+        let effects = strings_to_effects(&registry, &effect_json.effect)?;
         inherent_effects[substance as usize] = effects;
     }
 
-    // Convert effect price mapping
-    let mut price_mults = [0.; NUM_EFFECTS as usize];
-    for (effect_string, price_string) in &rules_file.effect_price {
-        let effect = string_to_effect(effect_string);
-        let idx = effect.bits().ilog2();
-        let price = price_string.parse::<f64>()?;
-        price_mults[idx as usize] = price;
+    // Convert the starting effects of each drug
+    let mut drug_effects = [Effects::empty(); DRUGS.len()];
+    for (drug_string, effect_strings) in &rules_file.weed_types {
+        let drug =
+            string_to_drug(drug_string).ok_or_else(|| format!("unknown drug '{drug_string}'"))?;
+        drug_effects[drug as usize] = strings_to_effects(&registry, effect_strings)?;
+    }
+
+    // Convert substance names, falling back to the identifier when the file does not name one
+    let mut substance_names: [String; SUBSTANCES.len()] =
+        std::array::from_fn(|idx| format!("{:?}", SUBSTANCES[idx]));
+    for (code, name) in &rules_file.substances {
+        let substance =
+            string_to_substance(code).ok_or_else(|| format!("unknown substance '{code}'"))?;
+        substance_names[substance as usize] = name.clone();
+    }
+    let mut substance_table = NameTable::new("substance");
+    for substance in SUBSTANCES.iter().copied() {
+        substance_table.insert(
+            substance,
+            &substance_names[substance as usize],
+            [format!("{substance:?}").as_str()],
+        );
     }
 
     Ok(MixtureRules {
         replacement_rules,
         inherent_effects,
-        price_mults,
+        drug_effects,
+        effects: registry,
+        substance_names,
+        substance_table,
+        max_effects,
+        extra: rules_file.extra,
     })
 }
 
@@ -389,51 +751,49 @@ fn string_to_substance(substance: &str) -> Option<Substance> {
     Some(substance)
 }
 
-// Helper function to convert string to Effect enum
-fn string_to_effect(s: &str) -> Effects {
-    match s {
-        "Ag" => Effects::AntiGravity,
-        "At" => Effects::Athletic,
-        "Ba" => Effects::Balding,
-        "Be" => Effects::BrightEyed,
-        "Ca" => Effects::Calming,
-        "Cd" => Effects::CalorieDense,
-        "Cy" => Effects::Cyclopean,
-        "Di" => Effects::Disorienting,
-        "El" => Effects::Electrifying,
-        "En" => Effects::Energizing,
-        "Eu" => Effects::Euphoric,
-        "Ex" => Effects::Explosive,
-        "Fc" => Effects::Focused,
-        "Fo" => Effects::Foggy,
-        "Gi" => Effects::Gingeritis,
-        "Gl" => Effects::Glowing,
-        "Je" => Effects::Jennerising,
-        "La" => Effects::Laxative,
-        "Lf" => Effects::LongFaced,
-        "Mu" => Effects::Munchies,
-        "Pa" => Effects::Paranoia,
-        "Re" => Effects::Refreshing,
-        "Sc" => Effects::Schizophrenia,
-        "Se" => Effects::Sedating,
-        "Sh" => Effects::Shrinking,
-        "Si" => Effects::SeizureInducing,
-        "Sl" => Effects::Slippery,
-        "Sm" => Effects::Smelly,
-        "Sn" => Effects::Sneaky,
-        "Sp" => Effects::Spicy,
-        "To" => Effects::Toxic,
-        "Tp" => Effects::ThoughtProvoking,
-        "Tt" => Effects::TropicThunder,
-        "Zo" => Effects::Zombifying,
-        _ => panic!("Unknown effect: {}", s),
+/// The letter code used for `substance` in the rules file, the inverse of [`string_to_substance`].
+fn substance_code(substance: Substance) -> &'static str {
+    const CODES: [&str; SUBSTANCES.len()] = [
+        "A", "B", "C", "D", "E", "F", "G", "H", "I", "J", "K", "L", "M", "N", "O", "P",
+    ];
+    CODES[substance as usize]
+}
+
+fn string_to_drug(drug: &str) -> Option<Drugs> {
+    // The rules file misspells Granddaddy Purple, accept either spelling.
+    match drug {
+        "Granddady Purple" => Some(Drugs::GranddaddyPurple),
+        _ => DRUGS.iter().copied().find(|d| d.to_string() == drug),
     }
 }
 
+// Helper function to convert an abbreviation to its effect
+fn string_to_effect(registry: &EffectRegistry, s: &str) -> Result<Effects, String> {
+    registry
+        .by_abbreviation(s)
+        .map(EffectInfo::effect)
+        .ok_or_else(|| format!("Unknown effect: {s}"))
+}
+
+fn strings_to_effects(registry: &EffectRegistry, strings: &[String]) -> Result<Effects, String> {
+    strings.iter().try_fold(Effects::empty(), |effects, s| {
+        Ok(effects | string_to_effect(registry, s)?)
+    })
+}
+
 #[cfg(test)]
+#[allow(clippy::items_after_test_module)]
 mod tests {
-    use crate::mixing::{parse_rules_file, Effects, Substance};
+    use crate::combinatorial::CombinatorialEncoder;
+    use crate::mixing::{
+        parse_drug, parse_rules_file, Drugs, Effects, MixtureRules, Substance, DRUGS, SUBSTANCES,
+    };
     use std::error::Error;
+    use std::fs::File;
+
+    fn parse(rules: &MixtureRules, effects: &str) -> Effects {
+        rules.effects().parse(effects).expect("invalid effects")
+    }
 
     #[test]
     fn test_regression_cocaine() -> Result<(), Box<dyn Error>> {
@@ -443,24 +803,24 @@ mod tests {
 
         // First mix
         let effects = rules.apply(Substance::HorseSemen, effects);
-        assert_eq!(effects, Effects::LongFaced);
+        assert_eq!(effects, parse(&rules, "LongFaced"));
 
         // Second mix
         let effects = rules.apply(Substance::Addy, effects);
-        assert_eq!(effects, Effects::Electrifying | Effects::ThoughtProvoking);
+        assert_eq!(effects, parse(&rules, "Electrifying | ThoughtProvoking"));
 
         // Third mix
         let effects = rules.apply(Substance::Battery, effects);
         assert_eq!(
             effects,
-            Effects::Euphoric | Effects::ThoughtProvoking | Effects::BrightEyed
+            parse(&rules, "Euphoric | ThoughtProvoking | BrightEyed")
         );
 
         // Fourth mix
         let effects = rules.apply(Substance::HorseSemen, effects);
         assert_eq!(
             effects,
-            Effects::Electrifying | Effects::BrightEyed | Effects::LongFaced | Effects::Euphoric
+            parse(&rules, "Electrifying | BrightEyed | LongFaced | Euphoric")
         );
 
         Ok(())
@@ -474,36 +834,87 @@ mod tests {
 
         // First mix
         let effects = rules.apply(Substance::MegaBean, effects);
-        assert_eq!(effects, Effects::Foggy);
+        assert_eq!(effects, parse(&rules, "Foggy"));
 
         // Second mix
         let effects = rules.apply(Substance::Cuke, effects);
-        assert_eq!(effects, Effects::Cyclopean | Effects::Energizing);
+        assert_eq!(effects, parse(&rules, "Cyclopean | Energizing"));
 
         // Third mix
         let effects = rules.apply(Substance::Banana, effects);
         assert_eq!(
             effects,
-            Effects::Energizing | Effects::ThoughtProvoking | Effects::Gingeritis
+            parse(&rules, "Energizing | ThoughtProvoking | Gingeritis")
         );
 
         // Fourth mix
         let effects = rules.apply(Substance::HorseSemen, effects);
         assert_eq!(
             effects,
-            Effects::Energizing | Effects::Electrifying | Effects::Refreshing | Effects::LongFaced
+            parse(&rules, "Energizing | Electrifying | Refreshing | LongFaced")
         );
 
         // Fifth mix
         let effects = rules.apply(Substance::Iodine, effects);
         assert_eq!(
             effects,
-            Effects::Energizing
-                | Effects::Electrifying
-                | Effects::ThoughtProvoking
-                | Effects::LongFaced
-                | Effects::Jennerising
+            parse(
+                &rules,
+                "Energizing | Electrifying | ThoughtProvoking | LongFaced | Jennerising"
+            )
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_effect_parameters() -> Result<(), Box<dyn Error>> {
+        let rules = parse_rules_file("sch1-mix-rules.json")?;
+        assert_eq!(rules.num_effects(), 34);
+        assert_eq!(rules.max_effects(), 8);
+
+        assert_eq!(rules.drug_effects(Drugs::OGKush), parse(&rules, "Calming"));
+        assert_eq!(
+            rules.drug_effects(Drugs::GranddaddyPurple),
+            parse(&rules, "Sedating")
         );
+        assert_eq!(rules.drug_effects(Drugs::Cocaine), Effects::empty());
+
+        let encoder = rules.encoder();
+        assert!(rules.check_encoder(&encoder).is_ok());
+        assert!(rules
+            .check_encoder(&CombinatorialEncoder::new(34, 7))
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_names() -> Result<(), Box<dyn Error>> {
+        let rules = parse_rules_file("sch1-mix-rules.json")?;
+        assert_eq!(rules.substance_name(Substance::FluMedicine), "Flu Medicine");
+        assert_eq!(
+            rules.parse_substances("cuke, Flu Medicine | MegaBean, cuke")?,
+            [
+                Substance::Cuke,
+                Substance::FluMedicine,
+                Substance::MegaBean,
+                Substance::Cuke
+            ]
+        );
+        let err = rules.parse_substances("Cuke, Banna").unwrap_err();
+        assert_eq!(err.suggestions.first().map(String::as_str), Some("Banana"));
+
+        assert_eq!(
+            rules
+                .effects()
+                .parse("anti-gravity, Fo, ThoughtProvoking")?,
+            parse(&rules, "Ag | Foggy | Thought-Provoking")
+        );
+
+        assert_eq!(parse_drug("og kush")?, Drugs::OGKush);
+        assert_eq!(parse_drug("GranddaddyPurple")?, Drugs::GranddaddyPurple);
+        assert!(parse_drug("Weed").is_err());
 
         Ok(())
     }
@@ -511,18 +922,212 @@ mod tests {
     #[test]
     fn test_price_regression() -> Result<(), Box<dyn Error>> {
         let rules = parse_rules_file("sch1-mix-rules.json")?;
-        let effects = Effects::AntiGravity
-            | Effects::Glowing
-            | Effects::TropicThunder
-            | Effects::Zombifying
-            | Effects::Cyclopean
-            | Effects::Foggy
-            | Effects::BrightEyed;
+        let effects = parse(
+            &rules,
+            "AntiGravity | Glowing | TropicThunder | Zombifying | Cyclopean | Foggy | BrightEyed",
+        );
         let price = (150.0 * rules.price_multiplier(effects)).round() as i64;
         assert_eq!(price, 657);
 
         Ok(())
     }
+
+    #[test]
+    fn test_constructors() -> Result<(), Box<dyn Error>> {
+        let from_file = parse_rules_file("sch1-mix-rules.json")?;
+        let json = std::fs::read_to_string("sch1-mix-rules.json")?;
+        let others = [
+            json.parse::<MixtureRules>()?,
+            MixtureRules::from_reader(File::open("sch1-mix-rules.json")?)?,
+            MixtureRules::from_value(serde_json::from_str(&json)?)?,
+        ];
+        let effects = parse(&from_file, "Calming, Foggy");
+        for rules in others {
+            assert_eq!(rules.effects(), from_file.effects());
+            assert_eq!(rules.max_effects(), from_file.max_effects());
+            assert_eq!(
+                rules.apply(Substance::Cuke, effects),
+                from_file.apply(Substance::Cuke, effects)
+            );
+        }
+
+        assert!("{}".parse::<MixtureRules>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_builder() -> Result<(), Box<dyn Error>> {
+        let rules = MixtureRules::builder()
+            .effect("Ca", "Calming", 0.1)
+            .effect("En", "Energizing", 0.22)
+            .effect("Fo", "Foggy", 0.36)
+            .inherent_effects(Substance::Cuke, &["En"])
+            .inherent_effects(Substance::Banana, &["Fo"])
+            // Chained replacements must not cascade: Ca -> En and En -> Fo applied to {Ca, En}
+            // gives {En, Fo}.
+            .rule(Substance::Banana, &["Ca"], &["En"], &[("Ca", "En")])
+            .rule(Substance::Banana, &["En"], &["Fo"], &[("En", "Fo")])
+            .drug_effects(Drugs::OGKush, &["Ca"])
+            .substance_name(Substance::Cuke, "Cucumber")
+            .max_effects(2)
+            .build()?;
+
+        assert_eq!(rules.num_effects(), 3);
+        assert_eq!(rules.effects().lookup("Energizing")?.bit, 1);
+        assert_eq!(rules.substance_name(Substance::Cuke), "Cucumber");
+        assert_eq!(rules.drug_effects(Drugs::Meth), Effects::empty());
+        assert_eq!(rules.price_multiplier(parse(&rules, "Ca, Fo")), 1.46);
+
+        let kush = rules.drug_effects(Drugs::OGKush);
+        assert_eq!(kush, parse(&rules, "Calming"));
+        let mixed = rules.apply(Substance::Cuke, kush);
+        assert_eq!(mixed, parse(&rules, "Calming, Energizing"));
+        assert_eq!(
+            rules.apply(Substance::Banana, mixed),
+            parse(&rules, "Energizing, Foggy")
+        );
+        // Already at the cap and no rule applies, so Cuke's Energizing is not added.
+        let capped = parse(&rules, "Ca, Fo");
+        assert_eq!(rules.apply(Substance::Cuke, capped), capped);
+
+        let unknown = MixtureRules::builder()
+            .effect("Ca", "Calming", 0.1)
+            .inherent_effects(Substance::Cuke, &["Xx"])
+            .max_effects(1)
+            .build();
+        assert!(unknown.is_err());
+
+        Ok(())
+    }
+
+    /// Checks that `a` and `b` apply every substance identically to every effect set with at most
+    /// `max_effects` effects. Replacement rules only read and write the effects they mention, and
+    /// the remaining effects only matter through their count, so it is enough to try every subset
+    /// of the mentioned effects padded out with each possible number of unrelated effects.
+    fn assert_same_behaviour(a: &MixtureRules, b: &MixtureRules) {
+        assert_eq!(a.max_effects(), b.max_effects());
+        assert_eq!(a.effects().all(), b.effects().all());
+        let max_effects = a.max_effects() as u32;
+        for substance in SUBSTANCES.iter().copied() {
+            let mentioned = [a, b]
+                .iter()
+                .flat_map(|r| &r.replacement_rules[substance as usize])
+                .fold(Effects::empty(), |m, r| {
+                    m | r.if_present | r.if_not_present | r.remove | r.add
+                });
+            let unrelated = a.effects().all() - mentioned - a.inherent_effects[substance as usize];
+            let padding = (0..=max_effects)
+                .map(|k| {
+                    let mut bits = unrelated.bits();
+                    for _ in 0..unrelated.bits().count_ones().saturating_sub(k) {
+                        bits &= bits - 1;
+                    }
+                    Effects::from_bits_retain(bits)
+                })
+                .collect::<Vec<_>>();
+
+            // Enumerate every subset of the mentioned effects.
+            let mut subset = 0u64;
+            loop {
+                let count = subset.count_ones();
+                if count <= max_effects {
+                    for pad in &padding[..=(max_effects - count) as usize] {
+                        let effects = Effects::from_bits_retain(subset) | *pad;
+                        assert_eq!(
+                            a.apply(substance, effects),
+                            b.apply(substance, effects),
+                            "{substance:?} applied to {}",
+                            a.effects().display(effects)
+                        );
+                    }
+                }
+                subset = subset.wrapping_sub(mentioned.bits()) & mentioned.bits();
+                if subset == 0 {
+                    break;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_round_trip() -> Result<(), Box<dyn Error>> {
+        let rules = parse_rules_file("sch1-mix-rules.json")?;
+        let json = serde_json::to_string_pretty(&rules)?;
+        let reparsed = json.parse::<MixtureRules>()?;
+
+        assert_same_behaviour(&rules, &reparsed);
+        assert_eq!(reparsed.effects(), rules.effects());
+        for drug in DRUGS.iter().copied() {
+            assert_eq!(reparsed.drug_effects(drug), rules.drug_effects(drug));
+        }
+        for substance in SUBSTANCES.iter().copied() {
+            assert_eq!(
+                reparsed.substance_name(substance),
+                rules.substance_name(substance)
+            );
+        }
+
+        // Output is stable, and fields this crate does not use are preserved.
+        assert_eq!(serde_json::to_string_pretty(&reparsed)?, json);
+        let value = rules.to_value();
+        assert_eq!(
+            value["ranks"],
+            serde_json::from_str::<serde_json::Value>(&std::fs::read_to_string(
+                "sch1-mix-rules.json"
+            )?)?["ranks"]
+        );
+        assert!(value.get("effect_bits").is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_round_trip_patch() -> Result<(), Box<dyn Error>> {
+        let rules = MixtureRules::builder()
+            .effect("Ca", "Calming", 0.1)
+            .effect("En", "Energizing", 0.22)
+            .inherent_effects(Substance::Cuke, &["En"])
+            .rule(Substance::Cuke, &["Ca"], &["En"], &[("Ca", "En")])
+            .max_effects(2)
+            .build()?;
+        let mut value = rules.to_value();
+        assert_eq!(value["effect_bits"]["En"], 1);
+
+        // Drop Cuke's only rule.
+        value["rules"] = serde_json::json!([]);
+        let patched = MixtureRules::from_value(value)?;
+        let calming = patched.effects().parse("Calming")?;
+        assert_eq!(
+            patched.apply(Substance::Cuke, calming),
+            parse(&patched, "Calming, Energizing")
+        );
+        assert_eq!(
+            rules.apply(Substance::Cuke, calming),
+            parse(&rules, "Energizing")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_builder_keeps_rules_with_shared_conditions() -> Result<(), Box<dyn Error>> {
+        let rules = MixtureRules::builder()
+            .effect("Ca", "Calming", 0.1)
+            .effect("En", "Energizing", 0.22)
+            .effect("Fo", "Focused", 0.16)
+            .rule(Substance::Cuke, &["Ca"], &["En"], &[("Ca", "En")])
+            .rule(Substance::Cuke, &["Ca"], &["Fo"], &[("Ca", "Fo")])
+            .max_effects(2)
+            .build()?;
+        assert_eq!(rules.substance_rules(Substance::Cuke).len(), 2);
+
+        let mut value = rules.to_value();
+        value["effects"] = serde_json::json!([{"substance": "Xx", "effect": ["En"]}]);
+        let err = MixtureRules::from_value(value)
+            .err()
+            .expect("unknown substance");
+        assert!(err.to_string().contains("unknown substance 'Xx'"));
+        Ok(())
+    }
 }
 
 pub fn base_price(drug: Drugs) -> f64 {
@@ -2,8 +2,9 @@
 //! Sedeño-Noda, Borndörfer. An Improved Multiobjective Shortest Path Algorithm. Computers and
 //! Operations Research 135 (2021).
 
+use crate::combinatorial::CombinatorialEncoder;
 use crate::effect_graph::EffectGraph;
-use crate::mixing::{Effects, Substance, SUBSTANCES};
+use crate::mixing::{Effects, MixtureRules, Substance, SUBSTANCES};
 use priority_queue::PriorityQueue;
 use savefile_derive::Savefile;
 use serde::{Deserialize, Serialize};
@@ -13,25 +14,49 @@ pub type EffectIndex = u32;
 pub type Cost = u16;
 pub type PathLength = u8;
 
-const NICHE: EffectIndex = EffectIndex::MAX;
+const NICHE: u32 = u32::MAX;
+
+/// Low bits of a backlink that hold the position of the extended label. The node sits in the bits
+/// above, so labels still order by node and then position.
+const POSITION_BITS: u32 = 7;
+
+/// Number of nodes a backlink can address without any backlink being [`NICHE`].
+const MAX_NODES: usize = (NICHE >> POSITION_BITS) as usize;
 
 #[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Eq, Ord, Savefile, Serialize, Deserialize)]
 pub struct Label {
     pub length: PathLength,
     pub cost: Cost,
     previous_substance: Substance,
-    backlink: EffectIndex,
+    /// The node this label extends, above the position of the extended label among the labels of
+    /// that node, or [`NICHE`] for the starting label. Labels are only ever appended to a node, so
+    /// the position is stable.
+    backlink: u32,
 }
 
 impl Label {
-    pub fn backlink(&self) -> Option<(EffectIndex, Substance)> {
+    /// The node this label extends, the position of the extended label at that node, and the
+    /// substance mixed in to get here. `None` for the starting label.
+    pub fn backlink(&self) -> Option<(EffectIndex, usize, Substance)> {
         match self.backlink {
             NICHE => None,
-            _ => Some((self.backlink, self.previous_substance)),
+            packed => Some((
+                packed >> POSITION_BITS,
+                (packed & ((1 << POSITION_BITS) - 1)) as usize,
+                self.previous_substance,
+            )),
         }
     }
 }
 
+fn pack_backlink(node: EffectIndex, position: usize) -> u32 {
+    let position = u32::try_from(position)
+        .ok()
+        .filter(|p| *p < 1 << POSITION_BITS)
+        .expect("a node holds at most one label per path length, and few lengths are efficient");
+    node << POSITION_BITS | position
+}
+
 type Queue = PriorityQueue<EffectIndex, Reverse<Label>>;
 
 fn label_nondominated_nonequal(label: Label, existing: &[Label]) -> bool {
@@ -66,12 +91,12 @@ fn next_candidate_label(
     let mut new_candidate = None;
     let existing_labels = &permanent_labels[node as usize];
     for (pred, sub) in predecessors {
-        for old_label in &permanent_labels[pred as usize] {
+        for (position, old_label) in permanent_labels[pred as usize].iter().enumerate() {
             let new_label = Label {
                 length: old_label.length + 1,
                 cost: old_label.cost + substance_costs[sub as usize],
                 previous_substance: sub,
-                backlink: pred,
+                backlink: pack_backlink(pred, position),
             };
             // Test for dominance of existing items over this new candidate
             if label_nondominated_nonequal(new_label, existing_labels) {
@@ -98,11 +123,15 @@ fn propagate(
     pending.push_increase(child, Reverse(new_label));
 }
 
-pub fn multiobjective_shortest_path<const N: u8, const K: u8>(
-    graph: &EffectGraph<N, K>,
+pub fn multiobjective_shortest_path(
+    graph: &EffectGraph,
     substance_costs: &[Cost],
     starting_node: Effects,
 ) -> Vec<Vec<Label>> {
+    assert!(
+        graph.num_nodes() <= MAX_NODES,
+        "backlinks address at most {MAX_NODES} effect sets"
+    );
     let mut permanent_labels = vec![Vec::new(); graph.num_nodes()];
     let mut pending = Queue::new();
     pending.push(
@@ -117,6 +146,7 @@ pub fn multiobjective_shortest_path<const N: u8, const K: u8>(
 
     while let Some((node, label)) = pending.pop() {
         permanent_labels[node as usize].push(label.0);
+        let backlink = pack_backlink(node, permanent_labels[node as usize].len() - 1);
         if let Some(candidate) = next_candidate_label(
             node,
             graph.predecessors_with_substances(node),
@@ -131,7 +161,7 @@ pub fn multiobjective_shortest_path<const N: u8, const K: u8>(
                     length: label.0.length + 1,
                     cost: label.0.cost + substance_costs[idx],
                     previous_substance: SUBSTANCES[idx],
-                    backlink: node,
+                    backlink,
                 },
                 *child,
                 &permanent_labels[*child as usize],
@@ -142,3 +172,168 @@ pub fn multiobjective_shortest_path<const N: u8, const K: u8>(
 
     permanent_labels
 }
+
+/// Reference for [`multiobjective_shortest_path`] on small instances: the Pareto-optimal
+/// `(length, cost)` pairs of every node, by increasing length.
+///
+/// Rather than following the graph, this applies `rules` to every effect set reachable with each
+/// path length in turn, keeping the cheapest cost of reaching each node with exactly that many
+/// substances. A node's front is every length that is cheaper than all shorter ones. Once a
+/// length improves no node, no longer path can either, so the search stops.
+pub fn reference_pareto_fronts(
+    rules: &MixtureRules,
+    encoder: &CombinatorialEncoder,
+    substance_costs: &[Cost],
+    starting_node: Effects,
+) -> Vec<Vec<(PathLength, Cost)>> {
+    let num_nodes = encoder.maximum_index() as usize;
+    let mut fronts = vec![Vec::new(); num_nodes];
+    let mut cheapest: Vec<Option<Cost>> = vec![None; num_nodes];
+    let mut layer: Vec<Option<Cost>> = vec![None; num_nodes];
+    layer[encoder.encode(starting_node.bits()) as usize] = Some(0);
+
+    for length in 0..=PathLength::MAX {
+        let mut improved = false;
+        for (node, cost) in layer.iter().enumerate() {
+            let Some(cost) = *cost else { continue };
+            if cheapest[node].is_none_or(|c| cost < c) {
+                cheapest[node] = Some(cost);
+                fronts[node].push((length, cost));
+                improved = true;
+            }
+        }
+        if !improved {
+            break;
+        }
+
+        let mut next: Vec<Option<Cost>> = vec![None; num_nodes];
+        for (node, cost) in layer.iter().enumerate() {
+            let Some(cost) = *cost else { continue };
+            let effects = Effects::from(encoder.decode(node as EffectIndex));
+            for substance in SUBSTANCES.iter().copied() {
+                let child = encoder.encode(rules.apply(substance, effects).bits()) as usize;
+                let cost = cost + substance_costs[substance as usize];
+                if next[child].is_none_or(|c| cost < c) {
+                    next[child] = Some(cost);
+                }
+            }
+        }
+        layer = next;
+    }
+    fronts
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::effect_graph::EffectGraph;
+    use crate::mixing::{Effects, MixtureRules, SUBSTANCES};
+    use crate::mosp::{multiobjective_shortest_path, reference_pareto_fronts, Cost, Label};
+    use proptest::prelude::*;
+
+    /// A random rule set over `num_effects` effects: inherent effects for every substance, and
+    /// replacement rules of the shape used by the game, `a unless b: a -> b`, optionally requiring
+    /// a second effect.
+    #[derive(Debug, Clone)]
+    struct Instance {
+        num_effects: u8,
+        max_effects: u8,
+        inherent: Vec<Option<u8>>,
+        rules: Vec<(usize, u8, u8, Option<u8>)>,
+        costs: Vec<Cost>,
+        start: Vec<u8>,
+    }
+
+    impl Instance {
+        fn rules(&self) -> MixtureRules {
+            let name = |e: u8| format!("E{e}");
+            let mut builder = MixtureRules::builder().max_effects(self.max_effects);
+            for e in 0..self.num_effects {
+                builder = builder.effect(&name(e), &name(e), 0.1 * e as f64);
+            }
+            for (substance, effect) in SUBSTANCES.iter().zip(&self.inherent) {
+                let effects = effect.map(name).into_iter().collect::<Vec<_>>();
+                let effects = effects.iter().map(String::as_str).collect::<Vec<_>>();
+                builder = builder.inherent_effects(*substance, &effects);
+            }
+            for &(substance, from, to, also) in &self.rules {
+                let (from, to) = (name(from), name(to));
+                let also = also.map(name);
+                let mut if_present = vec![from.as_str()];
+                if_present.extend(also.as_deref().filter(|a| *a != from && *a != to));
+                builder = builder.rule(
+                    SUBSTANCES[substance],
+                    &if_present,
+                    &[to.as_str()],
+                    &[(from.as_str(), to.as_str())],
+                );
+            }
+            builder.build().expect("generated rules should be valid")
+        }
+    }
+
+    fn instances() -> impl Strategy<Value = Instance> {
+        (2..=6u8)
+            .prop_flat_map(|n| (Just(n), 1..=n.min(4)))
+            .prop_flat_map(|(n, k)| {
+                let effect = 0..n;
+                let rule = (0..SUBSTANCES.len(), effect.clone(), effect.clone())
+                    .prop_filter("rules must replace an effect", |(_, a, b)| a != b)
+                    .prop_flat_map(move |(s, a, b)| {
+                        (Just(s), Just(a), Just(b), proptest::option::of(0..n))
+                    });
+                (
+                    Just(n),
+                    Just(k),
+                    proptest::collection::vec(
+                        proptest::option::of(effect.clone()),
+                        SUBSTANCES.len(),
+                    ),
+                    proptest::collection::vec(rule, 0..16),
+                    proptest::collection::vec(1..=4 as Cost, SUBSTANCES.len()),
+                    proptest::collection::btree_set(effect, 0..=k as usize),
+                )
+            })
+            .prop_map(
+                |(num_effects, max_effects, inherent, rules, costs, start)| Instance {
+                    num_effects,
+                    max_effects,
+                    inherent,
+                    rules,
+                    costs,
+                    start: start.into_iter().collect(),
+                },
+            )
+    }
+
+    proptest! {
+        #[test]
+        fn test_matches_reference(instance in instances()) {
+            let rules = instance.rules();
+            let encoder = rules.encoder();
+            let graph = EffectGraph::new(&rules, encoder.clone());
+            let start = instance
+                .start
+                .iter()
+                .fold(Effects::empty(), |e, bit| e | Effects::from(1u64 << bit));
+
+            let fronts = multiobjective_shortest_path(&graph, &instance.costs, start);
+            let expected = reference_pareto_fronts(&rules, &encoder, &instance.costs, start);
+            for (node, (labels, expected)) in fronts.iter().zip(&expected).enumerate() {
+                let mut found = labels.iter().map(|l| (l.length, l.cost)).collect::<Vec<_>>();
+                found.sort_unstable();
+                prop_assert_eq!(&found, expected, "node {}", node);
+                for label in labels {
+                    if let Some((previous, position, _)) = label.backlink() {
+                        let extended = fronts[previous as usize][position];
+                        prop_assert_eq!(extended.length + 1, label.length);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_label_size() {
+        assert_eq!(size_of::<Label>(), 8);
+    }
+}
@@ -0,0 +1,377 @@
+//! Machine-readable output for the CLI.
+//!
+//! Every subcommand that reports results can emit them as JSON (a single array), NDJSON (one JSON
+//! object per line) or CSV (one row per record, with a header). Each subcommand emits a single
+//! record type, documented below. Field names and order are part of the schema; new fields are
+//! only ever appended.
+//!
+//! Drugs and ingredients are written as identifiers (e.g. `OGKush`, `MegaBean`), effects as their
+//! display names from the rules file (e.g. `Anti-Gravity`). In CSV, list-valued fields are joined
+//! with `;` and missing values are left empty.
+//!
+//! | Subcommand       | Record                 |
+//! |------------------|------------------------|
+//! | `search`         | [`RouteRecord`]        |
+//! | `lookup`         | [`RouteRecord`]        |
+//! | `profit`         | [`ProfitRecord`]       |
+//! | `metadata`       | [`MetadataRecord`]     |
+//! | `route-sanity`   | [`SanityRecord`]       |
+//! | `simulate`       | [`SimulationRecord`]   |
+//! | `diff-rules`     | [`RulesDiffRecord`]    |
+//! | `verify-recipes` | [`RecipeRecord`]       |
+//! | `reachability`   | [`ReachabilityRecord`] |
+//! | `export`         | [`ExportRecord`]       |
+
+use crate::mixing::{Drugs, Substance};
+use serde::Serialize;
+use serde_json::Value;
+use std::error::Error;
+use std::io::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum Format {
+    /// Human-readable text
+    Text,
+    /// A single JSON array of records
+    Json,
+    /// One JSON record per line
+    Ndjson,
+    /// Comma-separated values with a header row
+    Csv,
+}
+
+/// A route from a drug's starting effects to an effect set.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RouteRecord {
+    /// Drug the route starts from. Meth routes also apply to Cocaine.
+    pub drug: Drugs,
+    /// For `search`, the objective that selected the route: `lowest_cost` or `shortest`. Always
+    /// empty for `lookup`.
+    pub criterion: Option<&'static str>,
+    /// Encoded index of the resulting effect set.
+    pub index: u32,
+    /// Resulting effects.
+    pub effects: Vec<String>,
+    /// Total cost of the ingredients.
+    pub cost: u16,
+    /// Number of ingredients.
+    pub length: u8,
+    /// Ingredients in the order they are mixed in.
+    pub ingredients: Vec<Substance>,
+}
+
+/// One of the most profitable effect sets reachable from a drug.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ProfitRecord {
+    pub drug: Drugs,
+    /// Encoded index of the resulting effect set.
+    pub index: u32,
+    pub effects: Vec<String>,
+    /// Sale price after markup, capped at `--max-price`.
+    pub sell_price: i32,
+    /// Total cost of the ingredients.
+    pub cost: u16,
+    /// `sell_price - cost`.
+    pub profit: i32,
+    /// Number of ingredients.
+    pub length: u8,
+    pub ingredients: Vec<Substance>,
+}
+
+/// A single statistic about a graph or routes file, in long format.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct MetadataRecord {
+    /// `graph` or `routes`.
+    pub section: &'static str,
+    /// Drug the statistic applies to, empty for graph statistics. Meth also covers Cocaine.
+    pub drug: Option<Drugs>,
+    /// One of:
+    /// - `nodes`, `backlinks` (graph): totals, `key` is empty.
+    /// - `file_size`, `raw_size` (graph, routes): bytes in the file, and as a plain savefile,
+    ///   `key` is empty. The file is smaller when saved in a container.
+    /// - `rules_hash`, `created` (graph, routes): from the file's header, see
+    ///   [`crate::file_header::FileHeader`], `key` is empty. Absent for files without a header.
+    /// - `labels` (routes): total number of Pareto labels, `key` is empty.
+    /// - `label_count` (routes): number of nodes with `key` labels.
+    /// - `min_length` (routes): number of nodes whose shortest route has `key` ingredients.
+    /// - `longest_min_length` (routes): a node index whose shortest route has `key` ingredients,
+    ///   for the nodes furthest from the starting effects.
+    pub metric: &'static str,
+    pub key: Option<u64>,
+    pub value: u64,
+}
+
+/// The outcome of one route sanity check for one drug.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SanityRecord {
+    /// Name of the check, e.g. `pareto optimality`.
+    pub check: &'static str,
+    pub drug: Drugs,
+    /// Number of effect sets violating the check.
+    pub violations: usize,
+    /// Up to ten violating effect set indices.
+    pub examples: Vec<u32>,
+    /// For checks of individual routes, the offending route of each example as comma-separated
+    /// ingredients. Empty otherwise.
+    pub example_paths: Vec<String>,
+}
+
+/// The state of a mix after one step of a simulation.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SimulationRecord {
+    pub drug: Drugs,
+    /// Number of ingredients mixed in so far. Step 0 is the unmixed drug.
+    pub step: usize,
+    /// Ingredient mixed in at this step, empty for step 0.
+    pub substance: Option<Substance>,
+    pub effects: Vec<String>,
+    /// Sale price of the mix at this step, without markup.
+    pub sell_price: i32,
+    /// Total cost of the ingredients mixed in so far.
+    pub cost: u16,
+}
+
+/// One difference between two rules files. `section` says which fields are filled in:
+///
+/// - `rule`: a rule of `substance` that was `added`, `removed` or `changed`, with the rule
+///   before and after.
+/// - `transitions`: how many of the `transitions` from effect sets mixed with `substance`
+///   produce different effects, with the indices of a few `examples`.
+/// - `recipe`: the effects and sale price of mixing `ingredients` into `drug` before and after,
+///   with `change` either `changed` or `unchanged`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RulesDiffRecord {
+    pub section: &'static str,
+    pub substance: Option<Substance>,
+    pub drug: Option<Drugs>,
+    pub ingredients: Vec<Substance>,
+    pub change: Option<&'static str>,
+    pub before: Option<String>,
+    pub after: Option<String>,
+    pub before_price: Option<i32>,
+    pub after_price: Option<i32>,
+    pub transitions: Option<u64>,
+    pub differing: Option<u64>,
+    pub examples: Vec<u32>,
+}
+
+/// A recipe from a recipe book, replayed under the current rules.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RecipeRecord {
+    pub name: String,
+    pub drug: Drugs,
+    pub ingredients: Vec<Substance>,
+    /// Resulting effects.
+    pub effects: Vec<String>,
+    /// Sale price of the result, without markup.
+    pub sell_price: i32,
+    /// Total cost of the ingredients.
+    pub cost: u16,
+    /// Number of ingredients.
+    pub length: usize,
+    /// Whether a route to the same effects is no more expensive and no longer, and better in one
+    /// of the two. Empty when no routes were given.
+    pub dominated: Option<bool>,
+    /// Cost of the cheapest such route.
+    pub better_cost: Option<u16>,
+    /// Length of that route.
+    pub better_length: Option<u8>,
+    /// Ingredients of that route, empty if the recipe is not dominated.
+    pub better_ingredients: Vec<Substance>,
+}
+
+/// What can be mixed from each product. `section` says which fields are filled in:
+///
+/// - `size`: how many of the `effect_sets` with `size` effects are `reachable` from `drug`, or
+///   from any product if `drug` is empty.
+/// - `never`: an effect in `effects` that no product can reach.
+/// - `exclusive`: two `effects` that can each be reached, but never together.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ReachabilityRecord {
+    pub section: &'static str,
+    pub drug: Option<Drugs>,
+    pub size: Option<u8>,
+    pub effect_sets: Option<u64>,
+    pub reachable: Option<u64>,
+    pub effects: Vec<String>,
+}
+
+/// A route in a table of every route, see [`crate::export`].
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ExportRecord {
+    /// Drug the route starts from. Meth and Cocaine share routes but are sold at different prices,
+    /// so both are listed.
+    pub drug: Drugs,
+    /// Encoded index of the resulting effect set.
+    pub index: u32,
+    pub effects: Vec<String>,
+    /// Number of resulting effects.
+    pub effect_count: u8,
+    /// Multiplier of the base price for the resulting effects.
+    pub price_multiplier: f64,
+    /// Total cost of the ingredients.
+    pub cost: u16,
+    /// Number of ingredients.
+    pub length: u8,
+    /// Sale price after markup.
+    pub sell_price: i32,
+    pub ingredients: Vec<Substance>,
+}
+
+/// Writes records in one of the machine-readable formats. Must be finished with
+/// [`RecordWriter::finish`] to produce valid JSON and to flush buffered CSV rows.
+pub struct RecordWriter<W: Write> {
+    format: Format,
+    sink: Sink<W>,
+    records: usize,
+}
+
+/// CSV rows go through one [`csv::Writer`], which buffers them; the other formats are written
+/// directly.
+enum Sink<W: Write> {
+    Plain(W),
+    Csv(Box<csv::Writer<W>>),
+}
+
+impl<W: Write> RecordWriter<W> {
+    /// Creates a writer for `format`, which must not be [`Format::Text`].
+    pub fn new(format: Format, writer: W) -> Self {
+        assert_ne!(format, Format::Text, "text output is not record-based");
+        let sink = match format {
+            Format::Csv => Sink::Csv(Box::new(csv::Writer::from_writer(writer))),
+            _ => Sink::Plain(writer),
+        };
+        Self {
+            format,
+            sink,
+            records: 0,
+        }
+    }
+
+    pub fn write<T: Serialize>(&mut self, record: &T) -> Result<(), Box<dyn Error>> {
+        match (self.format, &mut self.sink) {
+            (Format::Json, Sink::Plain(writer)) => {
+                writer.write_all(if self.records == 0 { b"[\n" } else { b",\n" })?;
+                serde_json::to_writer(writer, record)?;
+            }
+            (Format::Ndjson, Sink::Plain(writer)) => {
+                serde_json::to_writer(&mut *writer, record)?;
+                writer.write_all(b"\n")?;
+            }
+            (Format::Csv, Sink::Csv(csv)) => {
+                let Value::Object(fields) = serde_json::to_value(record)? else {
+                    return Err("CSV records must be structs".into());
+                };
+                if self.records == 0 {
+                    csv.write_record(fields.keys())?;
+                }
+                csv.write_record(fields.values().map(csv_field))?;
+            }
+            _ => unreachable!("checked on construction"),
+        }
+        self.records += 1;
+        Ok(())
+    }
+
+    pub fn write_all<'t, T: Serialize + 't>(
+        &mut self,
+        records: impl IntoIterator<Item = &'t T>,
+    ) -> Result<(), Box<dyn Error>> {
+        for record in records {
+            self.write(record)?;
+        }
+        Ok(())
+    }
+
+    /// Completes the output and returns the underlying writer.
+    pub fn finish(self) -> Result<W, Box<dyn Error>> {
+        let mut writer = match self.sink {
+            Sink::Plain(writer) => writer,
+            Sink::Csv(csv) => csv.into_inner().map_err(|e| e.into_error())?,
+        };
+        if self.format == Format::Json {
+            writer.write_all(if self.records == 0 { b"[]\n" } else { b"\n]\n" })?;
+        }
+        writer.flush()?;
+        Ok(writer)
+    }
+}
+
+fn csv_field(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        Value::Array(items) => items.iter().map(csv_field).collect::<Vec<_>>().join(";"),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mixing::{Drugs, Substance};
+    use crate::output::{Format, RecordWriter, RouteRecord};
+
+    fn records() -> Vec<RouteRecord> {
+        vec![
+            RouteRecord {
+                drug: Drugs::OGKush,
+                criterion: None,
+                index: 117,
+                effects: vec!["Calming".to_string(), "Foggy".to_string()],
+                cost: 7,
+                length: 1,
+                ingredients: vec![Substance::MegaBean],
+            },
+            RouteRecord {
+                drug: Drugs::Meth,
+                criterion: Some("shortest"),
+                index: 0,
+                effects: vec![],
+                cost: 0,
+                length: 0,
+                ingredients: vec![],
+            },
+        ]
+    }
+
+    fn render(format: Format, records: &[RouteRecord]) -> String {
+        let mut writer = RecordWriter::new(format, Vec::new());
+        writer.write_all(records).unwrap();
+        String::from_utf8(writer.finish().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_json() {
+        let out = render(Format::Json, &records());
+        let parsed: serde_json::Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(parsed.as_array().map(Vec::len), Some(2));
+        assert_eq!(parsed[0]["drug"], "OGKush");
+        assert_eq!(parsed[0]["ingredients"][0], "MegaBean");
+        assert_eq!(parsed[1]["criterion"], "shortest");
+
+        assert_eq!(render(Format::Json, &[]), "[]\n");
+    }
+
+    #[test]
+    fn test_ndjson() {
+        let out = render(Format::Ndjson, &records());
+        let lines = out.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines[0],
+            r#"{"drug":"OGKush","criterion":null,"index":117,"effects":["Calming","Foggy"],"cost":7,"length":1,"ingredients":["MegaBean"]}"#
+        );
+    }
+
+    #[test]
+    fn test_csv() {
+        let out = render(Format::Csv, &records());
+        assert_eq!(
+            out,
+            "drug,criterion,index,effects,cost,length,ingredients\n\
+             OGKush,,117,Calming;Foggy,7,1,MegaBean\n\
+             Meth,shortest,0,,0,0,\n"
+        );
+    }
+}
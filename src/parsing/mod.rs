@@ -0,0 +1,174 @@
+//! Lenient parsing of user-supplied names, shared by effects, substances and drugs.
+//!
+//! Names are compared after [`normalize`], so `Anti-Gravity`, `anti gravity` and `AntiGravity` are
+//! all equivalent. Lists may be separated by commas or `|`. Unknown names produce a [`ParseError`]
+//! carrying the closest known names as suggestions.
+
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+
+/// Maximum number of suggestions reported for an unknown name.
+const MAX_SUGGESTIONS: usize = 3;
+
+/// Minimum similarity (from 0 to 1) for a known name to be suggested.
+const SUGGESTION_THRESHOLD: f64 = 0.8;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// What was being parsed, e.g. `effect`.
+    pub kind: &'static str,
+    /// The name that could not be matched.
+    pub input: String,
+    /// Known names similar to `input`, most similar first.
+    pub suggestions: Vec<String>,
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unknown {} '{}'", self.kind, self.input)?;
+        match self.suggestions.as_slice() {
+            [] => Ok(()),
+            [only] => write!(f, ", did you mean '{only}'?"),
+            [rest @ .., last] => {
+                let rest = rest
+                    .iter()
+                    .map(|s| format!("'{s}'"))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                write!(f, ", did you mean {rest} or '{last}'?")
+            }
+        }
+    }
+}
+
+impl Error for ParseError {}
+
+/// Lowercases `name` and strips everything but letters and digits.
+pub fn normalize(name: &str) -> String {
+    name.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// Splits a comma- or `|`-separated list into trimmed, non-empty items.
+pub fn split_list(list: &str) -> impl Iterator<Item = &str> {
+    list.split([',', '|'])
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+}
+
+/// A set of names that resolve to values of type `T`. Each value may be known under several
+/// aliases, but only its canonical name is offered as a suggestion.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NameTable<T> {
+    kind: &'static str,
+    /// `(normalized alias, canonical name, value)`
+    entries: Vec<(String, String, T)>,
+}
+
+impl<T: Copy> NameTable<T> {
+    pub fn new(kind: &'static str) -> Self {
+        Self {
+            kind,
+            entries: Vec::new(),
+        }
+    }
+
+    /// Registers `value` under `canonical` and each of `aliases`.
+    pub fn insert<'a>(
+        &mut self,
+        value: T,
+        canonical: &'a str,
+        aliases: impl IntoIterator<Item = &'a str>,
+    ) {
+        for alias in std::iter::once(canonical).chain(aliases) {
+            self.entries
+                .push((normalize(alias), canonical.to_string(), value));
+        }
+    }
+
+    /// Resolves a single name.
+    pub fn get(&self, name: &str) -> Result<T, ParseError> {
+        let key = normalize(name);
+        self.entries
+            .iter()
+            .find(|(alias, _, _)| *alias == key)
+            .map(|(_, _, value)| *value)
+            .ok_or_else(|| ParseError {
+                kind: self.kind,
+                input: name.to_string(),
+                suggestions: self.suggest(&key),
+            })
+    }
+
+    /// Resolves every name in a comma- or `|`-separated list, preserving order.
+    pub fn get_list(&self, list: &str) -> Result<Vec<T>, ParseError> {
+        split_list(list).map(|name| self.get(name)).collect()
+    }
+
+    fn suggest(&self, key: &str) -> Vec<String> {
+        let mut scored: Vec<(f64, &str)> = Vec::new();
+        for (alias, canonical, _) in &self.entries {
+            let score = strsim::jaro_winkler(key, alias);
+            if score < SUGGESTION_THRESHOLD {
+                continue;
+            }
+            match scored.iter_mut().find(|(_, c)| *c == canonical) {
+                Some(existing) => existing.0 = existing.0.max(score),
+                None => scored.push((score, canonical)),
+            }
+        }
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+        scored
+            .into_iter()
+            .take(MAX_SUGGESTIONS)
+            .map(|(_, c)| c.to_string())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parsing::{normalize, split_list, NameTable};
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("Anti-Gravity"), "antigravity");
+        assert_eq!(normalize(" anti gravity "), "antigravity");
+        assert_eq!(normalize("AntiGravity"), "antigravity");
+    }
+
+    #[test]
+    fn test_split_list() {
+        let items = split_list("Calming, Foggy | Ag,,").collect::<Vec<_>>();
+        assert_eq!(items, ["Calming", "Foggy", "Ag"]);
+    }
+
+    #[test]
+    fn test_suggestions() {
+        let mut table = NameTable::new("effect");
+        table.insert(0, "Calming", ["Ca"]);
+        table.insert(1, "Calorie-Dense", ["Cd"]);
+        table.insert(2, "Zombifying", ["Zo"]);
+
+        assert_eq!(table.get("calming"), Ok(0));
+        assert_eq!(table.get_list("cd, ZO"), Ok(vec![1, 2]));
+
+        let err = table.get("Calmnig").unwrap_err();
+        assert_eq!(err.suggestions.first().map(String::as_str), Some("Calming"));
+        assert!(err
+            .to_string()
+            .starts_with("unknown effect 'Calmnig', did you mean"));
+
+        let err = table.get("cal").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unknown effect 'cal', did you mean 'Calming' or 'Calorie-Dense'?"
+        );
+
+        let err = table.get("xyzzy").unwrap_err();
+        assert!(err.suggestions.is_empty());
+        assert_eq!(err.to_string(), "unknown effect 'xyzzy'");
+    }
+}
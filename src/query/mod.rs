@@ -0,0 +1,809 @@
+//! Queries over a routes file, shared by the CLI and the HTTP server.
+//!
+//! A routes file holds the Pareto-optimal (cost, length) labels for every effect set reachable from
+//! each drug's starting effects, as computed by [`crate::mosp`]. Meth and Cocaine share starting
+//! effects and therefore share routes.
+
+use crate::combinatorial::CombinatorialEncoder;
+use crate::container::{self, Codec, Loaded};
+use crate::effect_graph::EffectGraph;
+use crate::file_header::FileHeader;
+use crate::flat_storage::{CompactStorage, CompactVec, FlatStorage, SparseStorage, SparseVec};
+use crate::mixing::{
+    base_price, parse_drug, substance_cost, Drugs, Effects, MixtureRules, Substance, DRUGS,
+    SUBSTANCES,
+};
+use crate::mosp::{multiobjective_shortest_path, Cost, EffectIndex, Label, PathLength};
+use crate::output::{ProfitRecord, RouteRecord, SimulationRecord};
+use crate::parsing::ParseError;
+use crate::route_index::RouteIndex;
+use rayon::prelude::*;
+use savefile::SavefileError;
+use savefile_derive::Savefile;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::path::Path;
+use topset::TopSet;
+
+/// Drugs with distinct starting effects, in the order routes are stored. Meth stands in for
+/// Cocaine.
+pub const STARTING_DRUGS: [Drugs; 5] = [
+    Drugs::OGKush,
+    Drugs::SourDiesel,
+    Drugs::GreenCrack,
+    Drugs::GranddaddyPurple,
+    Drugs::Meth,
+];
+
+/// Position of the starting point `drug` shares in [`STARTING_DRUGS`].
+pub fn starting_position(drug: Drugs) -> usize {
+    let drug = match drug {
+        Drugs::Cocaine => Drugs::Meth,
+        d => d,
+    };
+    STARTING_DRUGS
+        .iter()
+        .position(|d| *d == drug)
+        .expect("every drug should have a starting point")
+}
+
+/// Routes to each effect set. Only the effect sets with routes are stored, as most are unreachable.
+pub type FlatPaths = SparseStorage<Label>;
+
+pub const SHORTEST_PATH_VERSION: u32 = 7;
+
+/// Message for tracing routes that [`check_routes`] accepted.
+pub(crate) const CHECKED_ROUTES: &str = "loaded routes should extend stored routes";
+
+type DensePaths = FlatStorage<Label>;
+type DensePrices = Vec<u16>;
+
+fn dense_paths(paths: DensePaths) -> FlatPaths {
+    paths.into()
+}
+
+fn dense_prices(prices: DensePrices) -> SparseVec<u16> {
+    prices.into()
+}
+
+#[derive(Savefile, Serialize, Deserialize)]
+pub struct FlattenedResultsFile {
+    /// What the routes were built from, empty for routes from before version 6.
+    #[savefile_versions = "6.."]
+    #[serde(default)]
+    pub header: FileHeader,
+    /// Price multiplier of each effect set with a route from some drug, in hundredths.
+    #[savefile_versions_as = "0..4:dense_prices:DensePrices"]
+    #[savefile_versions = "5.."]
+    pub price_multipliers: SparseVec<u16>,
+    #[savefile_versions_as = "0..4:dense_paths:DensePaths"]
+    #[savefile_versions = "5.."]
+    pub kush: FlatPaths,
+    #[savefile_versions_as = "0..4:dense_paths:DensePaths"]
+    #[savefile_versions = "5.."]
+    pub sour_diesel: FlatPaths,
+    #[savefile_versions_as = "0..4:dense_paths:DensePaths"]
+    #[savefile_versions = "5.."]
+    pub green_crack: FlatPaths,
+    #[savefile_versions_as = "0..4:dense_paths:DensePaths"]
+    #[savefile_versions = "5.."]
+    pub granddaddy_purple: FlatPaths,
+    #[savefile_versions_as = "0..4:dense_paths:DensePaths"]
+    #[savefile_versions = "5.."]
+    pub meth_cocaine: FlatPaths,
+}
+
+impl FlattenedResultsFile {
+    /// Computes the routes for every drug from a graph built with `rules`.
+    pub fn build(rules: &MixtureRules, graph: &EffectGraph) -> Self {
+        let paths = STARTING_DRUGS.map(|d| shortest_path(rules.drug_effects(d), graph));
+        Self::from_paths(rules, graph.encoder(), paths)
+    }
+
+    /// Combines the routes of each drug, in the order of [`FlattenedResultsFile::by_drug`], with
+    /// the price multipliers of the effect sets they reach.
+    pub fn from_paths(
+        rules: &MixtureRules,
+        encoder: &CombinatorialEncoder,
+        paths: [FlatPaths; 5],
+    ) -> Self {
+        let price_multipliers = price_multipliers(rules, encoder, &paths);
+        let [kush, sour_diesel, green_crack, granddaddy_purple, meth_cocaine] = paths;
+        Self {
+            header: FileHeader::new(rules),
+            price_multipliers,
+            kush,
+            sour_diesel,
+            green_crack,
+            granddaddy_purple,
+            meth_cocaine,
+        }
+    }
+
+    /// Routes for each distinct starting point. Meth stands in for Cocaine.
+    pub fn by_drug(&self) -> [(Drugs, &FlatPaths); 5] {
+        STARTING_DRUGS.map(|drug| (drug, self.paths(drug)))
+    }
+
+    pub fn paths(&self, drug: Drugs) -> &FlatPaths {
+        [
+            &self.kush,
+            &self.sour_diesel,
+            &self.green_crack,
+            &self.granddaddy_purple,
+            &self.meth_cocaine,
+        ][starting_position(drug)]
+    }
+
+    /// Writes the routes in a container, see [`crate::container`].
+    pub fn serialize_container(
+        self,
+        writer: &mut impl Write,
+        codec: Codec,
+    ) -> Result<(), SavefileError> {
+        let compact = CompactResultsFile {
+            header: self.header,
+            price_multipliers: self.price_multipliers.into(),
+            kush: self.kush.into(),
+            sour_diesel: self.sour_diesel.into(),
+            green_crack: self.green_crack.into(),
+            granddaddy_purple: self.granddaddy_purple.into(),
+            meth_cocaine: self.meth_cocaine.into(),
+        };
+        container::save(writer, SHORTEST_PATH_VERSION, &compact, codec)
+    }
+}
+
+/// [`FlattenedResultsFile`] as stored in a container.
+#[derive(Savefile)]
+pub struct CompactResultsFile {
+    #[savefile_versions = "6.."]
+    header: FileHeader,
+    price_multipliers: CompactVec<u16>,
+    kush: CompactStorage<Label>,
+    sour_diesel: CompactStorage<Label>,
+    green_crack: CompactStorage<Label>,
+    granddaddy_purple: CompactStorage<Label>,
+    meth_cocaine: CompactStorage<Label>,
+}
+
+impl TryFrom<CompactResultsFile> for FlattenedResultsFile {
+    type Error = SavefileError;
+
+    fn try_from(compact: CompactResultsFile) -> Result<Self, SavefileError> {
+        Ok(Self {
+            header: compact.header,
+            price_multipliers: compact.price_multipliers.try_into()?,
+            kush: compact.kush.try_into()?,
+            sour_diesel: compact.sour_diesel.try_into()?,
+            green_crack: compact.green_crack.try_into()?,
+            granddaddy_purple: compact.granddaddy_purple.try_into()?,
+            meth_cocaine: compact.meth_cocaine.try_into()?,
+        })
+    }
+}
+
+/// Reads a routes file, saved plainly or in a container.
+fn load_routes_from(reader: &mut impl Read) -> Result<FlattenedResultsFile, SavefileError> {
+    match container::load::<FlattenedResultsFile, CompactResultsFile>(
+        reader,
+        SHORTEST_PATH_VERSION,
+    )? {
+        Loaded::Plain(routes) => Ok(routes),
+        Loaded::Compact(compact) => compact.try_into(),
+    }
+}
+
+/// Loads a routes file, checking that it covers the same effect sets as `encoder`.
+pub fn load_routes(
+    path: &Path,
+    encoder: &CombinatorialEncoder,
+) -> Result<FlattenedResultsFile, Box<dyn Error>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let routes = load_routes_from(&mut reader).map_err(outdated_routes)?;
+    check_routes(&routes, encoder).map_err(|e| format!("'{path:?}' {e}"))?;
+    Ok(routes)
+}
+
+/// Reads routes from the contents of a routes file, checking that it covers the same effect sets as
+/// `encoder`.
+pub fn read_routes(
+    bytes: &[u8],
+    encoder: &CombinatorialEncoder,
+) -> Result<FlattenedResultsFile, Box<dyn Error>> {
+    let routes = load_routes_from(&mut { bytes }).map_err(outdated_routes)?;
+    check_routes(&routes, encoder).map_err(|e| format!("routes file {e}"))?;
+    Ok(routes)
+}
+
+/// Routes files from before version 4 lack the labels' predecessor positions, and those from before
+/// version 7 keep them apart from the backlink. Neither can be converted, so suggest regenerating
+/// them.
+fn outdated_routes(e: SavefileError) -> Box<dyn Error> {
+    match e {
+        SavefileError::IncompatibleSchema { .. } => {
+            format!("{e}\nThe routes file is outdated, regenerate it with `shortest-path`").into()
+        }
+        e => e.into(),
+    }
+}
+
+/// Checks that `routes` covers the effect sets of `encoder`, that every effect set with a route
+/// has a price, and that every route extends a stored route one step shorter.
+pub fn check_routes(
+    routes: &FlattenedResultsFile,
+    encoder: &CombinatorialEncoder,
+) -> Result<(), String> {
+    if routes.price_multipliers.len() != encoder.maximum_index() as usize {
+        return Err(format!(
+            "has {} effect sets, but the rules produce {}",
+            routes.price_multipliers.len(),
+            encoder.maximum_index()
+        ));
+    }
+    for (drug, paths) in routes.by_drug() {
+        if paths.len() != routes.price_multipliers.len() {
+            return Err(format!(
+                "has {} effect sets for {drug}, but {} overall",
+                paths.len(),
+                routes.price_multipliers.len()
+            ));
+        }
+        if !paths
+            .present()
+            .is_subset(routes.price_multipliers.present())
+        {
+            return Err(format!(
+                "has routes for {drug} to effect sets without a price"
+            ));
+        }
+        for (idx, labels) in paths.iter() {
+            for label in labels {
+                let Some((node, position, _)) = label.backlink() else {
+                    continue;
+                };
+                let extended = paths.get(node as usize).get(position);
+                if extended.is_none_or(|l| label.length.checked_sub(1) != Some(l.length)) {
+                    return Err(format!(
+                        "has a route for {drug} to effect set {idx} extending a missing route"
+                    ));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn shortest_path(starting: Effects, graph: &EffectGraph) -> FlatPaths {
+    let costs = SUBSTANCES
+        .iter()
+        .copied()
+        .map(|s| substance_cost(s) as Cost)
+        .collect::<Vec<_>>();
+
+    multiobjective_shortest_path(graph, &costs, starting).into()
+}
+
+/// Price multiplier of every effect set with a route in `paths`, in hundredths.
+pub fn price_multipliers(
+    rules: &MixtureRules,
+    encoder: &CombinatorialEncoder,
+    paths: &[FlatPaths],
+) -> SparseVec<u16> {
+    SparseVec::from_sorted(
+        encoder.maximum_index(),
+        encoder
+            .combinations()
+            .filter(|(idx, _)| {
+                paths
+                    .iter()
+                    .any(|p| p.present().rank(*idx as usize).is_some())
+            })
+            .map(|(idx, bitset)| {
+                let multiplier = rules.price_multiplier(Effects::from(bitset));
+                (idx, (multiplier * 100.).round() as u16)
+            }),
+    )
+}
+
+/// The substances along the route ending in `start`, in the order they are mixed in. `None` if
+/// the route extends a label missing from `paths`, which [`check_routes`] rules out.
+pub fn trace_path(start: Label, paths: &FlatPaths) -> Option<Vec<Substance>> {
+    let mut path = Vec::with_capacity(start.length as usize);
+    let mut l = start;
+    while let Some((next, position, s)) = l.backlink() {
+        path.push(s);
+        l = *paths.get(next as usize).get(position)?;
+    }
+    // Since we started at the target and worked back to the root node, flip the order.
+    path.reverse();
+    Some(path)
+}
+
+pub fn search_inexact(
+    target_effects: Effects,
+    encoder: &CombinatorialEncoder,
+    labels: &FlatPaths,
+) -> Option<((usize, Label), (usize, Label))> {
+    // only effect sets containing the target can match
+    best_routes(
+        encoder.supersets(target_effects.bits()).map(|(idx, _)| idx),
+        labels,
+    )
+}
+
+/// The cheapest and the shortest route to any of `candidates`, preferring the first on ties.
+pub fn best_routes(
+    candidates: impl IntoIterator<Item = EffectIndex>,
+    labels: &FlatPaths,
+) -> Option<((usize, Label), (usize, Label))> {
+    let mut lowest_cost = None;
+    let mut shortest = None;
+    for idx in candidates {
+        let idx = idx as usize;
+        for path in labels.get(idx) {
+            if path.cost < lowest_cost.get_or_insert((idx, *path)).1.cost {
+                lowest_cost = Some((idx, *path));
+            }
+            if path.length < shortest.get_or_insert((idx, *path)).1.length {
+                shortest = Some((idx, *path));
+            }
+        }
+    }
+    lowest_cost.map(|(idx, path)| ((idx, path), shortest.unwrap()))
+}
+
+/// Display names of `effects`, for machine-readable output.
+pub fn effect_names(rules: &MixtureRules, effects: Effects) -> Vec<String> {
+    rules
+        .effects()
+        .names(effects)
+        .into_iter()
+        .map(String::from)
+        .collect()
+}
+
+pub fn route_record(
+    rules: &MixtureRules,
+    encoder: &CombinatorialEncoder,
+    drug: Drugs,
+    criterion: Option<&'static str>,
+    index: EffectIndex,
+    label: Label,
+    paths: &FlatPaths,
+) -> RouteRecord {
+    RouteRecord {
+        drug,
+        criterion,
+        index,
+        effects: effect_names(rules, Effects::from(encoder.decode(index))),
+        cost: label.cost,
+        length: label.length,
+        ingredients: trace_path(label, paths).expect(CHECKED_ROUTES),
+    }
+}
+
+/// Every Pareto-optimal route to the effect set `index`, for each drug.
+pub fn lookup_records(
+    rules: &MixtureRules,
+    encoder: &CombinatorialEncoder,
+    routes: &FlattenedResultsFile,
+    index: EffectIndex,
+) -> Vec<RouteRecord> {
+    routes
+        .by_drug()
+        .into_iter()
+        .flat_map(|(drug, paths)| {
+            paths
+                .get(index as usize)
+                .iter()
+                .map(move |label| route_record(rules, encoder, drug, None, index, *label, paths))
+        })
+        .collect()
+}
+
+/// The cheapest and the shortest route to any superset of `target`, for each drug that can reach
+/// one.
+pub fn search_records(
+    rules: &MixtureRules,
+    encoder: &CombinatorialEncoder,
+    routes: &FlattenedResultsFile,
+    target: Effects,
+) -> Vec<RouteRecord> {
+    filtered_search_records(rules, encoder, routes, None, target, Effects::empty())
+}
+
+/// Like [`search_records`], but skipping effect sets with any of the effects in `exclude`. The
+/// matching effect sets are taken from `index` if given, instead of enumerating every superset.
+pub fn filtered_search_records(
+    rules: &MixtureRules,
+    encoder: &CombinatorialEncoder,
+    routes: &FlattenedResultsFile,
+    index: Option<&RouteIndex>,
+    target: Effects,
+    exclude: Effects,
+) -> Vec<RouteRecord> {
+    let search = |drug: Drugs, paths: &FlatPaths| match index {
+        Some(index) => best_routes(index.matching(drug, target, exclude).iter(), paths),
+        None => best_routes(
+            encoder
+                .supersets(target.bits())
+                .filter(|(_, bitset)| bitset & exclude.bits() == 0)
+                .map(|(idx, _)| idx),
+            paths,
+        ),
+    };
+    routes
+        .by_drug()
+        .par_iter()
+        .filter_map(|(d, fp)| search(*d, fp).map(|p| (*d, p, *fp)))
+        .collect::<Vec<_>>()
+        .into_iter()
+        .flat_map(|(drug, (lowest_cost, shortest), paths)| {
+            [("lowest_cost", lowest_cost), ("shortest", shortest)].map(
+                |(criterion, (idx, label))| {
+                    route_record(
+                        rules,
+                        encoder,
+                        drug,
+                        Some(criterion),
+                        idx as u32,
+                        label,
+                        paths,
+                    )
+                },
+            )
+        })
+        .collect()
+}
+
+/// Upper bound on [`ProfitQuery::max_results`] accepted from the server and REPL.
+pub const MAX_PROFIT_RESULTS: usize = 1000;
+
+/// Parameters for [`profit_records`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ProfitQuery {
+    /// Longest route to consider.
+    pub max_mixins: PathLength,
+    /// Fraction added to each drug's base price.
+    pub markup: f64,
+    /// Cap on the sale price.
+    pub max_price: Cost,
+    /// Number of effect sets to report per drug.
+    pub max_results: usize,
+}
+
+impl Default for ProfitQuery {
+    fn default() -> Self {
+        Self {
+            max_mixins: PathLength::MAX,
+            markup: 0.,
+            max_price: 999,
+            max_results: 10,
+        }
+    }
+}
+
+/// The most profitable effect sets reachable from each drug, most profitable first.
+///
+/// With `drug` set, only that drug's routes are scanned.
+pub fn profit_records(
+    rules: &MixtureRules,
+    encoder: &CombinatorialEncoder,
+    routes: &FlattenedResultsFile,
+    query: &ProfitQuery,
+    drug: Option<Drugs>,
+) -> Vec<(Drugs, Vec<ProfitRecord>)> {
+    let drugs = match &drug {
+        Some(drug) => std::slice::from_ref(drug),
+        None => DRUGS,
+    };
+    drugs
+        .par_iter()
+        .copied()
+        .map(|d| {
+            let fp = routes.paths(d);
+            let mut top = TopSet::new(query.max_results, PartialOrd::gt);
+            let base_price = base_price(d) * (1. + query.markup);
+            for (idx, labels) in fp.iter() {
+                let best = labels
+                    .iter()
+                    .filter(|label| label.length <= query.max_mixins)
+                    .min_by_key(|l| l.cost);
+                if let Some(best) = best {
+                    let mult = *routes
+                        .price_multipliers
+                        .get(idx)
+                        .expect("effect sets with routes should have a price")
+                        as f64
+                        / 100.;
+                    let sell_price =
+                        query.max_price.min((base_price * mult).round() as Cost) as i32;
+                    let profit = sell_price - best.cost as i32;
+                    top.insert((profit, sell_price, idx, best));
+                }
+            }
+
+            let mut results = top.into_sorted_vec();
+            results.reverse();
+            let records = results
+                .into_iter()
+                .map(|(profit, sell_price, idx, label)| ProfitRecord {
+                    drug: d,
+                    index: idx as u32,
+                    effects: effect_names(rules, Effects::from(encoder.decode(idx as u32))),
+                    sell_price,
+                    cost: label.cost,
+                    profit,
+                    length: label.length,
+                    ingredients: trace_path(*label, fp).expect(CHECKED_ROUTES),
+                })
+                .collect::<Vec<_>>();
+            (d, records)
+        })
+        .collect()
+}
+
+/// Parses a recipe written as `drug: substances`, e.g. `OG Kush: Cuke, Gasoline`.
+pub fn parse_recipe(
+    rules: &MixtureRules,
+    recipe: &str,
+) -> Result<(Drugs, Vec<Substance>), ParseError> {
+    let (drug, substances) = recipe.split_once(':').unwrap_or((recipe, ""));
+    Ok((parse_drug(drug)?, rules.parse_substances(substances)?))
+}
+
+/// Mixes `substances` into `drug` one at a time, recording the state after each step. The first
+/// record is the unmixed drug. When a graph is supplied its transition table is used instead of
+/// applying the rules directly.
+pub fn simulate(
+    rules: &MixtureRules,
+    graph: Option<&EffectGraph>,
+    drug: Drugs,
+    substances: &[Substance],
+) -> Vec<SimulationRecord> {
+    let record =
+        |step: usize, substance: Option<Substance>, effects: Effects, cost: i64| SimulationRecord {
+            drug,
+            step,
+            substance,
+            effects: effect_names(rules, effects),
+            sell_price: (base_price(drug) * rules.price_multiplier(effects)).round() as i32,
+            cost: cost as Cost,
+        };
+
+    let mut effects = rules.drug_effects(drug);
+    let mut cost = 0;
+    let mut records = vec![record(0, None, effects, cost)];
+    for (step, substance) in substances.iter().copied().enumerate() {
+        effects = match graph {
+            Some(g) => g
+                .decode(g.successors(g.encode(effects))[substance as usize])
+                .expect("successors should be valid indices"),
+            None => rules.apply(substance, effects),
+        };
+        cost += substance_cost(substance);
+        records.push(record(step + 1, Some(substance), effects, cost));
+    }
+    records
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::effect_graph::EffectGraph;
+    use crate::flat_storage::{FlatStorage, SparseVec};
+    use crate::mixing::MixtureRules;
+    use crate::mixing::{substance_cost, Drugs, Effects, Substance};
+    use crate::mosp::Label;
+    use crate::query::{
+        filtered_search_records, lookup_records, profit_records, read_routes, search_records,
+        simulate, trace_path, FlatPaths, FlattenedResultsFile, ProfitQuery, SHORTEST_PATH_VERSION,
+    };
+    use crate::route_index::RouteIndex;
+    use savefile_derive::Savefile;
+    use std::error::Error;
+
+    /// The full rules, capped at `max_effects` effects so that graphs stay small enough for tests.
+    pub(crate) fn small_rules(max_effects: u8) -> Result<MixtureRules, Box<dyn Error>> {
+        let mut raw: serde_json::Value =
+            serde_json::from_str(include_str!("../../sch1-mix-rules.json"))?;
+        raw["max_effects"] = max_effects.into();
+        MixtureRules::from_value(raw)
+    }
+
+    /// [`small_rules`] with the graph they produce and the routes through it.
+    pub(crate) fn small_routes(
+        max_effects: u8,
+    ) -> Result<(MixtureRules, EffectGraph, FlattenedResultsFile), Box<dyn Error>> {
+        let rules = small_rules(max_effects)?;
+        let graph = EffectGraph::new(&rules, rules.encoder());
+        let routes = FlattenedResultsFile::build(&rules, &graph);
+        Ok((rules, graph, routes))
+    }
+
+    #[test]
+    fn test_queries() -> Result<(), Box<dyn Error>> {
+        let (rules, graph, routes) = small_routes(2)?;
+        let encoder = rules.encoder();
+
+        let steps = simulate(&rules, None, Drugs::OGKush, &[Substance::Cuke]);
+        assert_eq!(steps.len(), 2);
+        assert_eq!(steps[0].effects, ["Calming"]);
+        assert_eq!(steps[1].cost, 2);
+        assert_eq!(
+            steps,
+            simulate(&rules, Some(&graph), Drugs::OGKush, &[Substance::Cuke])
+        );
+
+        // Every route found by a lookup must replay to the effect set it was found for.
+        let index = encoder.encode(rules.effects().parse(&steps[1].effects.join(","))?.bits());
+        let records = lookup_records(&rules, &encoder, &routes, index);
+        assert!(records
+            .iter()
+            .any(|r| r.drug == Drugs::OGKush && r.ingredients == [Substance::Cuke]));
+        for record in &records {
+            let replay = simulate(&rules, None, record.drug, &record.ingredients);
+            assert_eq!(replay.last().unwrap().effects, record.effects);
+        }
+
+        // Every label traces back to a route with its cost and length that ends at its node.
+        for (drug, paths) in routes.by_drug() {
+            for idx in 0..encoder.maximum_index() {
+                for label in paths.get(idx as usize) {
+                    let path = trace_path(*label, paths).expect("routes should be complete");
+                    assert_eq!(path.len(), label.length as usize);
+                    let cost = path.iter().map(|s| substance_cost(*s)).sum::<i64>();
+                    assert_eq!(cost, label.cost as i64);
+                    assert_eq!(encoder.encode(rules.mix(drug, &path).bits()), idx);
+                }
+            }
+        }
+
+        let energizing = rules.effects().parse("Energizing")?;
+        for record in search_records(&rules, &encoder, &routes, energizing) {
+            assert!(record.effects.iter().any(|e| e == "Energizing"));
+        }
+
+        // Searching with an index finds the same routes as enumerating the supersets.
+        let calming = rules.effects().parse("Calming")?;
+        let route_index = RouteIndex::build(&encoder, &routes);
+        let scanned = filtered_search_records(&rules, &encoder, &routes, None, energizing, calming);
+        assert!(!scanned.is_empty());
+        for record in &scanned {
+            assert!(!record.effects.iter().any(|e| e == "Calming"));
+        }
+        let indexed = filtered_search_records(
+            &rules,
+            &encoder,
+            &routes,
+            Some(&route_index),
+            energizing,
+            calming,
+        );
+        assert_eq!(indexed, scanned);
+        assert_eq!(
+            filtered_search_records(
+                &rules,
+                &encoder,
+                &routes,
+                Some(&route_index),
+                energizing,
+                Effects::empty()
+            ),
+            search_records(&rules, &encoder, &routes, energizing)
+        );
+
+        let query = ProfitQuery {
+            max_results: 3,
+            ..ProfitQuery::default()
+        };
+        for (_, records) in profit_records(&rules, &encoder, &routes, &query, None) {
+            assert!(records.len() <= 3);
+            assert!(records.windows(2).all(|w| w[0].profit >= w[1].profit));
+        }
+
+        Ok(())
+    }
+
+    /// The routes file layout of version 4, before unreachable effect sets were dropped.
+    #[derive(Savefile)]
+    struct DenseResultsFile {
+        price_multipliers: Vec<u16>,
+        kush: FlatStorage<Label>,
+        sour_diesel: FlatStorage<Label>,
+        green_crack: FlatStorage<Label>,
+        granddaddy_purple: FlatStorage<Label>,
+        meth_cocaine: FlatStorage<Label>,
+    }
+
+    #[test]
+    fn test_reads_dense_routes() -> Result<(), Box<dyn Error>> {
+        let (rules, _, routes) = small_routes(2)?;
+        let encoder = rules.encoder();
+
+        let nodes = encoder.maximum_index() as usize;
+        let dense = |paths: &FlatPaths| {
+            FlatStorage::from(
+                (0..nodes)
+                    .map(|i| paths.get(i).to_vec())
+                    .collect::<Vec<_>>(),
+            )
+        };
+        let old = DenseResultsFile {
+            price_multipliers: encoder
+                .combinations()
+                .map(|(_, bitset)| (rules.price_multiplier(bitset.into()) * 100.).round() as u16)
+                .collect(),
+            kush: dense(&routes.kush),
+            sour_diesel: dense(&routes.sour_diesel),
+            green_crack: dense(&routes.green_crack),
+            granddaddy_purple: dense(&routes.granddaddy_purple),
+            meth_cocaine: dense(&routes.meth_cocaine),
+        };
+        let bytes = savefile::save_to_mem(4, &old)?;
+        let converted = read_routes(&bytes, &encoder)?;
+
+        let bytes = savefile::save_to_mem(SHORTEST_PATH_VERSION, &routes)?;
+        let sparse = read_routes(&bytes, &encoder)?;
+        assert!(sparse.kush.present().count() < nodes);
+        // Headers were added after the dense format.
+        assert!(!converted.header.is_present());
+        assert_eq!(sparse.header, routes.header);
+
+        for ((_, a), (_, b)) in converted.by_drug().into_iter().zip(sparse.by_drug()) {
+            for i in 0..nodes {
+                assert_eq!(a.get(i), b.get(i));
+                if !b.get(i).is_empty() {
+                    assert_eq!(
+                        converted.price_multipliers.get(i),
+                        sparse.price_multipliers.get(i)
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_rejects_inconsistent_routes() -> Result<(), Box<dyn Error>> {
+        let (rules, graph, mut routes) = small_routes(2)?;
+        let encoder = rules.encoder();
+        let nodes = encoder.maximum_index();
+        let read = |routes: &FlattenedResultsFile| {
+            let bytes = savefile::save_to_mem(SHORTEST_PATH_VERSION, routes)?;
+            read_routes(&bytes, &encoder)
+        };
+
+        // An effect set with a route but without a price.
+        let (unpriced, _) = routes.kush.iter().next().ok_or("kush should have routes")?;
+        let prices = (0..nodes)
+            .filter(|i| *i as usize != unpriced)
+            .filter_map(|i| Some((i, *routes.price_multipliers.get(i as usize)?)))
+            .collect::<Vec<_>>();
+        routes.price_multipliers = SparseVec::from_sorted(nodes, prices);
+        let err = read(&routes).err().ok_or("should be rejected")?;
+        assert!(err.to_string().contains("without a price"), "{err}");
+
+        // Routes for fewer effect sets than there are prices.
+        let mut routes = FlattenedResultsFile::build(&rules, &graph);
+        routes.meth_cocaine = FlatPaths::from(vec![Vec::new(); nodes as usize - 1]);
+        let err = read(&routes).err().ok_or("should be rejected")?;
+        assert!(err.to_string().contains("for Meth"), "{err}");
+
+        // Routes extending the starting label, after it has been dropped.
+        let mut routes = FlattenedResultsFile::build(&rules, &graph);
+        let rows = (0..nodes as usize)
+            .map(|i| routes.kush.get(i).to_vec())
+            .map(|row| match row.iter().any(|l| l.backlink().is_none()) {
+                true => Vec::new(),
+                false => row,
+            })
+            .collect::<Vec<_>>();
+        routes.kush = FlatPaths::from(rows);
+        let err = read(&routes).err().ok_or("should be rejected")?;
+        assert!(err.to_string().contains("missing route"), "{err}");
+        Ok(())
+    }
+}
@@ -0,0 +1,292 @@
+//! Which effect sets can be mixed at all, starting from each product.
+//!
+//! Most encoded effect sets cannot be produced by any sequence of substances. The reachable ones
+//! are found by a search over an [`EffectGraph`] from each product's starting effects and kept as
+//! [`Bitmap`]s, which can be saved with `reachability --output-file` and loaded again with
+//! `reachability --reachable`, so that downstream tables only need to cover the reachable effect
+//! sets.
+
+use crate::combinatorial::CombinatorialEncoder;
+use crate::effect_graph::EffectGraph;
+use crate::file_header::FileHeader;
+use crate::flat_storage::Bitmap;
+use crate::mixing::{Drugs, Effects, MixtureRules};
+use crate::output::ReachabilityRecord;
+use crate::query::{effect_names, starting_position, STARTING_DRUGS};
+use rayon::prelude::*;
+use savefile::SavefileError;
+use savefile_derive::Savefile;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::io::Write;
+use std::path::Path;
+
+pub const REACHABILITY_VERSION: u32 = 2;
+
+/// Every effect set reachable from `start`, including `start` itself.
+pub fn reachable_from(graph: &EffectGraph, start: Effects) -> Bitmap {
+    let mut reachable = Bitmap::new(graph.num_nodes() as u32);
+    let start = graph.encode(start);
+    reachable.insert(start);
+    let mut stack = vec![start];
+    while let Some(node) = stack.pop() {
+        for next in graph.successors(node).iter().copied() {
+            if !reachable.contains(next) {
+                reachable.insert(next);
+                stack.push(next);
+            }
+        }
+    }
+    reachable
+}
+
+/// The effect sets reachable from each product.
+#[derive(Savefile, Serialize, Deserialize)]
+pub struct ReachableSets {
+    /// What the sets were found from. Files from before version 2 have an empty header.
+    #[savefile_versions = "2.."]
+    header: FileHeader,
+    /// Number of effect sets covered.
+    nodes: u32,
+    /// Reachable effect sets, in the order of [`STARTING_DRUGS`].
+    by_drug: Vec<Bitmap>,
+}
+
+impl ReachableSets {
+    pub fn build(rules: &MixtureRules, graph: &EffectGraph) -> Self {
+        Self {
+            header: FileHeader::new(rules),
+            nodes: graph.num_nodes() as u32,
+            by_drug: STARTING_DRUGS
+                .par_iter()
+                .map(|d| reachable_from(graph, rules.drug_effects(*d)))
+                .collect(),
+        }
+    }
+
+    /// Loads reachable sets from `path` and checks that they cover the same effect sets as
+    /// `encoder`.
+    pub fn load<P: AsRef<Path>>(
+        path: P,
+        encoder: &CombinatorialEncoder,
+    ) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref();
+        let sets: Self = savefile::load_file(path, REACHABILITY_VERSION)?;
+        if sets.nodes != encoder.maximum_index() {
+            return Err(format!(
+                "'{path:?}' has {} effect sets, but the rules produce {}",
+                sets.nodes,
+                encoder.maximum_index()
+            )
+            .into());
+        }
+        Ok(sets)
+    }
+
+    pub fn serialize(&self, writer: &mut impl Write) -> Result<(), SavefileError> {
+        savefile::save(writer, REACHABILITY_VERSION, self)
+    }
+
+    pub fn header(&self) -> &FileHeader {
+        &self.header
+    }
+
+    /// Reachable effect sets for each distinct starting point. Meth stands in for Cocaine.
+    pub fn by_drug(&self) -> impl Iterator<Item = (Drugs, &Bitmap)> {
+        STARTING_DRUGS.iter().copied().zip(&self.by_drug)
+    }
+
+    pub fn get(&self, drug: Drugs) -> &Bitmap {
+        &self.by_drug[starting_position(drug)]
+    }
+
+    /// Effect sets reachable from at least one product.
+    pub fn any(&self) -> Bitmap {
+        let mut any = Bitmap::new(self.nodes);
+        for reachable in &self.by_drug {
+            any.union(reachable);
+        }
+        any
+    }
+}
+
+/// Number of effect sets in `reachable` with each number of effects, from none up to the cap.
+pub fn counts_by_size(encoder: &CombinatorialEncoder, reachable: &Bitmap) -> Vec<usize> {
+    (0..=encoder.max_elements())
+        .map(|k| reachable.count_range(encoder.size_range(k)))
+        .collect()
+}
+
+/// Effects that appear together in some effect set, see [`co_occurrence`].
+pub struct CoOccurrence {
+    /// For each effect, by bit, the effects it appears with, including itself if it appears at all.
+    together: Vec<u64>,
+}
+
+impl CoOccurrence {
+    /// Effects that are in no effect set.
+    pub fn never_present(&self) -> Effects {
+        self.together
+            .iter()
+            .enumerate()
+            .filter(|(bit, together)| *together & 1 << bit == 0)
+            .fold(Effects::empty(), |acc, (bit, _)| {
+                acc | Effects::from(1 << bit)
+            })
+    }
+
+    /// Pairs of effects that each appear in some effect set, but never in the same one.
+    pub fn exclusive_pairs(&self) -> Vec<(Effects, Effects)> {
+        let present = !self.never_present().bits();
+        let mut pairs = Vec::new();
+        for (a, together) in self.together.iter().enumerate() {
+            if present & 1 << a == 0 {
+                continue;
+            }
+            for b in a + 1..self.together.len() {
+                if present & 1 << b != 0 && together & 1 << b == 0 {
+                    pairs.push((Effects::from(1 << a), Effects::from(1 << b)));
+                }
+            }
+        }
+        pairs
+    }
+}
+
+/// Which effects appear together in the effect sets of `reachable`.
+pub fn co_occurrence(encoder: &CombinatorialEncoder, reachable: &Bitmap) -> CoOccurrence {
+    let mut together = vec![0u64; encoder.num_elements() as usize];
+    for (index, bitset) in encoder.combinations() {
+        if !reachable.contains(index) {
+            continue;
+        }
+        let mut remaining = bitset;
+        while remaining != 0 {
+            together[remaining.trailing_zeros() as usize] |= bitset;
+            remaining &= remaining - 1;
+        }
+    }
+    CoOccurrence { together }
+}
+
+/// Counts by size for each product and for any product, followed by the effects that never occur
+/// and the pairs that never occur together in effect sets reachable from any product.
+pub fn reachability_records(
+    rules: &MixtureRules,
+    encoder: &CombinatorialEncoder,
+    sets: &ReachableSets,
+) -> Vec<ReachabilityRecord> {
+    let empty = |section| ReachabilityRecord {
+        section,
+        drug: None,
+        size: None,
+        effect_sets: None,
+        reachable: None,
+        effects: Vec::new(),
+    };
+    let any = sets.any();
+    let mut records = Vec::new();
+    let products = sets.by_drug().map(|(d, r)| (Some(d), r));
+    for (drug, reachable) in products.chain([(None, &any)]) {
+        let counts = counts_by_size(encoder, reachable);
+        records.extend(
+            counts
+                .into_iter()
+                .enumerate()
+                .map(|(k, count)| ReachabilityRecord {
+                    drug,
+                    size: Some(k as u8),
+                    effect_sets: Some(encoder.size_range(k as u8).len() as u64),
+                    reachable: Some(count as u64),
+                    ..empty("size")
+                }),
+        );
+    }
+
+    let co_occurrence = co_occurrence(encoder, &any);
+    records.extend(
+        effect_names(rules, co_occurrence.never_present())
+            .into_iter()
+            .map(|name| ReachabilityRecord {
+                effects: vec![name],
+                ..empty("never")
+            }),
+    );
+    records.extend(
+        co_occurrence
+            .exclusive_pairs()
+            .into_iter()
+            .map(|(a, b)| ReachabilityRecord {
+                effects: effect_names(rules, a | b),
+                ..empty("exclusive")
+            }),
+    );
+    records
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mixing::{Drugs, Effects};
+    use crate::query::tests::{small_routes, small_rules};
+    use crate::reachability::{co_occurrence, counts_by_size, ReachableSets};
+    use std::error::Error;
+
+    #[test]
+    fn test_reachability() -> Result<(), Box<dyn Error>> {
+        let (rules, graph, routes) = small_routes(2)?;
+        let encoder = rules.encoder();
+        let sets = ReachableSets::build(&rules, &graph);
+
+        let path = std::env::temp_dir().join(format!("reachable-{}.bin", std::process::id()));
+        sets.serialize(&mut std::fs::File::create(&path)?)?;
+        let loaded = ReachableSets::load(&path, &encoder);
+        assert!(ReachableSets::load(&path, &small_rules(3)?.encoder()).is_err());
+        std::fs::remove_file(&path)?;
+        let sets = loaded?;
+        assert!(sets.header().mismatches(&rules).is_empty());
+
+        // Exactly the effect sets with a route are reachable.
+        for (drug, paths) in routes.by_drug() {
+            let reachable = sets.get(drug);
+            for index in 0..encoder.maximum_index() {
+                assert_eq!(
+                    reachable.contains(index),
+                    !paths.get(index as usize).is_empty(),
+                    "{drug} {index}"
+                );
+            }
+        }
+        assert_eq!(
+            sets.get(Drugs::Cocaine).count(),
+            sets.get(Drugs::Meth).count()
+        );
+
+        let any = sets.any();
+        let counts = counts_by_size(&encoder, &any);
+        assert_eq!(counts.len(), 3);
+        assert_eq!(counts.iter().sum::<usize>(), any.count());
+        // Meth starts without effects.
+        assert_eq!(counts[0], 1);
+        assert!(counts[1] > 0);
+
+        let co_occurrence = co_occurrence(&encoder, &any);
+        let never = co_occurrence.never_present();
+        let reachable = encoder
+            .combinations()
+            .filter(|(index, _)| any.contains(*index))
+            .map(|(_, bitset)| Effects::from(bitset))
+            .collect::<Vec<_>>();
+        for bit in 0..encoder.num_elements() {
+            let effect = Effects::from(1 << bit);
+            assert_eq!(
+                never.contains(effect),
+                !reachable.iter().any(|e| e.contains(effect))
+            );
+        }
+        for (a, b) in co_occurrence.exclusive_pairs() {
+            assert!(!never.intersects(a | b));
+            assert!(!reachable.iter().any(|e| e.contains(a | b)));
+        }
+        Ok(())
+    }
+}
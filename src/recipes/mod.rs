@@ -0,0 +1,171 @@
+//! Recipe books: named, shared recipes that can be replayed against the current rules.
+//!
+//! A recipe book is a JSON file holding a list of recipes, each a drug and the substances mixed
+//! into it in order. Names are parsed as on the command line.
+//!
+//! ```json
+//! {
+//!   "recipes": [
+//!     { "name": "Sweet Kush", "drug": "OG Kush", "substances": ["Cuke", "Banana"] }
+//!   ]
+//! }
+//! ```
+//!
+//! Verifying a recipe replays it with [`MixtureRules::apply`] and, given a routes file, checks
+//! whether a route to the same effects is at least as cheap and as short, and strictly better in
+//! one of the two.
+
+use crate::combinatorial::CombinatorialEncoder;
+use crate::mixing::{parse_drug, Drugs, MixtureRules, Substance};
+use crate::mosp::{Cost, Label, PathLength};
+use crate::output::RecipeRecord;
+use crate::query::{simulate, trace_path, FlattenedResultsFile, CHECKED_ROUTES};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+/// A recipe book as written on disk.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RecipeBook {
+    pub recipes: Vec<RecipeEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecipeEntry {
+    pub name: String,
+    pub drug: String,
+    pub substances: Vec<String>,
+}
+
+/// A recipe with its names resolved.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Recipe {
+    pub name: String,
+    pub drug: Drugs,
+    pub substances: Vec<Substance>,
+}
+
+impl RecipeBook {
+    pub fn from_reader<R: Read>(reader: R) -> Result<Self, Box<dyn Error>> {
+        Ok(serde_json::from_reader(reader)?)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn Error>> {
+        Self::from_reader(BufReader::new(File::open(path)?))
+    }
+
+    /// Resolves the drug and substance names of every recipe, failing on the first unknown name.
+    pub fn resolve(&self, rules: &MixtureRules) -> Result<Vec<Recipe>, Box<dyn Error>> {
+        self.recipes
+            .iter()
+            .map(|entry| {
+                let in_recipe = |e: &dyn Error| format!("recipe '{}': {e}", entry.name);
+                Ok(Recipe {
+                    name: entry.name.clone(),
+                    drug: parse_drug(&entry.drug).map_err(|e| in_recipe(&e))?,
+                    substances: entry
+                        .substances
+                        .iter()
+                        .map(|s| rules.parse_substance(s).map_err(|e| in_recipe(&e)))
+                        .collect::<Result<_, _>>()?,
+                })
+            })
+            .collect()
+    }
+}
+
+/// The cheapest of the routes in `labels` that dominate a recipe of `cost` and `length`, preferring
+/// shorter routes on ties.
+fn dominating_route(labels: &[Label], cost: Cost, length: PathLength) -> Option<Label> {
+    labels
+        .iter()
+        .filter(|l| l.cost <= cost && l.length <= length && (l.cost < cost || l.length < length))
+        .min_by_key(|l| (l.cost, l.length))
+        .copied()
+}
+
+/// Replays `recipe`, and compares it against `routes` if given.
+pub fn verify_recipe(
+    rules: &MixtureRules,
+    encoder: &CombinatorialEncoder,
+    routes: Option<&FlattenedResultsFile>,
+    recipe: &Recipe,
+) -> RecipeRecord {
+    let last = simulate(rules, None, recipe.drug, &recipe.substances)
+        .pop()
+        .expect("should include the drug");
+    let length = recipe.substances.len();
+    let better = routes.and_then(|routes| {
+        let paths = routes.paths(recipe.drug);
+        let length = length.min(PathLength::MAX as usize) as PathLength;
+        let labels =
+            paths.get(encoder.encode(rules.mix(recipe.drug, &recipe.substances).bits()) as usize);
+        dominating_route(labels, last.cost, length)
+            .map(|label| (label, trace_path(label, paths).expect(CHECKED_ROUTES)))
+    });
+
+    RecipeRecord {
+        name: recipe.name.clone(),
+        drug: recipe.drug,
+        ingredients: recipe.substances.clone(),
+        effects: last.effects,
+        sell_price: last.sell_price,
+        cost: last.cost,
+        length,
+        dominated: routes.map(|_| better.is_some()),
+        better_cost: better.as_ref().map(|(label, _)| label.cost),
+        better_length: better.as_ref().map(|(label, _)| label.length),
+        better_ingredients: better.map(|(_, path)| path).unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mixing::{Drugs, Substance};
+    use crate::query::tests::small_routes;
+    use crate::recipes::{verify_recipe, RecipeBook};
+    use std::error::Error;
+
+    #[test]
+    fn test_verify() -> Result<(), Box<dyn Error>> {
+        let (rules, _, routes) = small_routes(2)?;
+        let encoder = rules.encoder();
+
+        let book = RecipeBook::from_reader(
+            r#"{"recipes": [
+                {"name": "Plain", "drug": "meth", "substances": ["cuke"]},
+                {"name": "Detour", "drug": "Meth", "substances": ["Cuke", "Cuke", "Cuke"]}
+            ]}"#
+            .as_bytes(),
+        )?;
+        let recipes = book.resolve(&rules)?;
+        assert_eq!(recipes[1].drug, Drugs::Meth);
+        assert_eq!(recipes[1].substances, [Substance::Cuke; 3]);
+
+        let plain = verify_recipe(&rules, &encoder, Some(&routes), &recipes[0]);
+        assert_eq!(plain.effects, ["Energizing"]);
+        assert_eq!((plain.cost, plain.length), (2, 1));
+        assert_eq!(plain.dominated, Some(false));
+
+        // Cuke is idempotent here, so the extra steps only add cost.
+        let detour = verify_recipe(&rules, &encoder, Some(&routes), &recipes[1]);
+        assert_eq!(detour.effects, plain.effects);
+        assert_eq!(detour.dominated, Some(true));
+        assert_eq!(detour.better_cost, Some(2));
+        assert_eq!(detour.better_ingredients, [Substance::Cuke]);
+
+        let unchecked = verify_recipe(&rules, &encoder, None, &recipes[1]);
+        assert_eq!(unchecked.dominated, None);
+        assert!(unchecked.better_ingredients.is_empty());
+
+        let typo = RecipeBook::from_reader(
+            r#"{"recipes": [{"name": "Typo", "drug": "Meth", "substances": ["Cuek", "Bananna"]}]}"#
+                .as_bytes(),
+        )?;
+        let err = typo.resolve(&rules).unwrap_err().to_string();
+        assert!(err.starts_with("recipe 'Typo'"), "{err}");
+        Ok(())
+    }
+}
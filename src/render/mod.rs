@@ -0,0 +1,147 @@
+//! Human-readable rendering of effects and substances for terminal output.
+//!
+//! Effects are printed with their display names and, when colour is enabled, tinted with the hex
+//! colour from the rules file using 24-bit ANSI escape codes. Lists are comma-separated so they can
+//! be pasted back into the CLI's `--effects` and `--substances` arguments.
+
+use crate::mixing::{Effects, MixtureRules, Substance};
+use std::fmt::{Display, Formatter};
+use std::io::IsTerminal;
+
+/// Parses a `#RRGGBB` colour code.
+fn parse_hex_color(color: &str) -> Option<(u8, u8, u8)> {
+    let hex = color.strip_prefix('#')?;
+    if hex.len() != 6 {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+    Some((channel(0)?, channel(2)?, channel(4)?))
+}
+
+/// Returns true if standard output is a terminal and the user has not opted out of colour via the
+/// `NO_COLOR` environment variable.
+pub fn stdout_supports_color() -> bool {
+    std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty()) && std::io::stdout().is_terminal()
+}
+
+#[derive(Clone, Copy)]
+pub struct Renderer<'r> {
+    rules: &'r MixtureRules,
+    color: bool,
+}
+
+impl<'r> Renderer<'r> {
+    pub fn new(rules: &'r MixtureRules, color: bool) -> Self {
+        Self { rules, color }
+    }
+
+    pub fn color(&self) -> bool {
+        self.color
+    }
+
+    pub fn effects(&self, effects: Effects) -> RenderedEffects<'r> {
+        RenderedEffects {
+            renderer: *self,
+            effects,
+        }
+    }
+
+    pub fn substance(&self, substance: Substance) -> &'r str {
+        self.rules.substance_name(substance)
+    }
+
+    pub fn substances<'s>(&self, substances: &'s [Substance]) -> RenderedSubstances<'r, 's> {
+        RenderedSubstances {
+            renderer: *self,
+            substances,
+        }
+    }
+}
+
+pub struct RenderedEffects<'r> {
+    renderer: Renderer<'r>,
+    effects: Effects,
+}
+
+impl Display for RenderedEffects<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let display = self.renderer.rules.effects().display(self.effects);
+        if !self.renderer.color {
+            return write!(f, "{display}");
+        }
+        display.fmt_with(f, |f, effect| {
+            match effect.color.as_deref().and_then(parse_hex_color) {
+                Some((r, g, b)) => write!(f, "\x1b[38;2;{r};{g};{b}m{}\x1b[0m", effect.name),
+                None => write!(f, "{}", effect.name),
+            }
+        })
+    }
+}
+
+pub struct RenderedSubstances<'r, 's> {
+    renderer: Renderer<'r>,
+    substances: &'s [Substance],
+}
+
+impl Display for RenderedSubstances<'_, '_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.substances.is_empty() {
+            return write!(f, "(none)");
+        }
+        for (idx, substance) in self.substances.iter().copied().enumerate() {
+            if idx > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", self.renderer.substance(substance))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mixing::{parse_rules_file, Effects, Substance};
+    use crate::render::{parse_hex_color, Renderer};
+    use std::error::Error;
+
+    #[test]
+    fn test_parse_hex_color() {
+        assert_eq!(parse_hex_color("#FED09B"), Some((0xFE, 0xD0, 0x9B)));
+        assert_eq!(parse_hex_color("FED09B"), None);
+        assert_eq!(parse_hex_color("#FED09"), None);
+        assert_eq!(parse_hex_color("#GGGGGG"), None);
+    }
+
+    #[test]
+    fn test_render() -> Result<(), Box<dyn Error>> {
+        let rules = parse_rules_file("sch1-mix-rules.json")?;
+        let effects = rules.effects().parse("Calming, Foggy")?;
+
+        let plain = Renderer::new(&rules, false);
+        assert_eq!(plain.effects(effects).to_string(), "Calming, Foggy");
+        assert_eq!(
+            plain
+                .substances(&[Substance::FluMedicine, Substance::Cuke])
+                .to_string(),
+            "Flu Medicine, Cuke"
+        );
+        assert_eq!(plain.substances(&[]).to_string(), "(none)");
+
+        let colored = Renderer::new(&rules, true);
+        assert_eq!(
+            colored.effects(effects).to_string(),
+            "\x1b[38;2;254;208;155mCalming\x1b[0m, \x1b[38;2;176;176;175mFoggy\x1b[0m"
+        );
+
+        // Bits outside the rules are shown either way.
+        let unknown = Effects::from(1 << 63);
+        assert_eq!(plain.effects(unknown).to_string(), "0x8000000000000000");
+        assert_eq!(colored.effects(unknown).to_string(), "0x8000000000000000");
+        assert_eq!(
+            colored.effects(effects | unknown).to_string(),
+            format!("{}, 0x8000000000000000", colored.effects(effects))
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,475 @@
+//! An interactive prompt for exploring a routes file without reloading it for every query.
+//!
+//! Commands mirror the CLI subcommands and take the same lenient names:
+//!
+//! ```text
+//! lookup Calming, Foggy        routes to exactly these effects (or `lookup 117` by index)
+//! search Anti-Gravity          cheapest and shortest routes to any superset of these effects
+//! simulate OG Kush: Cuke, Gasoline
+//! profit [drug]                most profitable effect sets, using the current settings
+//! set markup 0.2               change a profit setting; `set` alone lists them
+//! help, quit
+//! ```
+//!
+//! Effect, substance and drug names are tab-completed.
+
+use crate::mixing::{parse_drug, Drugs, DRUGS, SUBSTANCES};
+use crate::mosp::{Cost, EffectIndex, PathLength};
+use crate::output::{Format, RecordWriter, RouteRecord};
+use crate::query::{
+    lookup_records, parse_recipe, profit_records, search_records, simulate, ProfitQuery,
+    MAX_PROFIT_RESULTS,
+};
+use crate::render::Renderer;
+use crate::server::QueryState;
+use rustyline::completion::{Completer, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use serde::Serialize;
+use std::error::Error;
+use std::io::{stdout, Write};
+use std::path::Path;
+
+const COMMANDS: &[&str] = &[
+    "help", "lookup", "profit", "quit", "search", "set", "simulate",
+];
+
+const SETTINGS: &[&str] = &["markup", "max_mixins", "max_price", "max_results"];
+
+const HELP: &str = "\
+lookup <effects|index>         routes to exactly these effects
+search <effects>               cheapest and shortest routes to any superset of these effects
+simulate <drug>: <substances>  mix substances into a drug one at a time
+profit [drug]                  most profitable effect sets, using the current settings
+set [<setting> <value>]        change or list the profit settings
+quit";
+
+/// Whether the prompt should keep reading commands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flow {
+    Continue,
+    Quit,
+}
+
+pub struct Session {
+    state: QueryState,
+    query: ProfitQuery,
+    color: bool,
+    format: Format,
+}
+
+impl Session {
+    pub fn new(state: QueryState, color: bool, format: Format) -> Self {
+        Self {
+            state,
+            query: ProfitQuery::default(),
+            color,
+            format,
+        }
+    }
+
+    /// Runs a single command, writing its output to `out`.
+    pub fn execute(&mut self, line: &str, out: &mut impl Write) -> Result<Flow, Box<dyn Error>> {
+        let line = line.trim();
+        let (command, args) = line.split_once(' ').unwrap_or((line, ""));
+        let args = args.trim();
+        match command {
+            "" => {}
+            "help" => writeln!(out, "{HELP}")?,
+            "quit" | "exit" => return Ok(Flow::Quit),
+            "lookup" => self.lookup(args, out)?,
+            "search" => self.search(args, out)?,
+            "simulate" => self.simulate(args, out)?,
+            "profit" => self.profit(args, out)?,
+            "set" => self.set(args, out)?,
+            _ => return Err(format!("unknown command '{command}', try 'help'").into()),
+        }
+        Ok(Flow::Continue)
+    }
+
+    fn renderer(&self) -> Renderer<'_> {
+        Renderer::new(self.state.rules(), self.color)
+    }
+
+    fn lookup(&self, args: &str, out: &mut impl Write) -> Result<(), Box<dyn Error>> {
+        let encoder = self.state.encoder();
+        let index = match args.parse::<EffectIndex>() {
+            Ok(index) => encoder.try_decode(index).map(|_| index)?,
+            Err(_) => encoder.try_encode(self.state.rules().effects().parse(args)?.bits())?,
+        };
+        let records = lookup_records(self.state.rules(), encoder, self.state.routes(), index);
+        if self.format != Format::Text {
+            return write_records(self.format, &records, out);
+        }
+
+        let render = self.renderer();
+        writeln!(
+            out,
+            "Effects: {} (index {index})",
+            render.effects(encoder.decode(index).into())
+        )?;
+        for (drug, _) in self.state.routes().by_drug() {
+            let routes = records.iter().filter(|r| r.drug == drug);
+            if routes.clone().next().is_none() {
+                continue;
+            }
+            writeln!(out, "{drug}")?;
+            for record in routes {
+                writeln!(
+                    out,
+                    "  cost: {}, length: {}, substances: {}",
+                    record.cost,
+                    record.length,
+                    render.substances(&record.ingredients)
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    fn search(&self, args: &str, out: &mut impl Write) -> Result<(), Box<dyn Error>> {
+        let rules = self.state.rules();
+        let target = rules.effects().parse(args)?;
+        let records = search_records(rules, self.state.encoder(), self.state.routes(), target);
+        if self.format != Format::Text {
+            return write_records(self.format, &records, out);
+        }
+
+        if records.is_empty() {
+            writeln!(out, "No routes found")?;
+        }
+        for pair in records.chunks(2) {
+            writeln!(out, "{}", pair[0].drug)?;
+            for (title, record) in ["Lowest Cost", "Shortest"].iter().zip(pair) {
+                writeln!(out, "  {title}: {}", self.describe_route(record))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn simulate(&self, args: &str, out: &mut impl Write) -> Result<(), Box<dyn Error>> {
+        let rules = self.state.rules();
+        let (drug, substances) = parse_recipe(rules, args)?;
+        let records = simulate(rules, self.state.graph(), drug, &substances);
+        if self.format != Format::Text {
+            return write_records(self.format, &records, out);
+        }
+
+        let render = self.renderer();
+        let mut effects = rules.drug_effects(drug);
+        writeln!(out, "{drug}: {}", render.effects(effects))?;
+        for substance in substances.iter().copied() {
+            effects = rules.apply(substance, effects);
+            writeln!(
+                out,
+                "  + {}: {}",
+                render.substance(substance),
+                render.effects(effects)
+            )?;
+        }
+        let last = records.last().expect("should include the drug");
+        writeln!(out, "Sell Price: {}, Cost: {}", last.sell_price, last.cost)?;
+        Ok(())
+    }
+
+    fn profit(&self, args: &str, out: &mut impl Write) -> Result<(), Box<dyn Error>> {
+        let drug = match args {
+            "" => None,
+            name => Some(parse_drug(name)?),
+        };
+        let results = profit_records(
+            self.state.rules(),
+            self.state.encoder(),
+            self.state.routes(),
+            &self.query,
+            drug,
+        );
+        if self.format != Format::Text {
+            let records = results.into_iter().flat_map(|(_, r)| r).collect::<Vec<_>>();
+            return write_records(self.format, &records, out);
+        }
+
+        let render = self.renderer();
+        let encoder = self.state.encoder();
+        for (drug, records) in results {
+            writeln!(out, "{drug}")?;
+            for record in records {
+                writeln!(
+                    out,
+                    "  profit: {}, sell price: {}, cost: {}, effects: {}, substances: {}",
+                    record.profit,
+                    record.sell_price,
+                    record.cost,
+                    render.effects(encoder.decode(record.index).into()),
+                    render.substances(&record.ingredients)
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    fn set(&mut self, args: &str, out: &mut impl Write) -> Result<(), Box<dyn Error>> {
+        let mut words = args.split_whitespace();
+        let (Some(setting), Some(value), None) = (words.next(), words.next(), words.next()) else {
+            if !args.is_empty() {
+                return Err("usage: set <setting> <value>".into());
+            }
+            let ProfitQuery {
+                max_mixins,
+                markup,
+                max_price,
+                max_results,
+            } = self.query;
+            writeln!(out, "markup = {markup}")?;
+            writeln!(out, "max_mixins = {max_mixins}")?;
+            writeln!(out, "max_price = {max_price}")?;
+            writeln!(out, "max_results = {max_results}")?;
+            return Ok(());
+        };
+        let invalid = || format!("invalid value '{value}' for {setting}");
+        match setting {
+            "markup" => self.query.markup = value.parse().map_err(|_| invalid())?,
+            "max_mixins" => {
+                self.query.max_mixins = value.parse::<PathLength>().map_err(|_| invalid())?
+            }
+            "max_price" => self.query.max_price = value.parse::<Cost>().map_err(|_| invalid())?,
+            "max_results" => {
+                self.query.max_results = value
+                    .parse()
+                    .ok()
+                    .filter(|n| (1..=MAX_PROFIT_RESULTS).contains(n))
+                    .ok_or_else(invalid)?
+            }
+            _ => {
+                return Err(format!(
+                    "unknown setting '{setting}', expected one of {}",
+                    SETTINGS.join(", ")
+                )
+                .into())
+            }
+        }
+        Ok(())
+    }
+
+    fn describe_route(&self, record: &RouteRecord) -> String {
+        let render = self.renderer();
+        format!(
+            "cost: {}, length: {}, effects: {}, substances: {}",
+            record.cost,
+            record.length,
+            render.effects(self.state.encoder().decode(record.index).into()),
+            render.substances(&record.ingredients)
+        )
+    }
+
+    /// Builds the tab-completion helper for this session's rules.
+    pub fn helper(&self) -> ReplHelper {
+        let rules = self.state.rules();
+        ReplHelper {
+            effects: rules.effects().iter().map(|e| e.name.clone()).collect(),
+            substances: SUBSTANCES
+                .iter()
+                .map(|s| rules.substance_name(*s).to_string())
+                .collect(),
+            drugs: DRUGS.iter().map(Drugs::to_string).collect(),
+        }
+    }
+}
+
+fn write_records<T: Serialize>(
+    format: Format,
+    records: &[T],
+    out: &mut impl Write,
+) -> Result<(), Box<dyn Error>> {
+    let mut writer = RecordWriter::new(format, out);
+    writer.write_all(records)?;
+    writer.finish().map(drop)
+}
+
+/// Completes command names, settings, and the effect, substance or drug name under the cursor.
+pub struct ReplHelper {
+    effects: Vec<String>,
+    substances: Vec<String>,
+    drugs: Vec<String>,
+}
+
+impl ReplHelper {
+    /// Returns the byte offset where the completed word starts, and the candidates for it.
+    fn candidates(&self, line: &str) -> (usize, Vec<&str>) {
+        let Some((command, args)) = line.split_once(' ') else {
+            return (0, matching(COMMANDS.iter().copied(), line));
+        };
+        let args_start = command.len() + 1;
+        let names: Vec<&str> = match command {
+            "lookup" | "search" => self.effects.iter().map(String::as_str).collect(),
+            "simulate" if args.contains(':') => {
+                self.substances.iter().map(String::as_str).collect()
+            }
+            "simulate" | "profit" => self.drugs.iter().map(String::as_str).collect(),
+            "set" if !args.contains(' ') => SETTINGS.to_vec(),
+            _ => Vec::new(),
+        };
+        let separator = args.rfind([',', '|', ':']).map_or(0, |i| i + 1);
+        let word = &args[separator..];
+        let start = args_start + separator + (word.len() - word.trim_start().len());
+        (start, matching(names, &line[start..]))
+    }
+}
+
+fn matching<'a>(names: impl IntoIterator<Item = &'a str>, prefix: &str) -> Vec<&'a str> {
+    let prefix = prefix.to_lowercase();
+    names
+        .into_iter()
+        .filter(|name| name.to_lowercase().starts_with(&prefix))
+        .collect()
+}
+
+impl Completer for ReplHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let (start, names) = self.candidates(&line[..pos]);
+        let pairs = names
+            .into_iter()
+            .map(|name| Pair {
+                display: name.to_string(),
+                replacement: name.to_string(),
+            })
+            .collect();
+        Ok((start, pairs))
+    }
+}
+
+impl Hinter for ReplHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ReplHelper {}
+
+impl Validator for ReplHelper {}
+
+impl Helper for ReplHelper {}
+
+/// Reads and runs commands until `quit` or end of input. History is loaded from and saved to
+/// `history`, if given.
+pub fn run(mut session: Session, history: Option<&Path>) -> Result<(), Box<dyn Error>> {
+    let mut editor = Editor::<ReplHelper, DefaultHistory>::new()?;
+    editor.set_helper(Some(session.helper()));
+    if let Some(path) = history {
+        // A missing history file just means this is the first session.
+        if path.exists() {
+            editor.load_history(path)?;
+        }
+    }
+
+    loop {
+        match editor.readline("> ") {
+            Ok(line) => {
+                editor.add_history_entry(line.as_str())?;
+                match session.execute(&line, &mut stdout().lock()) {
+                    Ok(Flow::Quit) => break,
+                    Ok(Flow::Continue) => {}
+                    Err(e) => eprintln!("Error: {e}"),
+                }
+            }
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    if let Some(path) = history {
+        editor.save_history(path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::output::Format;
+    use crate::query::tests::small_routes;
+    use crate::repl::{Flow, Session};
+    use crate::server::QueryState;
+    use std::error::Error;
+
+    fn session(format: Format) -> Result<Session, Box<dyn Error>> {
+        let (rules, graph, routes) = small_routes(2)?;
+        Ok(Session::new(
+            QueryState::new(rules, routes, Some(graph))?,
+            false,
+            format,
+        ))
+    }
+
+    fn run(session: &mut Session, line: &str) -> Result<String, Box<dyn Error>> {
+        let mut out = Vec::new();
+        assert_eq!(session.execute(line, &mut out)?, Flow::Continue);
+        Ok(String::from_utf8(out)?)
+    }
+
+    #[test]
+    fn test_commands() -> Result<(), Box<dyn Error>> {
+        let mut session = session(Format::Text)?;
+
+        let out = run(&mut session, "simulate og kush: cuke")?;
+        assert!(out.starts_with("OG Kush: Calming\n  + Cuke: "));
+
+        let out = run(&mut session, "lookup 0")?;
+        assert!(out.starts_with("Effects: (none) (index 0)\nMeth\n"));
+
+        run(&mut session, "set markup 0.5")?;
+        run(&mut session, "set max_results 1")?;
+        assert!(run(&mut session, "set")?.contains("markup = 0.5\n"));
+        assert!(run(&mut session, "set markup lots").is_err());
+        assert!(run(&mut session, "set max_results 99999999999999999999").is_err());
+        assert!(run(&mut session, "set max_results 18446744073709551615").is_err());
+        assert!(run(&mut session, "set max_results 0").is_err());
+        assert!(run(&mut session, "set max_results 1001").is_err());
+        assert!(run(&mut session, "set nothing 1").is_err());
+
+        let out = run(&mut session, "profit meth")?;
+        assert_eq!(out.lines().count(), 2);
+
+        assert!(run(&mut session, "search Calmnig").is_err());
+        assert!(run(&mut session, "frobnicate").is_err());
+        assert_eq!(session.execute("quit", &mut Vec::new())?, Flow::Quit);
+        Ok(())
+    }
+
+    #[test]
+    fn test_record_output() -> Result<(), Box<dyn Error>> {
+        let mut session = session(Format::Ndjson)?;
+        let out = run(&mut session, "search Calming")?;
+        let first: serde_json::Value = serde_json::from_str(out.lines().next().unwrap())?;
+        assert_eq!(first["criterion"], "lowest_cost");
+        Ok(())
+    }
+
+    #[test]
+    fn test_completion() -> Result<(), Box<dyn Error>> {
+        let helper = session(Format::Text)?.helper();
+
+        assert_eq!(helper.candidates("se"), (0, vec!["search", "set"]));
+        assert_eq!(
+            helper.candidates("lookup Calming, anti"),
+            (16, vec!["Anti-Gravity"])
+        );
+        assert_eq!(helper.candidates("simulate og"), (9, vec!["OG Kush"]));
+        assert_eq!(
+            helper.candidates("simulate OG Kush: Flu M"),
+            (18, vec!["Flu Medicine"])
+        );
+        assert_eq!(helper.candidates("set max_r"), (4, vec!["max_results"]));
+        assert!(helper.candidates("set markup 0").1.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,199 @@
+//! An inverted index over a routes file, for finding effect sets by the effects they contain.
+//!
+//! For each effect the index holds a bitmap of the effect sets that contain it, and for each drug a
+//! bitmap of the effect sets it can reach. Finding the reachable effect sets with some effects and
+//! without others is then an intersection of bitmaps, instead of a scan that decodes every index.
+//! The index is built from a routes file with `index` and saved alongside it. It keeps the routes
+//! file's header, so an index left over from routes built with other rules is caught on load.
+
+use crate::combinatorial::CombinatorialEncoder;
+use crate::file_header::FileHeader;
+use crate::flat_storage::Bitmap;
+use crate::mixing::{Drugs, Effects};
+use crate::query::{starting_position, FlattenedResultsFile};
+use rayon::prelude::*;
+use savefile::SavefileError;
+use savefile_derive::Savefile;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::io::Write;
+use std::path::Path;
+
+pub const ROUTE_INDEX_VERSION: u32 = 2;
+
+#[derive(Savefile, Serialize, Deserialize)]
+pub struct RouteIndex {
+    /// Header of the routes file the index was built from. Indexes from before version 2 have an
+    /// empty header.
+    #[savefile_versions = "2.."]
+    header: FileHeader,
+    /// Number of effect sets covered.
+    nodes: u32,
+    /// Effect sets containing each effect, by bit.
+    effects: Vec<Bitmap>,
+    /// Effect sets with at least one route, in the order of [`FlattenedResultsFile::by_drug`].
+    reachable: Vec<Bitmap>,
+}
+
+impl RouteIndex {
+    /// Indexes `routes`, which must cover the effect sets of `encoder`.
+    pub fn build(encoder: &CombinatorialEncoder, routes: &FlattenedResultsFile) -> Self {
+        let nodes = encoder.maximum_index();
+        let mut effects = vec![Bitmap::new(nodes); encoder.num_elements() as usize];
+        for (index, bitset) in encoder.combinations() {
+            let mut remaining = bitset;
+            while remaining != 0 {
+                effects[remaining.trailing_zeros() as usize].insert(index);
+                remaining &= remaining - 1;
+            }
+        }
+
+        let reachable = routes
+            .by_drug()
+            .par_iter()
+            .map(|(_, paths)| {
+                let mut reachable = Bitmap::new(nodes);
+                for index in (0..nodes).filter(|i| !paths.get(*i as usize).is_empty()) {
+                    reachable.insert(index);
+                }
+                reachable
+            })
+            .collect();
+
+        Self {
+            header: routes.header.clone(),
+            nodes,
+            effects,
+            reachable,
+        }
+    }
+
+    /// Loads an index from `path` and checks that it covers the same effect sets as `encoder`.
+    pub fn load<P: AsRef<Path>>(
+        path: P,
+        encoder: &CombinatorialEncoder,
+    ) -> Result<Self, Box<dyn Error>> {
+        let path = path.as_ref();
+        let index: Self = savefile::load_file(path, ROUTE_INDEX_VERSION)?;
+        index
+            .check(encoder)
+            .map_err(|e| format!("'{path:?}' {e}"))?;
+        Ok(index)
+    }
+
+    pub fn check(&self, encoder: &CombinatorialEncoder) -> Result<(), String> {
+        if self.nodes != encoder.maximum_index()
+            || self.effects.len() != encoder.num_elements() as usize
+        {
+            return Err(format!(
+                "indexes {} effect sets of {} effects, but the rules produce {} of {}",
+                self.nodes,
+                self.effects.len(),
+                encoder.maximum_index(),
+                encoder.num_elements()
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn serialize(&self, writer: &mut impl Write) -> Result<(), SavefileError> {
+        savefile::save(writer, ROUTE_INDEX_VERSION, self)
+    }
+
+    /// What the indexed routes were built from.
+    pub fn header(&self) -> &FileHeader {
+        &self.header
+    }
+
+    /// Effect sets reachable from `drug` with at least one route.
+    pub fn reachable(&self, drug: Drugs) -> &Bitmap {
+        &self.reachable[starting_position(drug)]
+    }
+
+    /// Effect sets reachable from `drug` that contain every effect of `include` and none of
+    /// `exclude`.
+    pub fn matching(&self, drug: Drugs, include: Effects, exclude: Effects) -> Bitmap {
+        let mut matching = self.reachable(drug).clone();
+        for bit in bits(include) {
+            match self.effects.get(bit) {
+                Some(effect) => matching.intersect(effect),
+                // No effect set contains an unknown effect.
+                None => matching.clear(),
+            }
+        }
+        for effect in bits(exclude).filter_map(|bit| self.effects.get(bit)) {
+            matching.subtract(effect);
+        }
+        matching
+    }
+}
+
+/// Positions of the set bits of `effects`.
+fn bits(effects: Effects) -> impl Iterator<Item = usize> {
+    let mut remaining = effects.bits();
+    std::iter::from_fn(move || {
+        if remaining == 0 {
+            return None;
+        }
+        let bit = remaining.trailing_zeros() as usize;
+        remaining &= remaining - 1;
+        Some(bit)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mixing::{Drugs, Effects};
+    use crate::query::tests::{small_routes, small_rules};
+    use crate::route_index::{RouteIndex, ROUTE_INDEX_VERSION};
+    use std::error::Error;
+
+    #[test]
+    fn test_matching() -> Result<(), Box<dyn Error>> {
+        let (rules, _, routes) = small_routes(2)?;
+        let encoder = rules.encoder();
+        let index = RouteIndex::build(&encoder, &routes);
+
+        let bytes = savefile::save_to_mem(ROUTE_INDEX_VERSION, &index)?;
+        let index: RouteIndex = savefile::load_from_mem(&bytes, ROUTE_INDEX_VERSION)?;
+        assert_eq!(*index.header(), routes.header);
+        assert!(index.check(&encoder).is_ok());
+        assert!(index.check(&small_rules(1)?.encoder()).is_err());
+
+        let n = encoder.num_elements();
+        for drug in [Drugs::OGKush, Drugs::Meth, Drugs::Cocaine] {
+            let paths = routes.paths(drug);
+            for (_, include) in encoder.combinations() {
+                for exclude in [0, 1, 1 << (n - 1)] {
+                    let (include, exclude) = (Effects::from(include), Effects::from(exclude));
+                    let expected = encoder
+                        .combinations()
+                        .filter(|(i, bitset)| {
+                            let effects = Effects::from(*bitset);
+                            !paths.get(*i as usize).is_empty()
+                                && effects.contains(include)
+                                && !effects.intersects(exclude)
+                        })
+                        .map(|(i, _)| i)
+                        .collect::<Vec<_>>();
+                    let matching = index.matching(drug, include, exclude);
+                    assert_eq!(matching.count(), expected.len());
+                    assert_eq!(matching.iter().collect::<Vec<_>>(), expected);
+                }
+            }
+        }
+        // Unknown effects match nothing, and excluding them changes nothing.
+        let unknown = Effects::from(1 << n);
+        assert_eq!(
+            index
+                .matching(Drugs::Meth, unknown, Effects::empty())
+                .count(),
+            0
+        );
+        assert_eq!(
+            index.matching(Drugs::Meth, Effects::empty(), unknown),
+            *index.reachable(Drugs::Meth)
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,388 @@
+//! Compares two rule sets, e.g. before and after a game patch, to find which recipes are affected.
+//!
+//! Rules are matched by substance and the effects they require and are blocked by, and compared by
+//! effect name so the two rule sets may assign bits differently. Transitions can only be compared
+//! when both rule sets use the same effects and bits.
+
+use crate::mixing::{Drugs, Effects, MixtureRules, Rule, Substance, SUBSTANCES};
+use crate::mosp::EffectIndex;
+use crate::output::RulesDiffRecord;
+use crate::query::simulate;
+use rayon::prelude::*;
+use std::collections::BTreeMap;
+
+/// Maximum number of differing effect sets reported per substance.
+const MAX_EXAMPLES: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    Added,
+    Removed,
+    Changed,
+}
+
+impl Change {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Change::Added => "added",
+            Change::Removed => "removed",
+            Change::Changed => "changed",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuleChange {
+    pub substance: Substance,
+    pub change: Change,
+    /// The rule as written in the old rules, if it existed there.
+    pub before: Option<String>,
+    /// The rule as written in the new rules, if it exists there.
+    pub after: Option<String>,
+}
+
+/// Describes a rule as `Calming, Foggy unless Energizing: Calming -> Energizing`.
+pub fn describe_rule(rules: &MixtureRules, rule: &Rule) -> String {
+    let registry = rules.effects();
+    let replace = rule
+        .replace
+        .iter()
+        .map(|(from, to)| format!("{} -> {}", registry.display(*from), registry.display(*to)))
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!(
+        "{} unless {}: {replace}",
+        registry.display(rule.if_present),
+        registry.display(rule.if_not_present)
+    )
+}
+
+/// Effects a rule requires and the effects that block it, by name.
+type Condition<'r> = (Vec<&'r str>, Vec<&'r str>);
+
+/// Rules of each substance, keyed by the names of the effects they require and are blocked by.
+/// Rules with the same condition are kept in a sorted list, so they compare as a multiset.
+fn rules_by_condition(
+    rules: &MixtureRules,
+    substance: Substance,
+) -> BTreeMap<Condition<'_>, Vec<String>> {
+    let names = |effects| {
+        let mut names = rules.effects().names(effects);
+        names.sort_unstable();
+        names
+    };
+    let mut by_condition = BTreeMap::<_, Vec<_>>::new();
+    for rule in rules.substance_rules(substance) {
+        by_condition
+            .entry((names(rule.if_present), names(rule.if_not_present)))
+            .or_default()
+            .push(describe_rule(rules, rule));
+    }
+    by_condition.values_mut().for_each(|r| r.sort_unstable());
+    by_condition
+}
+
+/// Rules that were added, removed or changed, ordered by substance. A rule is changed if a rule
+/// with the same condition replaces different effects.
+pub fn rule_changes(old: &MixtureRules, new: &MixtureRules) -> Vec<RuleChange> {
+    let mut changes = Vec::new();
+    for substance in SUBSTANCES.iter().copied() {
+        let mut after = rules_by_condition(new, substance);
+        let mut pairs = rules_by_condition(old, substance)
+            .into_iter()
+            .map(|(key, old_rules)| (old_rules, after.remove(&key).unwrap_or_default()))
+            .collect::<Vec<_>>();
+        pairs.extend(after.into_values().map(|new_rules| (Vec::new(), new_rules)));
+        for (old_rules, new_rules) in pairs {
+            let removed = old_rules
+                .iter()
+                .filter(|r| !new_rules.contains(r))
+                .cloned()
+                .collect::<Vec<_>>();
+            let added = new_rules
+                .into_iter()
+                .filter(|r| !old_rules.contains(r))
+                .collect::<Vec<_>>();
+            for idx in 0..removed.len().max(added.len()) {
+                let (before, after) = (removed.get(idx).cloned(), added.get(idx).cloned());
+                let change = match (&before, &after) {
+                    (Some(_), Some(_)) => Change::Changed,
+                    (Some(_), None) => Change::Removed,
+                    _ => Change::Added,
+                };
+                changes.push(RuleChange {
+                    substance,
+                    change,
+                    before,
+                    after,
+                });
+            }
+        }
+    }
+    changes
+}
+
+/// Rules of `substance` in a canonical order, as ties in the application order are arbitrary.
+fn sorted_rules(rules: &MixtureRules, substance: Substance) -> Vec<&Rule> {
+    let mut sorted = rules.substance_rules(substance).iter().collect::<Vec<_>>();
+    sorted.sort_unstable();
+    sorted
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransitionDiff {
+    pub substance: Substance,
+    /// Number of effect sets the substance was applied to.
+    pub transitions: u64,
+    /// Number of those for which the two rule sets disagree.
+    pub differing: u64,
+    /// The first few differing effect set indices.
+    pub examples: Vec<EffectIndex>,
+}
+
+/// Applies every substance to every effect set in the old rules' encoder domain under both rule
+/// sets, counting where they disagree.
+pub fn transition_diff(
+    old: &MixtureRules,
+    new: &MixtureRules,
+) -> Result<Vec<TransitionDiff>, String> {
+    let bits = |rules: &MixtureRules| {
+        rules
+            .effects()
+            .iter()
+            .map(|e| (e.abbreviation.clone(), e.bit))
+            .collect::<Vec<_>>()
+    };
+    if bits(old) != bits(new) {
+        return Err("transitions can only be compared between rules with the same effects".into());
+    }
+
+    let encoder = old.encoder();
+    let transitions = encoder.maximum_index() as u64;
+    Ok(SUBSTANCES
+        .iter()
+        .copied()
+        .map(|substance| {
+            // apply only depends on these, so identical ones cannot disagree
+            if sorted_rules(old, substance) == sorted_rules(new, substance)
+                && old.inherent_effects(substance) == new.inherent_effects(substance)
+                && old.max_effects() == new.max_effects()
+            {
+                return TransitionDiff {
+                    substance,
+                    transitions,
+                    differing: 0,
+                    examples: Vec::new(),
+                };
+            }
+            let differs = |bitset: u64| {
+                let effects = Effects::from(bitset);
+                old.apply(substance, effects) != new.apply(substance, effects)
+            };
+            TransitionDiff {
+                substance,
+                transitions,
+                differing: (0..encoder.maximum_index())
+                    .into_par_iter()
+                    .filter(|idx| differs(encoder.decode(*idx)))
+                    .count() as u64,
+                examples: encoder
+                    .combinations()
+                    .filter(|(_, bitset)| differs(*bitset))
+                    .map(|(idx, _)| idx)
+                    .take(MAX_EXAMPLES)
+                    .collect(),
+            }
+        })
+        .collect())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecipeDiff {
+    pub drug: Drugs,
+    pub ingredients: Vec<Substance>,
+    pub before: Vec<String>,
+    pub before_price: i32,
+    pub after: Vec<String>,
+    pub after_price: i32,
+}
+
+impl RecipeDiff {
+    pub fn changed(&self) -> bool {
+        self.before != self.after || self.before_price != self.after_price
+    }
+}
+
+/// Mixes a recipe under both rule sets.
+pub fn recipe_diff(
+    old: &MixtureRules,
+    new: &MixtureRules,
+    drug: Drugs,
+    ingredients: &[Substance],
+) -> RecipeDiff {
+    let before = simulate(old, None, drug, ingredients)
+        .pop()
+        .expect("should include the drug");
+    let after = simulate(new, None, drug, ingredients)
+        .pop()
+        .expect("should include the drug");
+    RecipeDiff {
+        drug,
+        ingredients: ingredients.to_vec(),
+        before: before.effects,
+        before_price: before.sell_price,
+        after: after.effects,
+        after_price: after.sell_price,
+    }
+}
+
+/// Everything that differs between two rule sets.
+pub struct RulesDiff {
+    pub rules: Vec<RuleChange>,
+    /// Empty if the rule sets define different effects.
+    pub transitions: Vec<TransitionDiff>,
+    pub recipes: Vec<RecipeDiff>,
+}
+
+impl RulesDiff {
+    pub fn records(&self) -> Vec<RulesDiffRecord> {
+        let empty = |section| RulesDiffRecord {
+            section,
+            substance: None,
+            drug: None,
+            ingredients: Vec::new(),
+            change: None,
+            before: None,
+            after: None,
+            before_price: None,
+            after_price: None,
+            transitions: None,
+            differing: None,
+            examples: Vec::new(),
+        };
+        let rules = self.rules.iter().map(|c| RulesDiffRecord {
+            substance: Some(c.substance),
+            change: Some(c.change.as_str()),
+            before: c.before.clone(),
+            after: c.after.clone(),
+            ..empty("rule")
+        });
+        let transitions = self.transitions.iter().map(|t| RulesDiffRecord {
+            substance: Some(t.substance),
+            transitions: Some(t.transitions),
+            differing: Some(t.differing),
+            examples: t.examples.clone(),
+            ..empty("transitions")
+        });
+        let recipes = self.recipes.iter().map(|r| RulesDiffRecord {
+            drug: Some(r.drug),
+            ingredients: r.ingredients.clone(),
+            change: Some(if r.changed() { "changed" } else { "unchanged" }),
+            before: Some(r.before.join(", ")),
+            after: Some(r.after.join(", ")),
+            before_price: Some(r.before_price),
+            after_price: Some(r.after_price),
+            ..empty("recipe")
+        });
+        rules.chain(transitions).chain(recipes).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mixing::{Drugs, MixtureRules, MixtureRulesBuilder, Substance};
+    use crate::rules_diff::{recipe_diff, rule_changes, transition_diff, Change};
+    use std::error::Error;
+
+    fn base() -> MixtureRulesBuilder {
+        MixtureRules::builder()
+            .effect("Ca", "Calming", 0.1)
+            .effect("En", "Energizing", 0.22)
+            .effect("Fo", "Foggy", 0.36)
+            .inherent_effects(Substance::Cuke, &["En"])
+            .inherent_effects(Substance::Banana, &["Fo"])
+            .drug_effects(Drugs::OGKush, &["Ca"])
+            .max_effects(2)
+    }
+
+    #[test]
+    fn test_diff() -> Result<(), Box<dyn Error>> {
+        let old = base()
+            .rule(Substance::Cuke, &["Ca"], &["En"], &[("Ca", "En")])
+            .rule(Substance::Banana, &["En"], &["Fo"], &[("En", "Fo")])
+            .build()?;
+        let new = base()
+            .rule(Substance::Cuke, &["Ca"], &["En"], &[("Ca", "Fo")])
+            .rule(Substance::Banana, &["Ca"], &["Fo"], &[("Ca", "Fo")])
+            .build()?;
+
+        let changes = rule_changes(&old, &new);
+        let summary = changes
+            .iter()
+            .map(|c| (c.substance, c.change))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                (Substance::Cuke, Change::Changed),
+                (Substance::Banana, Change::Removed),
+                (Substance::Banana, Change::Added),
+            ]
+        );
+        assert_eq!(
+            changes[0].before.as_deref(),
+            Some("Calming unless Energizing: Calming -> Energizing")
+        );
+        assert_eq!(
+            changes[0].after.as_deref(),
+            Some("Calming unless Energizing: Calming -> Foggy")
+        );
+        assert!(rule_changes(&old, &old).is_empty());
+
+        // Rules that only differ in the effects that block them are distinct.
+        let unless = |blocker| {
+            base()
+                .rule(Substance::Cuke, &["Ca"], &[blocker], &[("Ca", "En")])
+                .build()
+        };
+        let changes = rule_changes(&unless("En")?, &unless("Fo")?);
+        let summary = changes.iter().map(|c| c.change).collect::<Vec<_>>();
+        assert_eq!(summary, [Change::Removed, Change::Added]);
+        assert_eq!(
+            changes[1].after.as_deref(),
+            Some("Calming unless Foggy: Calming -> Energizing")
+        );
+        assert!(transition_diff(&old, &old)?
+            .iter()
+            .all(|t| t.differing == 0));
+
+        let transitions = transition_diff(&old, &new)?;
+        let encoder = old.encoder();
+        for diff in &transitions {
+            assert_eq!(diff.transitions, encoder.maximum_index() as u64);
+            let expected = (0..encoder.maximum_index())
+                .filter(|idx| {
+                    let effects = encoder.decode(*idx).into();
+                    old.apply(diff.substance, effects) != new.apply(diff.substance, effects)
+                })
+                .count() as u64;
+            assert_eq!(diff.differing, expected);
+            assert_eq!(diff.examples.len() as u64, expected.min(10));
+        }
+        let cuke = &transitions[Substance::Cuke as usize];
+        assert!(cuke.differing > 0);
+        assert_eq!(transitions[Substance::Gasoline as usize].differing, 0);
+
+        let recipe = recipe_diff(&old, &new, Drugs::OGKush, &[Substance::Cuke]);
+        assert_eq!(recipe.before, ["Energizing"]);
+        assert_eq!(recipe.after, ["Energizing", "Foggy"]);
+        assert!(recipe.changed());
+
+        let other = MixtureRules::builder()
+            .effect("Ca", "Calming", 0.1)
+            .max_effects(1)
+            .build()?;
+        assert!(transition_diff(&old, &other).is_err());
+        Ok(())
+    }
+}
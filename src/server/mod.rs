@@ -0,0 +1,410 @@
+//! A local HTTP server answering route queries as JSON, so the rules, graph and routes only need to
+//! be loaded once.
+//!
+//! All endpoints take `GET` requests with URL query parameters and respond with a JSON array of the
+//! records documented in [`crate::output`]. Effects, substances and drugs are parsed as on the
+//! command line. Errors are reported as `{"error": "..."}` with a 4xx status.
+//!
+//! | Endpoint    | Parameters                                                  | Record               |
+//! |-------------|-------------------------------------------------------------|----------------------|
+//! | `/lookup`   | `effects` or `index`                                        | [`RouteRecord`]      |
+//! | `/search`   | `effects`                                                   | [`RouteRecord`]      |
+//! | `/profit`   | `drug`, `markup`, `max_mixins`, `max_price`, `max_results`  | [`ProfitRecord`]     |
+//! | `/simulate` | `drug`, `substances`                                        | [`SimulationRecord`] |
+//!
+//! All `/profit` parameters are optional; without `drug` every drug is ranked.
+//!
+//! [`RouteRecord`]: crate::output::RouteRecord
+//! [`ProfitRecord`]: crate::output::ProfitRecord
+//! [`SimulationRecord`]: crate::output::SimulationRecord
+
+use crate::combinatorial::CombinatorialEncoder;
+use crate::effect_graph::EffectGraph;
+use crate::mixing::{parse_drug, MixtureRules};
+use crate::query::{
+    check_routes, lookup_records, profit_records, search_records, simulate, FlattenedResultsFile,
+    ProfitQuery, MAX_PROFIT_RESULTS,
+};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::panic::{self, AssertUnwindSafe};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tiny_http::{Header, Method, Response, Server};
+
+/// How often idle workers check whether the server has been shut down.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Everything a query needs, loaded once at startup.
+pub struct QueryState {
+    rules: MixtureRules,
+    encoder: CombinatorialEncoder,
+    routes: FlattenedResultsFile,
+    graph: Option<EffectGraph>,
+}
+
+impl QueryState {
+    /// Bundles the loaded files. The routes must have been computed with `rules`; if a graph is
+    /// given, it is used for simulations.
+    pub fn new(
+        rules: MixtureRules,
+        routes: FlattenedResultsFile,
+        graph: Option<EffectGraph>,
+    ) -> Result<Self, String> {
+        let encoder = rules.encoder();
+        check_routes(&routes, &encoder).map_err(|e| format!("routes file {e}"))?;
+        if let Some(graph) = &graph {
+            rules.check_encoder(graph.encoder())?;
+        }
+        Ok(Self {
+            rules,
+            encoder,
+            routes,
+            graph,
+        })
+    }
+
+    pub fn rules(&self) -> &MixtureRules {
+        &self.rules
+    }
+
+    pub fn encoder(&self) -> &CombinatorialEncoder {
+        &self.encoder
+    }
+
+    pub fn routes(&self) -> &FlattenedResultsFile {
+        &self.routes
+    }
+
+    pub fn graph(&self) -> Option<&EffectGraph> {
+        self.graph.as_ref()
+    }
+
+    /// Answers a single request, returning the status code and JSON body.
+    pub fn handle(&self, method: &Method, url: &str) -> (u16, String) {
+        if *method != Method::Get {
+            return error(405, "only GET requests are supported");
+        }
+        let (path, query) = url.split_once('?').unwrap_or((url, ""));
+        let params = match parse_query(query) {
+            Ok(params) => params,
+            Err(e) => return error(400, e),
+        };
+        let result = match path {
+            "/lookup" => self.lookup(&params),
+            "/search" => self.search(&params),
+            "/profit" => self.profit(&params),
+            "/simulate" => self.simulate(&params),
+            _ => return error(404, format!("unknown endpoint '{path}'")),
+        };
+        match result {
+            Ok(body) => (200, body),
+            Err(e) => error(400, e),
+        }
+    }
+
+    fn lookup(&self, params: &Params) -> Result<String, Box<dyn Error>> {
+        let index = match (params.get("index"), params.get("effects")) {
+            (Some(_), Some(_)) => return Err("'index' and 'effects' cannot both be given".into()),
+            (Some(_), None) => {
+                let index = parse_param(params, "index")?.expect("checked above");
+                self.encoder.try_decode(index).map(|_| index)?
+            }
+            (None, Some(effects)) => {
+                let effects = self.rules.effects().parse(effects)?;
+                self.encoder.try_encode(effects.bits())?
+            }
+            (None, None) => return Err("one of 'index' or 'effects' is required".into()),
+        };
+        to_json(&lookup_records(
+            &self.rules,
+            &self.encoder,
+            &self.routes,
+            index,
+        ))
+    }
+
+    fn search(&self, params: &Params) -> Result<String, Box<dyn Error>> {
+        let effects = self.rules.effects().parse(required(params, "effects")?)?;
+        to_json(&search_records(
+            &self.rules,
+            &self.encoder,
+            &self.routes,
+            effects,
+        ))
+    }
+
+    fn profit(&self, params: &Params) -> Result<String, Box<dyn Error>> {
+        let drug = params.get("drug").map(|d| parse_drug(d)).transpose()?;
+        let defaults = ProfitQuery::default();
+        let query = ProfitQuery {
+            max_mixins: parse_param(params, "max_mixins")?.unwrap_or(defaults.max_mixins),
+            markup: parse_param(params, "markup")?.unwrap_or(defaults.markup),
+            max_price: parse_param(params, "max_price")?.unwrap_or(defaults.max_price),
+            max_results: parse_param(params, "max_results")?.unwrap_or(defaults.max_results),
+        };
+        if query.max_results == 0 || query.max_results > MAX_PROFIT_RESULTS {
+            return Err(format!("'max_results' must be between 1 and {MAX_PROFIT_RESULTS}").into());
+        }
+        let records = profit_records(&self.rules, &self.encoder, &self.routes, &query, drug)
+            .into_iter()
+            .flat_map(|(_, r)| r)
+            .collect::<Vec<_>>();
+        to_json(&records)
+    }
+
+    fn simulate(&self, params: &Params) -> Result<String, Box<dyn Error>> {
+        let drug = parse_drug(required(params, "drug")?)?;
+        let substances = self
+            .rules
+            .parse_substances(params.get("substances").map_or("", String::as_str))?;
+        to_json(&simulate(
+            &self.rules,
+            self.graph.as_ref(),
+            drug,
+            &substances,
+        ))
+    }
+}
+
+type Params = HashMap<String, String>;
+
+fn required<'p>(params: &'p Params, name: &str) -> Result<&'p str, String> {
+    params
+        .get(name)
+        .map(String::as_str)
+        .ok_or_else(|| format!("missing parameter '{name}'"))
+}
+
+fn parse_param<T: FromStr>(params: &Params, name: &str) -> Result<Option<T>, String> {
+    params
+        .get(name)
+        .map(|v| {
+            v.parse()
+                .map_err(|_| format!("invalid value '{v}' for parameter '{name}'"))
+        })
+        .transpose()
+}
+
+fn to_json<T: Serialize>(records: &T) -> Result<String, Box<dyn Error>> {
+    Ok(serde_json::to_string(records)?)
+}
+
+fn error(status: u16, message: impl ToString) -> (u16, String) {
+    let body = serde_json::json!({ "error": message.to_string() });
+    (status, body.to_string())
+}
+
+/// Parses an `application/x-www-form-urlencoded` query string.
+fn parse_query(query: &str) -> Result<Params, String> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            Ok((percent_decode(key)?, percent_decode(value)?))
+        })
+        .collect()
+}
+
+fn percent_decode(s: &str) -> Result<String, String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut input = s.bytes();
+    while let Some(b) = input.next() {
+        match b {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex = [input.next(), input.next()];
+                let decoded = match hex {
+                    [Some(hi), Some(lo)] => std::str::from_utf8(&[hi, lo])
+                        .ok()
+                        .and_then(|h| u8::from_str_radix(h, 16).ok()),
+                    _ => None,
+                };
+                bytes.push(decoded.ok_or_else(|| format!("invalid escape in '{s}'"))?);
+            }
+            _ => bytes.push(b),
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| format!("'{s}' is not valid UTF-8"))
+}
+
+pub struct QueryServer {
+    server: Server,
+    state: QueryState,
+    running: AtomicBool,
+}
+
+impl QueryServer {
+    /// Binds to `addr`. Use port 0 to pick any free port.
+    pub fn bind(
+        addr: impl ToSocketAddrs,
+        state: QueryState,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        Ok(Self {
+            server: Server::http(addr)?,
+            state,
+            running: AtomicBool::new(true),
+        })
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.server.server_addr().to_ip()
+    }
+
+    /// Serves requests on `workers` threads until [`QueryServer::shutdown`] is called.
+    pub fn run(&self, workers: usize) -> io::Result<()> {
+        std::thread::scope(|scope| {
+            let handles = (0..workers.max(1))
+                .map(|_| scope.spawn(|| self.work()))
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .try_for_each(|h| h.join().expect("worker should not panic"))
+        })
+    }
+
+    /// Stops the server once in-flight requests are answered.
+    pub fn shutdown(&self) {
+        self.running.store(false, Ordering::Relaxed);
+    }
+
+    fn work(&self) -> io::Result<()> {
+        let content_type =
+            Header::from_bytes("Content-Type", "application/json").expect("header should be valid");
+        while self.running.load(Ordering::Relaxed) {
+            let Some(request) = self.server.recv_timeout(POLL_INTERVAL)? else {
+                continue;
+            };
+            // A bug in one handler answers that request with an error rather than ending the worker.
+            let (status, body) = panic::catch_unwind(AssertUnwindSafe(|| {
+                self.state.handle(request.method(), request.url())
+            }))
+            .unwrap_or_else(|_| error(500, "internal error"));
+            let response = Response::from_string(body)
+                .with_status_code(status)
+                .with_header(content_type.clone());
+            // A client hanging up early is not an error for the server.
+            let _ = request.respond(response);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::query::tests::small_routes;
+    use crate::server::{parse_query, QueryServer, QueryState};
+    use serde_json::Value;
+    use std::error::Error;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use tiny_http::Method;
+
+    fn state() -> Result<QueryState, Box<dyn Error>> {
+        let (rules, graph, routes) = small_routes(2)?;
+        Ok(QueryState::new(rules, routes, Some(graph))?)
+    }
+
+    fn get(addr: SocketAddr, url: &str) -> Result<(u16, Value), Box<dyn Error>> {
+        let mut stream = TcpStream::connect(addr)?;
+        write!(
+            stream,
+            "GET {url} HTTP/1.1\r\nHost: {addr}\r\nConnection: close\r\n\r\n"
+        )?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        let status = response
+            .split(' ')
+            .nth(1)
+            .ok_or("missing status")?
+            .parse()?;
+        let (_, body) = response.split_once("\r\n\r\n").ok_or("missing body")?;
+        Ok((status, serde_json::from_str(body)?))
+    }
+
+    #[test]
+    fn test_parse_query() {
+        let params = parse_query("effects=Anti-Gravity%2C+Foggy&index=3&&flag").unwrap();
+        assert_eq!(params["effects"], "Anti-Gravity, Foggy");
+        assert_eq!(params["index"], "3");
+        assert_eq!(params["flag"], "");
+        assert!(parse_query("effects=%zz").is_err());
+    }
+
+    #[test]
+    fn test_handle() -> Result<(), Box<dyn Error>> {
+        let state = state()?;
+
+        let (status, body) = state.handle(&Method::Get, "/simulate?drug=og+kush&substances=cuke");
+        assert_eq!(status, 200);
+        let steps: Value = serde_json::from_str(&body)?;
+        assert_eq!(steps[1]["substance"], "Cuke");
+        let effects = steps[1]["effects"]
+            .as_array()
+            .ok_or("effects should be a list")?
+            .iter()
+            .filter_map(Value::as_str)
+            .collect::<Vec<_>>()
+            .join(",");
+
+        let (status, body) = state.handle(&Method::Get, &format!("/lookup?effects={effects}"));
+        assert_eq!(status, 200);
+        let routes: Value = serde_json::from_str(&body)?;
+        assert!(routes
+            .as_array()
+            .ok_or("routes should be a list")?
+            .iter()
+            .any(|r| r["drug"] == "OGKush" && r["ingredients"] == serde_json::json!(["Cuke"])));
+
+        let (status, body) = state.handle(&Method::Get, "/profit?drug=meth&max_results=2");
+        assert_eq!(status, 200);
+        let records: Value = serde_json::from_str(&body)?;
+        assert_eq!(records.as_array().map(Vec::len), Some(2));
+        assert_eq!(records[0]["drug"], "Meth");
+
+        let (status, body) = state.handle(&Method::Get, "/search?effects=Calmnig");
+        assert_eq!(status, 400);
+        assert!(body.contains("did you mean 'Calming'"));
+
+        let (status, body) = state.handle(&Method::Get, "/profit?max_results=18446744073709551615");
+        assert_eq!(status, 400);
+        assert!(body.contains("max_results"));
+        assert_eq!(state.handle(&Method::Get, "/profit?max_results=0").0, 400);
+        assert_eq!(
+            state.handle(&Method::Get, "/profit?max_results=1001").0,
+            400
+        );
+
+        assert_eq!(state.handle(&Method::Get, "/lookup?index=x").0, 400);
+        assert_eq!(state.handle(&Method::Get, "/lookup?index=999999").0, 400);
+        assert_eq!(state.handle(&Method::Get, "/nope").0, 404);
+        assert_eq!(state.handle(&Method::Post, "/lookup?index=0").0, 405);
+        Ok(())
+    }
+
+    #[test]
+    fn test_serve() -> Result<(), Box<dyn Error>> {
+        let server = QueryServer::bind("127.0.0.1:0", state()?).map_err(|e| e.to_string())?;
+        let addr = server
+            .local_addr()
+            .ok_or("should listen on an IP address")?;
+        std::thread::scope(|scope| {
+            let handle = scope.spawn(|| server.run(1));
+            let rejected = get(addr, "/profit?max_results=18446744073709551615");
+            let result = get(addr, "/search?effects=Calming");
+            server.shutdown();
+            handle.join().expect("server should not panic")?;
+
+            assert_eq!(rejected?.0, 400);
+            let (status, body) = result?;
+            assert_eq!(status, 200);
+            assert_eq!(body[0]["criterion"], "lowest_cost");
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,162 @@
+//! JavaScript bindings for running the simulator and route lookup in a browser, without a
+//! filesystem or a server.
+//!
+//! Build with `wasm-pack build --no-default-features --features wasm`. Everything is exposed through
+//! [`Mixer`], which is constructed from the contents of a rules file; a routes file can then be
+//! loaded from its bytes to enable lookups. Names are parsed as on the command line, and results
+//! are returned as JSON strings holding the records documented in [`crate::output`].
+//!
+//! ```js
+//! const mixer = new Mixer(await (await fetch("sch1-mix-rules.json")).text());
+//! mixer.loadRoutes(new Uint8Array(await (await fetch("routes.bin")).arrayBuffer()));
+//! JSON.parse(mixer.simulate("OG Kush", "Cuke, Gasoline"));
+//! ```
+
+use crate::combinatorial::CombinatorialEncoder;
+use crate::mixing::{base_price, parse_drug, MixtureRules};
+use crate::query::{lookup_records, read_routes, simulate, FlattenedResultsFile};
+use std::error::Error;
+use wasm_bindgen::prelude::*;
+
+#[wasm_bindgen]
+pub struct Mixer {
+    rules: MixtureRules,
+    encoder: CombinatorialEncoder,
+    routes: Option<FlattenedResultsFile>,
+}
+
+#[wasm_bindgen]
+impl Mixer {
+    /// Parses the contents of a rules file.
+    #[wasm_bindgen(constructor)]
+    pub fn new(rules: &str) -> Result<Mixer, JsError> {
+        Self::from_rules(rules).map_err(js_error)
+    }
+
+    /// Loads the contents of a routes file generated from the same rules. Routes whose header
+    /// records different rules or costs are refused.
+    #[wasm_bindgen(js_name = loadRoutes)]
+    pub fn load_routes(&mut self, routes: &[u8]) -> Result<(), JsError> {
+        self.try_load_routes(routes).map_err(js_error)
+    }
+
+    /// Mixes a comma-separated list of substances into `drug`, returning a JSON array of
+    /// `SimulationRecord`s.
+    pub fn simulate(&self, drug: &str, substances: &str) -> Result<String, JsError> {
+        self.try_simulate(drug, substances).map_err(js_error)
+    }
+
+    /// Sale price of `drug` with `effects`, after `markup` (e.g. `0.2` for 20%).
+    pub fn price(&self, drug: &str, effects: &str, markup: f64) -> Result<f64, JsError> {
+        self.try_price(drug, effects, markup).map_err(js_error)
+    }
+
+    /// Routes to exactly `effects`, returning a JSON array of `RouteRecord`s.
+    pub fn lookup(&self, effects: &str) -> Result<String, JsError> {
+        self.try_lookup(effects).map_err(js_error)
+    }
+}
+
+impl Mixer {
+    fn from_rules(rules: &str) -> Result<Self, Box<dyn Error>> {
+        let rules = rules.parse::<MixtureRules>()?;
+        Ok(Self {
+            encoder: rules.encoder(),
+            rules,
+            routes: None,
+        })
+    }
+
+    fn try_load_routes(&mut self, routes: &[u8]) -> Result<(), Box<dyn Error>> {
+        let routes = read_routes(routes, &self.encoder)?;
+        let mismatches = routes.header.mismatches(&self.rules);
+        if routes.header.is_present() && !mismatches.is_empty() {
+            return Err(format!("routes file was {}", mismatches.join(" and ")).into());
+        }
+        self.routes = Some(routes);
+        Ok(())
+    }
+
+    fn try_simulate(&self, drug: &str, substances: &str) -> Result<String, Box<dyn Error>> {
+        let drug = parse_drug(drug)?;
+        let substances = self.rules.parse_substances(substances)?;
+        let records = simulate(&self.rules, None, drug, &substances);
+        Ok(serde_json::to_string(&records)?)
+    }
+
+    fn try_price(&self, drug: &str, effects: &str, markup: f64) -> Result<f64, Box<dyn Error>> {
+        let drug = parse_drug(drug)?;
+        let effects = self.rules.effects().parse(effects)?;
+        let price = base_price(drug) * (1. + markup) * self.rules.price_multiplier(effects);
+        Ok(price.round())
+    }
+
+    fn try_lookup(&self, effects: &str) -> Result<String, Box<dyn Error>> {
+        let routes = self
+            .routes
+            .as_ref()
+            .ok_or("no routes loaded, call loadRoutes first")?;
+        let effects = self.rules.effects().parse(effects)?;
+        let index = self.encoder.try_encode(effects.bits())?;
+        let records = lookup_records(&self.rules, &self.encoder, routes, index);
+        Ok(serde_json::to_string(&records)?)
+    }
+}
+
+fn js_error(e: Box<dyn Error>) -> JsError {
+    JsError::new(&e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::effect_graph::EffectGraph;
+    use crate::query::{FlattenedResultsFile, SHORTEST_PATH_VERSION};
+    use crate::wasm::Mixer;
+    use serde_json::Value;
+
+    fn rules(max_effects: u8) -> String {
+        let mut raw: Value =
+            serde_json::from_str(include_str!("../../sch1-mix-rules.json")).unwrap();
+        raw["max_effects"] = max_effects.into();
+        raw.to_string()
+    }
+
+    #[cfg_attr(not(target_arch = "wasm32"), test)]
+    #[cfg_attr(target_arch = "wasm32", wasm_bindgen_test::wasm_bindgen_test)]
+    fn test_simulate_and_price() {
+        let mixer = Mixer::from_rules(&rules(8)).unwrap();
+
+        let steps: Value =
+            serde_json::from_str(&mixer.try_simulate("og kush", "cuke, banana").unwrap()).unwrap();
+        assert_eq!(steps.as_array().map(Vec::len), Some(3));
+        assert_eq!(steps[1]["substance"], "Cuke");
+
+        assert_eq!(mixer.try_price("Meth", "", 0.).unwrap(), 70.);
+        assert_eq!(mixer.try_price("Meth", "", 0.5).unwrap(), 105.);
+        assert!(mixer.try_price("Meth", "Calmnig", 0.).is_err());
+        assert!(mixer.try_lookup("Calming").is_err());
+    }
+
+    #[cfg_attr(not(target_arch = "wasm32"), test)]
+    #[cfg_attr(target_arch = "wasm32", wasm_bindgen_test::wasm_bindgen_test)]
+    fn test_lookup() {
+        let mut mixer = Mixer::from_rules(&rules(2)).unwrap();
+        let graph = EffectGraph::new(&mixer.rules, mixer.encoder.clone());
+        let routes = FlattenedResultsFile::build(&mixer.rules, &graph);
+        let bytes = savefile::save_to_mem(SHORTEST_PATH_VERSION, &routes).unwrap();
+        assert!(mixer.try_load_routes(&bytes).is_ok());
+
+        // Routes built from other rules are refused.
+        let mut repriced: Value = serde_json::from_str(&rules(2)).unwrap();
+        repriced["effect_price"]["Ca"] = "0.5".into();
+        let mut other = Mixer::from_rules(&repriced.to_string()).unwrap();
+        assert!(other.try_load_routes(&bytes).is_err());
+
+        let routes: Value = serde_json::from_str(&mixer.try_lookup("Energizing").unwrap()).unwrap();
+        assert!(routes
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|r| r["drug"] == "Meth" && r["ingredients"] == serde_json::json!(["Cuke"])));
+    }
+}